@@ -0,0 +1 @@
+ALTER TABLE fang_tasks DROP COLUMN binary_metadata;
@@ -0,0 +1 @@
+ALTER TABLE fang_tasks ADD COLUMN binary_metadata BYTEA;
@@ -0,0 +1,7 @@
+CREATE TABLE fang_control (
+     id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+     inserts_paused BOOLEAN DEFAULT FALSE NOT NULL,
+     updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO fang_control DEFAULT VALUES;
@@ -0,0 +1,34 @@
+DROP FUNCTION fang_create_task_type_partition(VARCHAR);
+
+ALTER TABLE fang_tasks RENAME TO fang_tasks_partitioned;
+ALTER TABLE fang_tasks_partitioned RENAME CONSTRAINT fang_tasks_pkey TO fang_tasks_partitioned_pkey;
+
+DROP INDEX fang_tasks_state_index;
+DROP INDEX fang_tasks_type_index;
+DROP INDEX fang_tasks_scheduled_at_index;
+DROP INDEX fang_tasks_uniq_hash;
+
+CREATE TABLE fang_tasks (
+     id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+     metadata jsonb NOT NULL,
+     error_message TEXT,
+     state fang_task_state DEFAULT 'new' NOT NULL,
+     task_type VARCHAR DEFAULT 'common' NOT NULL,
+     uniq_hash CHAR(64),
+     retries INTEGER DEFAULT 0 NOT NULL,
+     scheduled_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     binary_metadata BYTEA
+);
+
+INSERT INTO fang_tasks (id, metadata, error_message, state, task_type, uniq_hash, retries, scheduled_at, created_at, updated_at, binary_metadata)
+SELECT id, metadata, error_message, state, task_type, uniq_hash, retries, scheduled_at, created_at, updated_at, binary_metadata
+FROM fang_tasks_partitioned;
+
+DROP TABLE fang_tasks_partitioned;
+
+CREATE INDEX fang_tasks_state_index ON fang_tasks(state);
+CREATE INDEX fang_tasks_type_index ON fang_tasks(task_type);
+CREATE INDEX fang_tasks_scheduled_at_index ON fang_tasks(scheduled_at);
+CREATE INDEX fang_tasks_uniq_hash ON fang_tasks(uniq_hash);
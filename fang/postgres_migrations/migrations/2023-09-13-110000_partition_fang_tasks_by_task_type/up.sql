@@ -0,0 +1,46 @@
+-- fang_tasks becomes a table partitioned by task_type. Tasks go to fang_tasks_default
+-- unless a partition for their type is created with fang_create_task_type_partition.
+DROP INDEX fang_tasks_state_index;
+DROP INDEX fang_tasks_type_index;
+DROP INDEX fang_tasks_scheduled_at_index;
+DROP INDEX fang_tasks_uniq_hash;
+
+ALTER TABLE fang_tasks RENAME TO fang_tasks_unpartitioned;
+ALTER TABLE fang_tasks_unpartitioned RENAME CONSTRAINT fang_tasks_pkey TO fang_tasks_unpartitioned_pkey;
+
+CREATE TABLE fang_tasks (
+     id uuid NOT NULL DEFAULT uuid_generate_v4(),
+     metadata jsonb NOT NULL,
+     error_message TEXT,
+     state fang_task_state DEFAULT 'new' NOT NULL,
+     task_type VARCHAR DEFAULT 'common' NOT NULL,
+     uniq_hash CHAR(64),
+     retries INTEGER DEFAULT 0 NOT NULL,
+     scheduled_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     updated_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+     binary_metadata BYTEA,
+     PRIMARY KEY (id, task_type)
+) PARTITION BY LIST (task_type);
+
+CREATE TABLE fang_tasks_default PARTITION OF fang_tasks DEFAULT;
+
+INSERT INTO fang_tasks (id, metadata, error_message, state, task_type, uniq_hash, retries, scheduled_at, created_at, updated_at, binary_metadata)
+SELECT id, metadata, error_message, state, task_type, uniq_hash, retries, scheduled_at, created_at, updated_at, binary_metadata
+FROM fang_tasks_unpartitioned;
+
+DROP TABLE fang_tasks_unpartitioned;
+
+CREATE INDEX fang_tasks_state_index ON fang_tasks(state);
+CREATE INDEX fang_tasks_type_index ON fang_tasks(task_type);
+CREATE INDEX fang_tasks_scheduled_at_index ON fang_tasks(scheduled_at);
+CREATE INDEX fang_tasks_uniq_hash ON fang_tasks(uniq_hash);
+
+-- Creates the fang_tasks_<task_type> partition for tasks of the task_type type.
+-- The default partition must not contain tasks of this type.
+CREATE FUNCTION fang_create_task_type_partition(_task_type VARCHAR) RETURNS VOID AS $$
+BEGIN
+    EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF fang_tasks FOR VALUES IN (%L)',
+                   'fang_tasks_' || _task_type, _task_type);
+END;
+$$ LANGUAGE plpgsql;
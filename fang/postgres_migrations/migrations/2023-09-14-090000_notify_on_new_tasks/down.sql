@@ -0,0 +1,2 @@
+DROP TRIGGER fang_tasks_notify ON fang_tasks;
+DROP FUNCTION fang_tasks_notify();
@@ -0,0 +1,10 @@
+-- Notifies the listeners of the fang_tasks channel about new tasks, the payload is the task_type.
+CREATE FUNCTION fang_tasks_notify() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('fang_tasks', NEW.task_type);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER fang_tasks_notify AFTER INSERT ON fang_tasks
+    FOR EACH ROW EXECUTE FUNCTION fang_tasks_notify();
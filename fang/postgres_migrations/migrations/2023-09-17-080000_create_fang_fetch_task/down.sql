@@ -0,0 +1 @@
+DROP FUNCTION fang_fetch_task(TEXT);
@@ -0,0 +1,15 @@
+-- Fetches the oldest task of the given type that can be executed and marks it as in_progress in one statement.
+CREATE FUNCTION fang_fetch_task(_task_type TEXT) RETURNS SETOF fang_tasks AS $$
+    UPDATE fang_tasks
+    SET state = 'in_progress', updated_at = statement_timestamp()
+    WHERE task_type = _task_type AND id = (
+        SELECT id FROM fang_tasks
+        WHERE task_type = _task_type
+          AND state IN ('new', 'retried')
+          AND scheduled_at <= statement_timestamp()
+        ORDER BY created_at ASC, scheduled_at ASC
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING *;
+$$ LANGUAGE sql;
@@ -0,0 +1,17 @@
+ALTER TABLE fang_tasks ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE;
+
+CREATE OR REPLACE FUNCTION fang_fetch_task(_task_type TEXT) RETURNS SETOF fang_tasks AS $$
+    UPDATE fang_tasks
+    SET state = 'in_progress', updated_at = statement_timestamp()
+    WHERE task_type = _task_type AND id = (
+        SELECT id FROM fang_tasks
+        WHERE task_type = _task_type
+          AND state IN ('new', 'retried')
+          AND deleted_at IS NULL
+          AND scheduled_at <= statement_timestamp()
+        ORDER BY created_at ASC, scheduled_at ASC
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING *;
+$$ LANGUAGE sql;
@@ -0,0 +1 @@
+ALTER TABLE fang_tasks ADD COLUMN priority INTEGER DEFAULT 0 NOT NULL;
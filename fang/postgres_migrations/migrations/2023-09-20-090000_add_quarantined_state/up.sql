@@ -0,0 +1 @@
+ALTER TYPE fang_task_state ADD VALUE 'quarantined';
@@ -0,0 +1,20 @@
+-- Tasks are fetched by priority first, the index follows the order of the fetch so
+-- the FOR UPDATE SKIP LOCKED fetch doesn't sort all the pending tasks of the type.
+CREATE INDEX fang_tasks_priority_created_at_index
+    ON fang_tasks(task_type, priority DESC, created_at ASC, scheduled_at ASC);
+
+CREATE OR REPLACE FUNCTION fang_fetch_task(_task_type TEXT) RETURNS SETOF fang_tasks AS $$
+    UPDATE fang_tasks
+    SET state = 'in_progress', updated_at = statement_timestamp()
+    WHERE task_type = _task_type AND id = (
+        SELECT id FROM fang_tasks
+        WHERE task_type = _task_type
+          AND state IN ('new', 'retried')
+          AND deleted_at IS NULL
+          AND scheduled_at <= statement_timestamp()
+        ORDER BY priority DESC, created_at ASC, scheduled_at ASC
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING *;
+$$ LANGUAGE sql;
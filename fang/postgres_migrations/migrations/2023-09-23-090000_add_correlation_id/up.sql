@@ -0,0 +1,4 @@
+-- Tasks of one workflow share the correlation id, it's the id of the first task of the workflow.
+ALTER TABLE fang_tasks ADD COLUMN correlation_id uuid;
+
+CREATE INDEX fang_tasks_correlation_id_index ON fang_tasks(correlation_id);
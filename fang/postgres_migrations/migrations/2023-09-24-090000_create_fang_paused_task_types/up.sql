@@ -0,0 +1,22 @@
+-- Workers don't fetch the tasks of the task types in this table.
+CREATE TABLE fang_paused_task_types (
+     task_type VARCHAR PRIMARY KEY,
+     paused_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE OR REPLACE FUNCTION fang_fetch_task(_task_type TEXT) RETURNS SETOF fang_tasks AS $$
+    UPDATE fang_tasks
+    SET state = 'in_progress', updated_at = statement_timestamp()
+    WHERE task_type = _task_type AND id = (
+        SELECT id FROM fang_tasks
+        WHERE task_type = _task_type
+          AND state IN ('new', 'retried')
+          AND deleted_at IS NULL
+          AND scheduled_at <= statement_timestamp()
+          AND NOT EXISTS (SELECT 1 FROM fang_paused_task_types WHERE task_type = _task_type)
+        ORDER BY priority DESC, created_at ASC, scheduled_at ASC
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING *;
+$$ LANGUAGE sql;
@@ -0,0 +1,9 @@
+-- The tasks that were still executed by workers when they were shut down.
+CREATE TABLE fang_interrupted (
+     id uuid PRIMARY KEY DEFAULT uuid_generate_v4(),
+     task_id uuid NOT NULL,
+     task_type VARCHAR NOT NULL,
+     interrupted_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX fang_interrupted_task_id_index ON fang_interrupted(task_id);
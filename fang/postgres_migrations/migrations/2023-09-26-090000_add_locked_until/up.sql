@@ -0,0 +1,4 @@
+-- The end of the lease of a task fetched with a lease, the task is requeued after its lease expired.
+ALTER TABLE fang_tasks ADD COLUMN locked_until timestamptz;
+
+CREATE INDEX fang_tasks_locked_until_index ON fang_tasks(locked_until) WHERE locked_until IS NOT NULL;
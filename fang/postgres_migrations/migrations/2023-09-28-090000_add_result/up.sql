@@ -0,0 +1,2 @@
+-- The value returned by the task, stored when the task is finished.
+ALTER TABLE fang_tasks ADD COLUMN result jsonb;
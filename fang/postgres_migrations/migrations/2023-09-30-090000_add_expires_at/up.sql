@@ -0,0 +1,24 @@
+-- A task that is not executed before its expiration time is not fetched and it's moved to the expired state.
+ALTER TABLE fang_tasks ADD COLUMN expires_at timestamptz;
+
+CREATE OR REPLACE FUNCTION fang_fetch_task(_task_type TEXT) RETURNS SETOF fang_tasks AS $$
+    UPDATE fang_tasks
+    SET state = 'in_progress', updated_at = statement_timestamp()
+    WHERE task_type = _task_type AND id = (
+        SELECT id FROM fang_tasks
+        WHERE task_type = _task_type
+          AND state IN ('new', 'retried')
+          AND deleted_at IS NULL
+          AND scheduled_at <= statement_timestamp()
+          AND NOT EXISTS (SELECT 1 FROM fang_paused_task_types WHERE task_type = _task_type)
+          AND (fang_tasks.depends_on IS NULL OR EXISTS (
+              SELECT 1 FROM fang_tasks AS dependency
+              WHERE dependency.id = fang_tasks.depends_on AND dependency.state = 'finished'
+          ))
+          AND (expires_at IS NULL OR expires_at > statement_timestamp())
+        ORDER BY priority DESC, created_at ASC, scheduled_at ASC
+        LIMIT 1
+        FOR UPDATE SKIP LOCKED
+    )
+    RETURNING *;
+$$ LANGUAGE sql;
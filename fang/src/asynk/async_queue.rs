@@ -45,6 +45,7 @@ const FETCH_TASK_TYPE_QUERY: &str = include_str!("queries/fetch_task_type.sql");
 const FIND_TASK_BY_UNIQ_HASH_QUERY: &str = include_str!("queries/find_task_by_uniq_hash.sql");
 const FIND_TASK_BY_ID_QUERY: &str = include_str!("queries/find_task_by_id.sql");
 const RETRY_TASK_QUERY: &str = include_str!("queries/retry_task.sql");
+const NEXT_SCHEDULED_AT_QUERY: &str = include_str!("queries/next_scheduled_at.sql");
 
 pub const DEFAULT_TASK_TYPE: &str = "common";
 
@@ -134,6 +135,15 @@ pub trait AsyncQueueable: Send {
     /// Schedule a task.
     async fn schedule_task(&mut self, task: &dyn AsyncRunnable) -> Result<Task, AsyncQueueError>;
 
+    /// The earliest `scheduled_at` of the tasks of the `task_type` type in the `FangTaskState::New`
+    /// or `FangTaskState::Retried` states that are scheduled in the future, `None` if there are no such tasks.
+    /// Soft-deleted and expired tasks, and the tasks of a paused type are not considered.
+    /// If `task_type` is `None` the type `common` is used.
+    async fn next_scheduled_at(
+        &mut self,
+        task_type: Option<String>,
+    ) -> Result<Option<DateTime<Utc>>, AsyncQueueError>;
+
     async fn schedule_retry(
         &mut self,
         task: &Task,
@@ -365,10 +375,9 @@ where
             None => DEFAULT_TASK_TYPE.to_string(),
         };
 
-        let task = match Self::get_task_type_query(transaction, &task_type).await {
-            Ok(some_task) => Some(some_task),
-            Err(_) => None,
-        };
+        let task = Self::get_task_type_query(transaction, &task_type)
+            .await
+            .ok();
         let result_task = if let Some(some_task) = task {
             Some(
                 Self::update_task_state_query(transaction, &some_task, FangTaskState::InProgress)
@@ -380,6 +389,19 @@ where
         Ok(result_task)
     }
 
+    async fn next_scheduled_at_query(
+        transaction: &mut Transaction<'_>,
+        task_type: Option<String>,
+    ) -> Result<Option<DateTime<Utc>>, AsyncQueueError> {
+        let task_type = task_type.unwrap_or_else(|| DEFAULT_TASK_TYPE.to_string());
+
+        let row: Row = transaction
+            .query_one(NEXT_SCHEDULED_AT_QUERY, &[&task_type, &Utc::now()])
+            .await?;
+
+        Ok(row.try_get("scheduled_at")?)
+    }
+
     async fn get_task_type_query(
         transaction: &mut Transaction<'_>,
         task_type: &str,
@@ -508,6 +530,17 @@ where
         let created_at: DateTime<Utc> = row.get("created_at");
         let updated_at: DateTime<Utc> = row.get("updated_at");
         let scheduled_at: DateTime<Utc> = row.get("scheduled_at");
+        let binary_metadata: Option<Vec<u8>> = row.try_get("binary_metadata").ok();
+        let error_details: Option<serde_json::Value> = row.try_get("error_details").ok();
+        let max_retries: Option<i32> = row.try_get("max_retries").ok();
+        let deleted_at: Option<DateTime<Utc>> = row.try_get("deleted_at").ok();
+        let priority: i32 = row.try_get("priority").unwrap_or_default();
+        let tags: Option<serde_json::Value> = row.try_get("tags").ok();
+        let correlation_id: Option<Uuid> = row.try_get("correlation_id").ok();
+        let locked_until: Option<DateTime<Utc>> = row.try_get("locked_until").ok();
+        let depends_on: Option<Uuid> = row.try_get("depends_on").ok();
+        let result: Option<serde_json::Value> = row.try_get("result").ok();
+        let expires_at: Option<DateTime<Utc>> = row.try_get("expires_at").ok();
 
         Task::builder()
             .id(id)
@@ -520,6 +553,17 @@ where
             .created_at(created_at)
             .updated_at(updated_at)
             .scheduled_at(scheduled_at)
+            .binary_metadata(binary_metadata)
+            .error_details(error_details)
+            .max_retries(max_retries)
+            .deleted_at(deleted_at)
+            .priority(priority)
+            .tags(tags)
+            .correlation_id(correlation_id)
+            .locked_until(locked_until)
+            .depends_on(depends_on)
+            .result(result)
+            .expires_at(expires_at)
             .build()
     }
 
@@ -597,6 +641,21 @@ where
         Ok(task)
     }
 
+    async fn next_scheduled_at(
+        &mut self,
+        task_type: Option<String>,
+    ) -> Result<Option<DateTime<Utc>>, AsyncQueueError> {
+        self.check_if_connection()?;
+        let mut connection = self.pool.as_ref().unwrap().get().await?;
+        let mut transaction = connection.transaction().await?;
+
+        let next_scheduled_at = Self::next_scheduled_at_query(&mut transaction, task_type).await?;
+
+        transaction.commit().await?;
+
+        Ok(next_scheduled_at)
+    }
+
     async fn insert_task(&mut self, task: &dyn AsyncRunnable) -> Result<Task, AsyncQueueError> {
         self.check_if_connection()?;
         let mut connection = self.pool.as_ref().unwrap().get().await?;
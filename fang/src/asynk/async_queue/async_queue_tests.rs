@@ -181,6 +181,40 @@ macro_rules! test_asynk_queue {
                 assert_eq!(task.scheduled_at, datetime);
             }
 
+            #[tokio::test]
+            async fn next_scheduled_at_returns_the_earliest_pending_task() {
+                let mut test: $q = $e.await;
+
+                assert_eq!(None, test.next_scheduled_at(None).await.unwrap());
+
+                // the tasks that are already due are not considered
+                test.insert_task(&AsyncTask { number: 3 }).await.unwrap();
+                assert_eq!(None, test.next_scheduled_at(None).await.unwrap());
+
+                let datetime = (Utc::now() + Duration::seconds(7)).round_subsecs(0);
+
+                test.schedule_task(&AsyncTaskSchedule {
+                    number: 1,
+                    datetime: (datetime + Duration::seconds(7)).to_string(),
+                })
+                .await
+                .unwrap();
+                test.schedule_task(&AsyncTaskSchedule {
+                    number: 2,
+                    datetime: datetime.to_string(),
+                })
+                .await
+                .unwrap();
+
+                assert_eq!(Some(datetime), test.next_scheduled_at(None).await.unwrap());
+                assert_eq!(
+                    None,
+                    test.next_scheduled_at(Some("mytype".to_string()))
+                        .await
+                        .unwrap()
+                );
+            }
+
             #[tokio::test]
             async fn remove_all_scheduled_tasks_test() {
                 let mut test: $q = $e.await;
@@ -72,7 +72,6 @@ pub trait AsyncRunnable: Send + Sync {
      }
     ```
     */
-
     /// In order to schedule  a task once, use the `Scheduled::ScheduleOnce` enum variant.
     fn cron(&self) -> Option<Scheduled> {
         None
@@ -6,7 +6,11 @@ use crate::FangTaskState;
 use crate::Scheduled::*;
 use crate::Task;
 use crate::{RetentionMode, SleepParams};
+use chrono::Utc;
 use log::error;
+use std::time::Duration;
+use tokio::time::Interval;
+use tokio::time::MissedTickBehavior;
 use typed_builder::TypedBuilder;
 
 /// it executes tasks only of task_type type, it sleeps when there are no tasks in the queue
@@ -23,6 +27,12 @@ where
     pub sleep_params: SleepParams,
     #[builder(default, setter(into))]
     pub retention_mode: RetentionMode,
+    /// Wait for tasks on the ticks of a `tokio::time::interval` of the sleep period instead of sleeping,
+    /// and wake up exactly when the next scheduled task is due if it's due before the next tick.
+    #[builder(default, setter(into))]
+    pub precise_scheduling: bool,
+    #[builder(default, setter(skip))]
+    interval: Option<Interval>,
 }
 
 impl<AQueue> AsyncWorker<AQueue>
@@ -86,7 +96,60 @@ where
     async fn sleep(&mut self) {
         self.sleep_params.maybe_increase_sleep_period();
 
-        tokio::time::sleep(self.sleep_params.sleep_period).await;
+        if self.precise_scheduling {
+            self.wait_for_next_tick().await;
+        } else {
+            tokio::time::sleep(self.sleep_params.sleep_period).await;
+        }
+    }
+
+    /// Wait for the next tick of the interval or until the next scheduled task is due, whichever comes first.
+    async fn wait_for_next_tick(&mut self) {
+        let until_next_task = self.until_next_task().await;
+
+        self.wait_for_tick_or(until_next_task).await;
+    }
+
+    /// How long until the next scheduled task of the type of the worker is due.
+    async fn until_next_task(&mut self) -> Option<Duration> {
+        let next_scheduled_at = match self
+            .queue
+            .next_scheduled_at(Some(self.task_type.clone()))
+            .await
+        {
+            Ok(next_scheduled_at) => next_scheduled_at,
+            Err(error) => {
+                error!("Failed to get the next scheduled task {:?}", error);
+
+                None
+            }
+        };
+
+        // tasks that are already due but were not fetched, for example locked by other workers, wait for the tick
+        next_scheduled_at.and_then(|scheduled_at| (scheduled_at - Utc::now()).to_std().ok())
+    }
+
+    async fn wait_for_tick_or(&mut self, until_next_task: Option<Duration>) {
+        let sleep_period = self.sleep_params.sleep_period;
+
+        if self.interval.as_ref().map(Interval::period) != Some(sleep_period) {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + sleep_period, sleep_period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            self.interval = Some(interval);
+        }
+
+        if let Some(interval) = self.interval.as_mut() {
+            match until_next_task {
+                Some(until_next_task) => {
+                    let _ = tokio::time::timeout(until_next_task, interval.tick()).await;
+                }
+                None => {
+                    interval.tick().await;
+                }
+            }
+        }
     }
 
     pub(crate) async fn run_tasks(&mut self) -> Result<(), FangError> {
@@ -250,6 +313,7 @@ impl<'a> AsyncWorkerTest<'a> {
 
 #[cfg(test)]
 mod async_worker_tests {
+    use super::AsyncWorker;
     use super::AsyncWorkerTest;
     use crate::asynk::async_queue::AsyncQueue;
     use crate::asynk::async_queue::AsyncQueueable;
@@ -259,6 +323,7 @@ mod async_worker_tests {
     use crate::FangTaskState;
     use crate::RetentionMode;
     use crate::Scheduled;
+    use crate::SleepParams;
     use async_trait::async_trait;
     use bb8_postgres::tokio_postgres::NoTls;
     use chrono::Duration;
@@ -385,6 +450,69 @@ mod async_worker_tests {
         }
     }
 
+    #[derive(Serialize, Deserialize)]
+    struct PreciseTask {
+        pub datetime: String,
+    }
+
+    #[typetag::serde]
+    #[async_trait]
+    impl AsyncRunnable for PreciseTask {
+        async fn run(&self, _queueable: &mut dyn AsyncQueueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn cron(&self) -> Option<Scheduled> {
+            let datetime = self.datetime.parse::<chrono::DateTime<Utc>>().ok()?;
+            Some(Scheduled::ScheduleOnce(datetime))
+        }
+
+        fn task_type(&self) -> String {
+            "precise".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn precise_scheduling_wakes_up_when_the_next_task_is_due() {
+        let mut test = AsyncQueue::<NoTls>::test().await;
+
+        test.schedule_task(&PreciseTask {
+            datetime: (Utc::now() + Duration::hours(1)).to_string(),
+        })
+        .await
+        .unwrap();
+
+        // a sleeping worker would wake up only after two hours
+        let two_hours = std::time::Duration::from_secs(2 * 60 * 60);
+        let mut worker = AsyncWorker::<AsyncQueue<NoTls>>::builder()
+            .queue(test.clone())
+            .task_type("precise")
+            .retention_mode(RetentionMode::KeepAll)
+            .sleep_params(SleepParams {
+                sleep_period: two_hours,
+                max_sleep_period: two_hours,
+                min_sleep_period: two_hours,
+                sleep_step: std::time::Duration::ZERO,
+            })
+            .precise_scheduling(true)
+            .build();
+
+        let until_next_task = worker.until_next_task().await.unwrap();
+        assert!(until_next_task <= std::time::Duration::from_secs(60 * 60));
+
+        // the paused clock of tokio advances only to the timers of the test, no queries are made while it's paused
+        tokio::time::pause();
+
+        let wait = worker.wait_for_tick_or(Some(until_next_task));
+        tokio::pin!(wait);
+
+        let not_due = std::time::Duration::from_secs(59 * 60);
+        assert!(tokio::time::timeout(not_due, &mut wait).await.is_err());
+
+        let due = std::time::Duration::from_secs(2 * 60);
+        assert!(tokio::time::timeout(due, &mut wait).await.is_ok());
+    }
+
     #[tokio::test]
     async fn execute_and_finishes_task() {
         let mut test = AsyncQueue::<NoTls>::test().await;
@@ -28,6 +28,9 @@ where
     /// The type of tasks that will be executed by `AsyncWorkerPool`.
     #[builder(default=DEFAULT_TASK_TYPE.to_string(), setter(into))]
     pub task_type: String,
+    /// Wake up the workers exactly when scheduled tasks are due, see `AsyncWorker::precise_scheduling`.
+    #[builder(default, setter(into))]
+    pub precise_scheduling: bool,
 }
 
 impl<AQueue> AsyncWorkerPool<AQueue>
@@ -51,6 +54,7 @@ where
             pool.sleep_params.clone(),
             pool.retention_mode.clone(),
             pool.task_type.clone(),
+            pool.precise_scheduling,
         )
         .await;
 
@@ -68,9 +72,17 @@ where
         sleep_params: SleepParams,
         retention_mode: RetentionMode,
         task_type: String,
+        precise_scheduling: bool,
     ) -> JoinHandle<Result<(), FangError>> {
         tokio::spawn(async move {
-            Self::run_worker(queue, sleep_params, retention_mode, task_type).await
+            Self::run_worker(
+                queue,
+                sleep_params,
+                retention_mode,
+                task_type,
+                precise_scheduling,
+            )
+            .await
         })
     }
     async fn run_worker(
@@ -78,12 +90,14 @@ where
         sleep_params: SleepParams,
         retention_mode: RetentionMode,
         task_type: String,
+        precise_scheduling: bool,
     ) -> Result<(), FangError> {
         let mut worker: AsyncWorker<AQueue> = AsyncWorker::builder()
             .queue(queue)
             .sleep_params(sleep_params)
             .retention_mode(retention_mode)
             .task_type(task_type)
+            .precise_scheduling(precise_scheduling)
             .build();
 
         worker.run_tasks().await
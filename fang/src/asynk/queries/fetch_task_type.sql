@@ -1 +1 @@
-SELECT * FROM fang_tasks  WHERE task_type = $1 AND state in ('new', 'retried') AND $2 >= scheduled_at  ORDER BY created_at ASC, scheduled_at ASC LIMIT 1 FOR UPDATE SKIP LOCKED
+SELECT * FROM fang_tasks  WHERE task_type = $1 AND state in ('new', 'retried') AND $2 >= scheduled_at AND NOT EXISTS (SELECT 1 FROM fang_paused_task_types WHERE task_type = $1) AND (fang_tasks.depends_on IS NULL OR EXISTS (SELECT 1 FROM fang_tasks AS dependency WHERE dependency.id = fang_tasks.depends_on AND dependency.state = 'finished')) AND (expires_at IS NULL OR expires_at > $2) ORDER BY priority DESC, created_at ASC, scheduled_at ASC LIMIT 1 FOR UPDATE SKIP LOCKED
@@ -0,0 +1 @@
+SELECT min(scheduled_at) AS scheduled_at FROM fang_tasks WHERE task_type = $1 AND state in ('new', 'retried') AND deleted_at IS NULL AND scheduled_at > $2 AND (expires_at IS NULL OR expires_at > $2) AND NOT EXISTS (SELECT 1 FROM fang_paused_task_types WHERE task_type = $1)
@@ -1,14 +1,24 @@
+pub mod clock;
+pub mod codec;
 mod error;
 pub mod mysql_schema;
 pub mod postgres_schema;
 pub mod queue;
 pub mod runnable;
+#[cfg(feature = "json-schema")]
+pub mod schema_registry;
 pub mod sqlite_schema;
+pub mod typed_queue;
 pub mod worker;
 pub mod worker_pool;
 
+pub use clock::{Clock, MockClock, SystemClock};
+pub use codec::MetadataCodec;
 pub use postgres_schema::*;
 pub use queue::*;
 pub use runnable::Runnable;
+#[cfg(feature = "json-schema")]
+pub use schema_registry::SchemaRegistry;
+pub use typed_queue::TypedQueue;
 pub use worker::*;
 pub use worker_pool::*;
@@ -0,0 +1,62 @@
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// The source of the current time of a queue, see `Queue::clock`.
+///
+/// A queue with a clock uses it for all the times it computes: the creation, update and `scheduled_at` times
+/// of tasks, the expiration of tasks and leases, and the time tasks are fetched at. Use a [`MockClock`] in tests
+/// to move the time forward instead of sleeping.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock, `Utc::now()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that stands still until it's moved, clones of a `MockClock` share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<DateTime<Utc>>>,
+}
+
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(now)),
+        }
+    }
+
+    /// Move the time of the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+
+        *now += duration;
+    }
+
+    /// Set the time of the clock.
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(Utc::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,110 @@
+use crate::runnable::Runnable;
+use crate::Task;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use uuid::Uuid;
+
+/// Serialization formats that can be used to store the metadata of tasks.
+///
+/// The default codec is [`MetadataCodec::Json`], which stores the metadata in the `metadata` JSONB column.
+/// Binary codecs store the encoded metadata in the `binary_metadata` column and leave JSON `null` in `metadata`.
+///
+/// Only self-describing formats are supported because tasks are deserialized with `typetag`,
+/// that's why there is no `bincode` codec.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum MetadataCodec {
+    /// Store metadata as JSON
+    #[default]
+    Json,
+    /// Store metadata as CBOR
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// List of error types that can occur while encoding or decoding metadata of tasks.
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[cfg(feature = "cbor")]
+    #[error("CBOR error: {0}")]
+    CborError(String),
+    #[error("Task {0} does not have binary metadata")]
+    MissingBinaryMetadata(Uuid),
+}
+
+/// The metadata of a task encoded with a [`MetadataCodec`]
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EncodedMetadata {
+    /// The value of the `metadata` column
+    pub metadata: serde_json::Value,
+    /// The value of the `binary_metadata` column
+    pub binary_metadata: Option<Vec<u8>>,
+}
+
+impl EncodedMetadata {
+    /// The bytes used to calculate the `uniq_hash` of a task
+    pub fn hash_input(&self) -> Vec<u8> {
+        match &self.binary_metadata {
+            Some(bytes) => bytes.clone(),
+            None => self.metadata.to_string().into_bytes(),
+        }
+    }
+}
+
+impl MetadataCodec {
+    pub fn encode(&self, runnable: &dyn Runnable) -> Result<EncodedMetadata, CodecError> {
+        match self {
+            MetadataCodec::Json => Ok(EncodedMetadata {
+                metadata: serde_json::to_value(runnable)?,
+                binary_metadata: None,
+            }),
+            #[cfg(feature = "cbor")]
+            MetadataCodec::Cbor => {
+                let mut bytes = Vec::new();
+
+                ciborium::into_writer(runnable, &mut bytes)
+                    .map_err(|error| CodecError::CborError(error.to_string()))?;
+
+                Ok(EncodedMetadata {
+                    metadata: serde_json::Value::Null,
+                    binary_metadata: Some(bytes),
+                })
+            }
+        }
+    }
+
+    pub fn decode(&self, task: &Task) -> Result<Box<dyn Runnable>, CodecError> {
+        match self {
+            MetadataCodec::Json => Ok(serde_json::from_value(task.metadata.clone())?),
+            #[cfg(feature = "cbor")]
+            MetadataCodec::Cbor => {
+                let bytes = task
+                    .binary_metadata
+                    .as_ref()
+                    .ok_or(CodecError::MissingBinaryMetadata(task.id))?;
+
+                ciborium::from_reader(bytes.as_slice())
+                    .map_err(|error| CodecError::CborError(error.to_string()))
+            }
+        }
+    }
+
+    /// Decode the metadata of a task into a concrete type instead of a `Box<dyn Runnable>`.
+    /// The `type` tag added by `typetag` is ignored unless `T` denies unknown fields.
+    pub fn decode_as<T: DeserializeOwned>(&self, task: &Task) -> Result<T, CodecError> {
+        match self {
+            MetadataCodec::Json => Ok(serde_json::from_value(task.metadata.clone())?),
+            #[cfg(feature = "cbor")]
+            MetadataCodec::Cbor => {
+                let bytes = task
+                    .binary_metadata
+                    .as_ref()
+                    .ok_or(CodecError::MissingBinaryMetadata(task.id))?;
+
+                ciborium::from_reader(bytes.as_slice())
+                    .map_err(|error| CodecError::CborError(error.to_string()))
+            }
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use crate::blocking::codec::CodecError;
 use crate::blocking::queue::QueueError;
 use crate::FangError;
 use diesel::r2d2::PoolError;
@@ -20,7 +21,7 @@ impl From<QueueError> for FangError {
 
 impl From<DieselError> for FangError {
     fn from(error: DieselError) -> Self {
-        Self::from(QueueError::DieselError(error))
+        Self::from(QueueError::from(error))
     }
 }
 
@@ -29,3 +30,9 @@ impl From<PoolError> for FangError {
         Self::from(QueueError::PoolError(error))
     }
 }
+
+impl From<CodecError> for FangError {
+    fn from(error: CodecError) -> Self {
+        Self::from(QueueError::CodecError(error))
+    }
+}
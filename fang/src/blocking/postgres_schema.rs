@@ -1,11 +1,35 @@
 // @generated automatically by Diesel CLI.
 
 pub mod sql_types {
-    #[derive(diesel::sql_types::SqlType)]
+    #[derive(diesel::query_builder::QueryId, diesel::sql_types::SqlType)]
     #[diesel(postgres_type(name = "fang_task_state"))]
     pub struct FangTaskState;
 }
 
+diesel::table! {
+    fang_control (id) {
+        id -> Bool,
+        inserts_paused -> Bool,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    fang_interrupted (id) {
+        id -> Uuid,
+        task_id -> Uuid,
+        task_type -> Varchar,
+        interrupted_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    fang_paused_task_types (task_type) {
+        task_type -> Varchar,
+        paused_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     use diesel::sql_types::*;
     use super::sql_types::FangTaskState;
@@ -22,5 +46,16 @@ diesel::table! {
         scheduled_at -> Timestamptz,
         created_at -> Timestamptz,
         updated_at -> Timestamptz,
+        binary_metadata -> Nullable<Bytea>,
+        error_details -> Nullable<Jsonb>,
+        max_retries -> Nullable<Int4>,
+        deleted_at -> Nullable<Timestamptz>,
+        priority -> Int4,
+        tags -> Nullable<Jsonb>,
+        correlation_id -> Nullable<Uuid>,
+        locked_until -> Nullable<Timestamptz>,
+        depends_on -> Nullable<Uuid>,
+        result -> Nullable<Jsonb>,
+        expires_at -> Nullable<Timestamptz>,
     }
 }
@@ -1,9 +1,19 @@
 #[cfg(test)]
 mod queue_tests;
 
+use crate::clock::Clock;
+use crate::codec::CodecError;
+use crate::codec::MetadataCodec;
+use crate::postgres_schema::fang_control;
+use crate::postgres_schema::fang_interrupted;
+use crate::postgres_schema::fang_paused_task_types;
 use crate::postgres_schema::fang_tasks;
 use crate::runnable::Runnable;
+use crate::runnable::COMMON_TYPE;
+#[cfg(feature = "json-schema")]
+use crate::schema_registry::SchemaRegistry;
 use crate::CronError;
+use crate::FangError;
 use crate::FangTaskState;
 use crate::Scheduled::*;
 use crate::Task;
@@ -11,16 +21,33 @@ use chrono::DateTime;
 use chrono::Duration;
 use chrono::Utc;
 use cron::Schedule;
+use diesel::connection::AnsiTransactionManager;
+use diesel::connection::TransactionManager;
+use diesel::dsl::count_star;
+use diesel::dsl::sql;
+use diesel::expression::SqlLiteral;
+use diesel::expression::UncheckedBind;
+use diesel::pg::Pg;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::r2d2;
 use diesel::r2d2::ConnectionManager;
 use diesel::r2d2::PoolError;
 use diesel::r2d2::PooledConnection;
+use diesel::result::DatabaseErrorKind;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::Bool;
+use diesel::sql_types::Double;
+use diesel::sql_types::Nullable;
+use diesel::sql_types::Text;
+use diesel::sql_types::Timestamptz;
+use serde::de::DeserializeOwned;
 use sha2::Digest;
 use sha2::Sha256;
+use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::str::FromStr;
+use std::sync::Arc;
 use thiserror::Error;
 use typed_builder::TypedBuilder;
 use uuid::Uuid;
@@ -32,9 +59,59 @@ use std::env;
 
 pub type PoolConnection = PooledConnection<ConnectionManager<PgConnection>>;
 
+/// A boxed query selecting tasks from the `fang_tasks` table, see [`Queue::runnable_tasks_query`].
+pub type BoxedTaskQuery<'a> = fang_tasks::BoxedQuery<'a, Pg>;
+
+type PendingFilter = diesel::dsl::And<
+    diesel::dsl::EqAny<fang_tasks::state, Vec<FangTaskState>>,
+    diesel::dsl::IsNull<fang_tasks::deleted_at>,
+>;
+/// An SQL expression with the time the tasks are compared with bound to it, see [`Queue::time_of`].
+type WithTime<ST> = SqlLiteral<
+    ST,
+    UncheckedBind<
+        SqlLiteral<ST>,
+        diesel::dsl::AsExprOf<Option<DateTime<Utc>>, Nullable<Timestamptz>>,
+    >,
+>;
+type RunnableFilter = diesel::dsl::And<
+    diesel::dsl::And<
+        diesel::dsl::And<
+            PendingFilter,
+            diesel::dsl::LtEq<fang_tasks::scheduled_at, WithTime<Timestamptz>>,
+        >,
+        SqlLiteral<Bool>,
+    >,
+    WithTime<Bool>,
+>;
+
+/// Runs a setup function on every connection established by the pool of a [`Queue`],
+/// see [`Queue::new_with_setup`].
+struct ConnectionSetup<F> {
+    setup: F,
+}
+
+impl<F> std::fmt::Debug for ConnectionSetup<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ConnectionSetup").finish_non_exhaustive()
+    }
+}
+
+impl<F> r2d2::CustomizeConnection<PgConnection, r2d2::Error> for ConnectionSetup<F>
+where
+    F: Fn(&mut PgConnection) -> Result<(), DieselError> + Send + Sync + 'static,
+{
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), r2d2::Error> {
+        (self.setup)(connection).map_err(r2d2::Error::QueryError)
+    }
+}
+
 #[derive(Insertable, Debug, Eq, PartialEq, Clone, TypedBuilder)]
 #[diesel(table_name = fang_tasks)]
 pub struct NewTask {
+    /// the id of the task, it's generated by the queue or the database if it's not set.
+    #[builder(default, setter(into))]
+    id: Option<Uuid>,
     #[builder(setter(into))]
     metadata: serde_json::Value,
     #[builder(setter(into))]
@@ -43,18 +120,267 @@ pub struct NewTask {
     uniq_hash: Option<String>,
     #[builder(setter(into))]
     scheduled_at: DateTime<Utc>,
+    #[builder(setter(into))]
+    binary_metadata: Option<Vec<u8>>,
+    /// the creation time of the task, the current time of the database is used if it's not set.
+    #[builder(default, setter(into))]
+    created_at: Option<DateTime<Utc>>,
+    /// always the same as `created_at`, so `updated_at == created_at` tells that the task was never updated.
+    #[builder(default = created_at, setter(skip))]
+    updated_at: Option<DateTime<Utc>>,
+    /// the number of retries of the task, `Runnable::max_retries` is used if it's not set.
+    #[builder(default, setter(into))]
+    max_retries: Option<i32>,
+    /// the priority of the task, `0` if it's not set.
+    #[builder(default, setter(into))]
+    priority: Option<i32>,
+    /// key/value tags of the task for routing and observability, they are not passed to the task.
+    #[builder(default, setter(into))]
+    tags: Option<serde_json::Value>,
+    /// the id of the workflow of the task, see `Queueable::insert_child`.
+    #[builder(default, setter(into))]
+    correlation_id: Option<Uuid>,
+    /// the id of the task that must be finished before this task is fetched, see `Queueable::insert_after`.
+    #[builder(default, setter(into))]
+    depends_on: Option<Uuid>,
+    /// the time after which the task is not fetched anymore, see `Queueable::insert_with_ttl`.
+    #[builder(default, setter(into))]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Isolation levels of the transaction used to fetch a task and update its state.
+///
+/// The default isolation level is [`IsolationLevel::ReadCommitted`].
+/// `SKIP LOCKED` already prevents two workers from fetching the same task, so stronger levels are only
+/// useful if you need extra guarantees about the rows read inside the transaction.
+/// Be aware that under `RepeatableRead` and `Serializable` concurrent workers can fail with
+/// serialization errors, which lowers the throughput when there is a lot of contention.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+/// Row locks taken by [`Queueable::fetch_task_with_lock`].
+///
+/// The default lock mode is [`LockMode::ForUpdate`], which is used by workers.
+/// The locks are held until the end of the transaction.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum LockMode {
+    /// Don't lock the fetched task
+    None,
+    /// `FOR UPDATE SKIP LOCKED`, the task is skipped if it's locked by another transaction
+    #[default]
+    ForUpdate,
+    /// `FOR SHARE`, other transactions can read and share-lock the task but they can't modify it
+    ForShare,
+    /// `FOR UPDATE NOWAIT`, an error is returned immediately if the next task is locked by another transaction
+    NoWait,
+}
+
+impl NewTask {
+    /// Build a `NewTask` from a `Runnable`. The metadata is serialized as JSON and the `task_type`
+    /// is taken from the `Runnable`, so producers and workers always agree on the type of the task.
+    /// The task is scheduled to be executed as soon as possible.
+    pub fn from_runnable(job: &dyn Runnable) -> Result<Self, QueueError> {
+        Self::from_runnable_with_codec(job, &MetadataCodec::default(), Utc::now())
+    }
+
+    /// Build a `NewTask` from a `Runnable` encoding its metadata with the specified codec.
+    pub fn from_runnable_with_codec(
+        job: &dyn Runnable,
+        codec: &MetadataCodec,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<Self, QueueError> {
+        let encoded = codec.encode(job)?;
+
+        let uniq_hash = if job.uniq() {
+            Some(Queue::calculate_hash(&encoded.hash_input()))
+        } else {
+            None
+        };
+
+        Ok(NewTask::builder()
+            .scheduled_at(scheduled_at)
+            .uniq_hash(uniq_hash)
+            .task_type(job.task_type())
+            .metadata(encoded.metadata)
+            .binary_metadata(encoded.binary_metadata)
+            .build())
+    }
+
+    /// Override the creation time of the task, for example to backfill historical tasks.
+    /// Tasks of the same priority are fetched in the `created_at` order, so this also changes the position of the task in the queue.
+    pub fn with_created_at(mut self, created_at: DateTime<Utc>) -> Self {
+        self.created_at = Some(created_at);
+        self.updated_at = Some(created_at);
+        self
+    }
+
+    /// Override the number of retries of the task, instead of the value returned by `Runnable::max_retries`.
+    pub fn with_max_retries(mut self, max_retries: i32) -> Self {
+        self.max_retries = Some(max_retries);
+        self
+    }
+
+    /// Set the priority of the task.
+    pub fn with_priority(mut self, priority: i32) -> Self {
+        self.priority = Some(priority);
+        self
+    }
+
+    /// Set the tags of the task, for example `json!({"tenant": 42, "source": "import"})`.
+    pub fn with_tags(mut self, tags: serde_json::Value) -> Self {
+        self.tags = Some(tags);
+        self
+    }
+
+    /// Set the id of the task instead of the generated one.
+    pub fn with_id(mut self, id: Uuid) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    /// Set the correlation id of the task, the tasks of one workflow share it.
+    pub fn with_correlation_id(mut self, correlation_id: Uuid) -> Self {
+        self.correlation_id = Some(correlation_id);
+        self
+    }
+
+    /// Set the id of the task that must be finished before this task is fetched.
+    pub fn with_depends_on(mut self, depends_on: Uuid) -> Self {
+        self.depends_on = Some(depends_on);
+        self
+    }
+
+    /// Set the time after which the task is not fetched anymore if it wasn't executed.
+    pub fn with_expires_at(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+}
+
+/// A generator of the ids of inserted tasks, see `Queue::id_generator`.
+/// For example `Arc::new(Uuid::now_v7)` with the `v7` feature of the `uuid` crate.
+pub type IdGenerator = Arc<dyn Fn() -> Uuid + Send + Sync>;
+
+/// The state of the connection pool of a queue, see [`Queue::pool_state`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PoolState {
+    /// the number of connections currently managed by the pool
+    pub connections: u32,
+    /// the number of idle connections, the pool is saturated when it's zero
+    pub idle_connections: u32,
+}
+
+/// A task that a worker was still executing when it was shut down, see `Shutdown::shutdown`.
+#[derive(Queryable, Clone, Debug, Eq, PartialEq)]
+#[diesel(table_name = fang_interrupted)]
+pub struct InterruptedTask {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub task_type: String,
+    pub interrupted_at: DateTime<Utc>,
+}
+
+/// The lifecycle information of a task without its metadata, see [`Queueable::task_status`].
+#[derive(Queryable, Clone, Debug, Eq, PartialEq)]
+#[diesel(table_name = fang_tasks)]
+pub struct TaskStatus {
+    pub id: Uuid,
+    pub state: FangTaskState,
+    pub retries: i32,
+    pub error_message: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Error)]
 pub enum QueueError {
     #[error(transparent)]
-    DieselError(#[from] DieselError),
+    DieselError(DieselError),
+    #[error("The tables of fang do not exist, please run the migrations of fang, for example with `fang::run_migrations_postgres`")]
+    SchemaNotInitialized,
     #[error(transparent)]
     PoolError(#[from] PoolError),
     #[error(transparent)]
     CronError(#[from] CronError),
+    #[error(transparent)]
+    CodecError(#[from] CodecError),
     #[error("Can not perform this operation if task is not uniq, please check its definition in impl Runnable")]
     TaskNotUniqError,
+    #[error("Task {0} does not exist or it is not in the New or Retried state")]
+    TaskNotPendingError(Uuid),
+    #[error("The queue is paused, new tasks can not be inserted")]
+    QueuePausedError,
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+    #[error("The JSON Schema is not valid: {0}")]
+    InvalidSchemaError(String),
+    #[error("The metadata of a task of type {0} does not match its schema: {1}")]
+    MetadataValidationError(String, String),
+    #[error("The metadata of a task of type {0} is null")]
+    NullMetadataError(String),
+    #[error("The payload of a task of type {0} is not valid: {1}")]
+    InvalidPayloadError(String, String),
+    #[error("The lease of task {0} expired, the task may be executed by another worker")]
+    LeaseLostError(Uuid),
+}
+
+/// The tables created by the migrations of fang.
+const FANG_TABLES: [&str; 4] = [
+    "fang_tasks",
+    "fang_control",
+    "fang_interrupted",
+    "fang_paused_task_types",
+];
+
+/// The functions created by the migrations of fang that the queue calls.
+const FANG_FUNCTIONS: [&str; 2] = ["fang_fetch_task", "fang_create_task_type_partition"];
+
+impl From<DieselError> for QueueError {
+    fn from(error: DieselError) -> Self {
+        match &error {
+            DieselError::DatabaseError(DatabaseErrorKind::Unknown, info)
+                if is_undefined_fang_object(info.message()) =>
+            {
+                QueueError::SchemaNotInitialized
+            }
+            _ => QueueError::DieselError(error),
+        }
+    }
+}
+
+/// `undefined_table` (42P01) and `undefined_function` (42883) errors on the tables and functions of fang.
+///
+/// diesel reports them as `DatabaseErrorKind::Unknown` and doesn't expose the SQLSTATE, so the errors are
+/// recognized by their messages, `relation "<table>" does not exist` and `function <name>(<types>) does not exist`.
+/// Missing columns and the objects that don't belong to fang are not matched.
+fn is_undefined_fang_object(message: &str) -> bool {
+    let object = match message.strip_suffix(" does not exist") {
+        Some(object) => object,
+        None => return false,
+    };
+
+    if let Some(relation) = object
+        .strip_prefix("relation \"")
+        .and_then(|rest| rest.strip_suffix('"'))
+    {
+        // the relation may be qualified with the schema
+        let table = relation.rsplit('.').next().unwrap_or(relation);
+
+        return FANG_TABLES.contains(&table);
+    }
+
+    match object
+        .strip_prefix("function ")
+        .and_then(|function| function.split_once('('))
+    {
+        Some((name, _)) => FANG_FUNCTIONS.contains(&name.rsplit('.').next().unwrap_or(name)),
+        None => false,
+    }
 }
 
 impl From<cron::error::Error> for QueueError {
@@ -69,12 +395,228 @@ impl From<cron::error::Error> for QueueError {
 pub trait Queueable {
     /// This method should retrieve one task of the `task_type` type. After fetching it should update the state
     /// of the task to `FangTaskState::InProgress`.
+    /// Tasks with a higher `priority` are fetched first, tasks of the same priority in the order of insertion.
     fn fetch_and_touch_task(&self, task_type: String) -> Result<Option<Task>, QueueError>;
 
+    /// The same as `fetch_and_touch_task` but a random task is picked among the tasks that can be executed,
+    /// so concurrent workers don't compete for the oldest rows. Tasks are not executed in the order of insertion.
+    fn fetch_and_touch_random_task(&self, task_type: String) -> Result<Option<Task>, QueueError>;
+
+    /// The same as `fetch_and_touch_task` but the task with the lowest priority is picked first,
+    /// so a dedicated worker can drain the low-priority backlog without competing with high-priority work.
+    fn fetch_and_touch_lowest_priority_task(
+        &self,
+        task_type: String,
+    ) -> Result<Option<Task>, QueueError>;
+
+    /// The same as `fetch_and_touch_task` but the task is picked from the type with the largest number of tasks
+    /// that can be executed now, whatever the type is, to keep the backlogs of all types even.
+    /// The tasks of paused types are not fetched.
+    fn fetch_and_touch_longest_backlog_task(&self) -> Result<Option<Task>, QueueError>;
+
+    /// The next task of the `task_type` type that would be fetched by a worker, without locking it or changing its state.
+    fn peek_next_task(&self, task_type: String) -> Result<Option<Task>, QueueError>;
+
+    /// The number of tasks of the `task_type` type that can be executed now, tasks in the
+    /// `FangTaskState::New` or `FangTaskState::Retried` states which are not scheduled in the future.
+    fn count_runnable_tasks(&self, task_type: String) -> Result<i64, QueueError>;
+
+    /// The number of runnable tasks of the same type ahead of the task with the `id` id in the order of
+    /// `fetch_and_touch_task`, `0` if it's the next task to be fetched. Returns `None` if the task doesn't exist
+    /// or is not in the `FangTaskState::New` or `FangTaskState::Retried` states.
+    fn task_position(&self, id: Uuid) -> Result<Option<i64>, QueueError>;
+
+    /// Fetch the next task of the `task_type` type without changing its state,
+    /// locking it according to the `lock_mode`.
+    fn fetch_task_with_lock(
+        &self,
+        task_type: String,
+        lock_mode: LockMode,
+    ) -> Result<Option<Task>, QueueError>;
+
+    /// Fetch up to `limit` tasks of the `task_type` type in the order of `fetch_and_touch_task`
+    /// and mark them as `FangTaskState::InProgress` in one transaction.
+    fn fetch_and_touch_tasks(&self, task_type: String, limit: i64)
+        -> Result<Vec<Task>, QueueError>;
+
+    /// The same as `fetch_and_touch_tasks` but the metadata of every task is decoded into `T`.
+    /// The tasks that can not be decoded are failed and skipped.
+    fn claim_tasks<T>(&self, task_type: String, limit: i64) -> Result<Vec<(Task, T)>, QueueError>
+    where
+        T: DeserializeOwned,
+        Self: Sized,
+    {
+        let codec = self.codec();
+        let mut claimed = Vec::new();
+
+        for task in self.fetch_and_touch_tasks(task_type, limit)? {
+            match codec.decode_as::<T>(&task) {
+                Ok(job) => claimed.push((task, job)),
+                Err(error) => {
+                    self.fail_task(&task, &error.to_string())?;
+                }
+            }
+        }
+
+        Ok(claimed)
+    }
+
+    /// The same as `fetch_and_touch_task` but the supplied `now` is compared with `scheduled_at`
+    /// instead of the time of the clock of the queue or of the database, so scheduling can be tested without sleeping.
+    fn fetch_task_at(
+        &self,
+        task_type: String,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError>;
+
+    /// The same as `fetch_and_touch_task` but the task is fetched among the tasks of all the `task_types` types,
+    /// so one worker can execute a set of task types. The paused task types are skipped.
+    fn fetch_task_of_types(&self, task_types: &[&str]) -> Result<Option<Task>, QueueError>;
+
+    /// The same as `fetch_and_touch_task` but only the tasks with the `key` tag set to `value` are fetched.
+    fn fetch_task_by_tag(
+        &self,
+        task_type: String,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<Option<Task>, QueueError>;
+
+    /// Fetch the oldest pending task of the `task_type` type and mark it as `FangTaskState::InProgress`,
+    /// but only if there are no tasks of this type in progress. Concurrent fetches are serialized with an
+    /// advisory lock, so tasks are executed strictly in the `created_at` order even by workers in different processes.
+    /// Nothing is fetched while the oldest task is scheduled in the future.
+    fn fetch_and_touch_task_in_order(&self, task_type: String) -> Result<Option<Task>, QueueError>;
+
+    /// Fetch a task of the `task_type` type and mark it as `FangTaskState::Finished` in a single statement.
+    /// It's meant for very high volume tasks that don't do anything besides being recorded,
+    /// the returned task is already finished so it must not be executed.
+    fn fetch_and_finish_task(&self, task_type: String) -> Result<Option<Task>, QueueError>;
+
+    /// Fetch a task of the `task_type` type, mark it as `FangTaskState::InProgress` and lease it for `lease`,
+    /// no transaction is held while the task is executed. The task is requeued by `Queueable::requeue_expired_leases`
+    /// if it's still in progress when the lease expired, so the lease must be longer than the execution of the task.
+    fn fetch_with_lease(
+        &self,
+        task_type: &str,
+        lease: Duration,
+    ) -> Result<Option<Task>, QueueError>;
+
+    /// Requeue the tasks in the `FangTaskState::InProgress` state whose lease expired, see `Queueable::fetch_with_lease`.
+    /// The requeued tasks are in the `FangTaskState::New` state again. Returns the number of requeued tasks.
+    fn requeue_expired_leases(&self) -> Result<usize, QueueError>;
+
+    /// Release the lease of a task fetched by `Queueable::fetch_with_lease` before its outcome is stored,
+    /// so the task isn't requeued anymore. `QueueError::LeaseLostError` is returned if the lease expired
+    /// or the task was requeued and fetched again, the outcome must be discarded then.
+    /// Tasks without a lease are not changed.
+    fn release_lease(&self, task: &Task) -> Result<(), QueueError>;
+
+    /// Move a task that is still executed to the `FangTaskState::Retried` state, so other workers pick it up.
+    /// Only the task in the `FangTaskState::InProgress` state that wasn't updated since `task` was fetched
+    /// is requeued, `None` is returned if it was finished, failed or requeued in the meantime.
+    fn requeue_task(&self, task: &Task) -> Result<Option<Task>, QueueError>;
+
     /// Enqueue a task to the queue, The task will be executed as soon as possible by the worker of the same type
     /// created by an `WorkerPool`.
     fn insert_task(&self, params: &dyn Runnable) -> Result<Task, QueueError>;
 
+    /// Enqueue a task with JSON `metadata` in one call. The options that are `None` get their defaults:
+    /// the default task type of the queue, priority `0` and the current time as `scheduled_at`.
+    fn insert_full(
+        &self,
+        metadata: serde_json::Value,
+        task_type: Option<String>,
+        priority: Option<i32>,
+        scheduled_at: Option<DateTime<Utc>>,
+    ) -> Result<Task, QueueError>;
+
+    /// Enqueue an already built `NewTask`. If the task has a `uniq_hash` and there is a pending task with
+    /// the same hash, the existing task is returned.
+    fn insert_new_task(&self, new_task: &NewTask) -> Result<Task, QueueError>;
+
+    /// The same as `insert_new_task` but only the `id` of the task is returned,
+    /// so the rest of the row doesn't have to be sent back and deserialized.
+    fn insert_returning_id(&self, new_task: &NewTask) -> Result<Uuid, QueueError>;
+
+    /// Enqueue a task with a coalescing `key`. If there is a pending task of the same type with the same key,
+    /// its metadata is replaced with the metadata of the `task` instead, so multiple enqueues before
+    /// the task is executed result in a single execution with the latest metadata.
+    /// The key is stored as the `uniq_hash` of the task.
+    fn insert_coalesce(&self, key: &str, task: &dyn Runnable) -> Result<Task, QueueError>;
+
+    /// Enqueue a task unless an identical task, with the same `uniq_hash`, was created less than `window` ago,
+    /// in any state. Otherwise the latest identical task is returned, so the task is enqueued at most once per `window`.
+    /// The `uniq_hash` is calculated even if the task isn't `uniq`.
+    fn insert_throttled(&self, task: &dyn Runnable, window: Duration) -> Result<Task, QueueError>;
+
+    /// Enqueue a task of the workflow of the `parent` task. The correlation id of the parent is propagated
+    /// to the new task, a parent without a correlation id is the first task of the workflow and its id is used.
+    fn insert_child(&self, parent: &Task, task: &dyn Runnable) -> Result<Task, QueueError>;
+
+    /// Enqueue a task that is not fetched until the `depends_on` task is finished.
+    /// The task is never fetched if the `depends_on` task doesn't exist or it's removed before it's finished.
+    fn insert_after(&self, task: &dyn Runnable, depends_on: Uuid) -> Result<Task, QueueError>;
+
+    /// Enqueue a task that is valuable only for the `ttl` time, for example a one-time password.
+    /// The task is not fetched after it expired and `expire_tasks` moves it to the `FangTaskState::Expired` state.
+    fn insert_with_ttl(&self, task: &dyn Runnable, ttl: Duration) -> Result<Task, QueueError>;
+
+    /// Move the tasks in the `FangTaskState::New` or `FangTaskState::Retried` states whose expiration time passed
+    /// to the `FangTaskState::Expired` state, see `insert_with_ttl`. Returns the number of expired tasks.
+    fn expire_tasks(&self) -> Result<usize, QueueError>;
+
+    /// All the tasks of a workflow, see `Queueable::insert_child`, ordered by `created_at`.
+    /// The first task of the workflow is the task with the `correlation_id` id.
+    fn fetch_tasks_by_correlation(&self, correlation_id: Uuid) -> Result<Vec<Task>, QueueError>;
+
+    /// Insert the tasks of an iterator in chunks of `chunk_size` tasks, every chunk in its own transaction,
+    /// so large backfills don't exceed the limits of a single statement. Returns the number of inserted tasks.
+    /// Unlike `insert_new_task`, the `uniq_hash` of the tasks is not checked against the existing tasks.
+    fn insert_stream<I>(&self, tasks: I, chunk_size: usize) -> Result<usize, QueueError>
+    where
+        I: Iterator<Item = NewTask>,
+        Self: Sized;
+
+    /// Pause the queue for producers, while it's paused all inserts return `QueueError::QueuePausedError`.
+    /// Workers keep executing the tasks that are already in the queue.
+    fn pause_inserts(&self) -> Result<(), QueueError>;
+
+    /// Allow inserting tasks again after `pause_inserts` was called.
+    fn resume_inserts(&self) -> Result<(), QueueError>;
+
+    /// Check if the inserts are paused.
+    fn inserts_paused(&self) -> Result<bool, QueueError>;
+
+    /// Stop fetching the tasks of the `task_type` type, fetches of this type return no tasks until
+    /// `resume_task_type` is called. The tasks of other types are fetched as usual and inserts are not affected.
+    fn pause_task_type(&self, task_type: &str) -> Result<(), QueueError>;
+
+    /// Fetch the tasks of the `task_type` type again after `pause_task_type` was called.
+    fn resume_task_type(&self, task_type: &str) -> Result<(), QueueError>;
+
+    /// Check if the fetches of the `task_type` type are paused.
+    fn task_type_paused(&self, task_type: &str) -> Result<bool, QueueError>;
+
+    /// Record the tasks that were interrupted by the shutdown of a worker in the `fang_interrupted` log.
+    /// The state of the tasks is not changed. Returns the number of recorded tasks.
+    fn record_interrupted(&self, tasks: &[Task]) -> Result<usize, QueueError>;
+
+    /// The log of the interrupted tasks, ordered by `interrupted_at`.
+    fn interrupted_tasks(&self) -> Result<Vec<InterruptedTask>, QueueError>;
+
+    /// Create a separate partition of `fang_tasks` for the tasks of the `task_type` type,
+    /// named `fang_tasks_<task_type>`. Tasks of types without a partition are stored in `fang_tasks_default`.
+    /// The partition can be created only while the queue doesn't contain tasks of this type.
+    fn create_task_type_partition(&self, task_type: &str) -> Result<(), QueueError>;
+
+    /// Serialize all tasks in the `state` state to a JSON array, ordered by `created_at`.
+    /// It can be used to back up a part of the queue, see `import_tasks`.
+    fn export_tasks(&self, state: FangTaskState) -> Result<String, QueueError>;
+
+    /// Insert the tasks from a JSON array created by `export_tasks`, keeping all their columns.
+    /// Tasks that already exist are skipped. Returns the number of inserted tasks.
+    fn import_tasks(&self, json: &str) -> Result<usize, QueueError>;
+
     /// The method will remove all tasks from the queue
     fn remove_all_tasks(&self) -> Result<usize, QueueError>;
 
@@ -87,13 +629,41 @@ pub trait Queueable {
     /// Remove a task by its id.
     fn remove_task(&self, id: Uuid) -> Result<usize, QueueError>;
 
+    /// Keep the size of the table bounded. If there are more than `threshold` tasks, the oldest tasks in
+    /// the `FangTaskState::Finished` state are removed until `target` tasks are left or there are no finished tasks.
+    /// Soft-deleted tasks are neither counted nor removed, nothing is removed if `target` isn't less than the count.
+    /// It can be called periodically, for example by a scheduled task. Returns the number of removed tasks.
+    fn prune_finished_tasks(&self, threshold: i64, target: i64) -> Result<usize, QueueError>;
+
     /// To use this function task has to be uniq. uniq() has to return true.
     /// If task is not uniq this function will not do anything.
     /// Remove a task by its metadata (struct fields values)
     fn remove_task_by_metadata(&self, task: &dyn Runnable) -> Result<usize, QueueError>;
 
+    /// Find a task by its id. Soft-deleted tasks are not returned.
     fn find_task_by_id(&self, id: Uuid) -> Option<Task>;
 
+    /// Find a task by its id, soft-deleted tasks are returned only if `include_deleted` is `true`.
+    fn find_task_by_id_with_deleted(&self, id: Uuid, include_deleted: bool) -> Option<Task>;
+
+    /// Check if there is a task in the `FangTaskState::New` or `FangTaskState::Retried` state with the `uniq_hash`,
+    /// so producers can skip building the payload of a task which is already queued.
+    fn pending_task_exists(&self, uniq_hash: &str) -> Result<bool, QueueError>;
+
+    /// The state, retries and error of a task by its id, without loading its metadata,
+    /// for example to answer status polls. Soft-deleted tasks are not returned.
+    fn task_status(&self, id: Uuid) -> Result<Option<TaskStatus>, QueueError>;
+
+    /// Hide a task by setting its `deleted_at`, the row is kept for auditing.
+    /// Soft-deleted tasks are not fetched, counted or listed.
+    fn soft_delete_task(&self, id: Uuid) -> Result<usize, QueueError>;
+
+    /// Move a task from the `fang_tasks` table of the queue to the `target_table` atomically,
+    /// for example from a staging schema to the schema of the workers, see `Queue::with_schema`.
+    /// The target table must have the columns of `fang_tasks` in the same order, the name can be
+    /// qualified with a schema like `staging.fang_tasks`. Returns the moved task or `None` if it doesn't exist.
+    fn move_task_to(&self, id: Uuid, target_table: &str) -> Result<Option<Task>, QueueError>;
+
     /// Update the state field of the specified task
     /// See the `FangTaskState` enum for possible states.
     fn update_task_state(&self, task: &Task, state: FangTaskState) -> Result<Task, QueueError>;
@@ -101,6 +671,35 @@ pub trait Queueable {
     /// Update the state of a task to `FangTaskState::Failed` and set an error_message.
     fn fail_task(&self, task: &Task, error: &str) -> Result<Task, QueueError>;
 
+    /// The same as `fail_task` but it also stores a structured description of the error,
+    /// like an error code, in the `error_details` column.
+    fn fail_task_with_details(
+        &self,
+        task: &Task,
+        error: &str,
+        details: serde_json::Value,
+    ) -> Result<Task, QueueError>;
+
+    /// Update the state of a task to `FangTaskState::Finished` and store the value returned by the task
+    /// in the `result` column.
+    fn finish_task_with_result(
+        &self,
+        task: &Task,
+        result: serde_json::Value,
+    ) -> Result<Task, QueueError>;
+
+    /// Update the state of the tasks with the specified ids to `FangTaskState::Finished` in one statement.
+    /// Returns the number of updated tasks.
+    fn finish_tasks(&self, ids: &[Uuid]) -> Result<usize, QueueError>;
+
+    /// Update the state of the tasks with the specified ids to `FangTaskState::Failed`
+    /// and set the same error_message in one statement. Returns the number of updated tasks.
+    fn fail_tasks(&self, ids: &[Uuid], error: &str) -> Result<usize, QueueError>;
+
+    /// Update the state of a task to `FangTaskState::Quarantined` and set an error_message.
+    /// Quarantined tasks are never fetched by workers.
+    fn quarantine_task(&self, task: &Task, error: &str) -> Result<Task, QueueError>;
+
     /// Schedule a task.
     fn schedule_task(&self, task: &dyn Runnable) -> Result<Task, QueueError>;
 
@@ -110,6 +709,251 @@ pub trait Queueable {
         backoff_in_seconds: u32,
         error: &str,
     ) -> Result<Task, QueueError>;
+
+    /// The same as `schedule_retry` but the task is retried after a `delay` with millisecond precision.
+    fn schedule_retry_after(
+        &self,
+        task: &Task,
+        delay: Duration,
+        error: &str,
+    ) -> Result<Task, QueueError>;
+
+    /// Update the state of a task to `FangTaskState::Cancelled`, so it will never be executed.
+    /// Only tasks in the `FangTaskState::New` or `FangTaskState::Retried` states can be cancelled,
+    /// `QueueError::TaskNotPendingError` is returned for other tasks and they are left untouched.
+    fn cancel_task(&self, id: Uuid) -> Result<Task, QueueError>;
+
+    /// Move a task to the front of the queue of its type by raising its `priority` above the highest priority
+    /// of the other pending tasks of the type, so it's fetched next by the workers of its type.
+    /// Only tasks in the `FangTaskState::New` or `FangTaskState::Retried` states can be bumped.
+    fn bump_task(&self, id: Uuid) -> Result<Task, QueueError>;
+
+    /// Update the `scheduled_at` field of a task, so it's not executed before the new time.
+    /// Only tasks in the `FangTaskState::New` or `FangTaskState::Retried` states can be rescheduled.
+    fn reschedule_task(&self, id: Uuid, scheduled_at: DateTime<Utc>) -> Result<Task, QueueError>;
+
+    /// Move a task to another task type, so it's executed by the workers of the `new_type` type.
+    /// Only tasks in the `FangTaskState::New` or `FangTaskState::Retried` states can be reassigned,
+    /// `QueueError::TaskNotPendingError` is returned for other tasks and they are left untouched.
+    fn reassign_task(&self, id: Uuid, new_type: &str) -> Result<Task, QueueError>;
+
+    /// Atomically increment the `retries` of a task without changing its state, returns the new number of retries.
+    /// It's useful for custom retry flows which count attempts separately from the state transitions.
+    fn increment_retries(&self, id: Uuid) -> Result<i32, QueueError>;
+
+    /// Refresh the `updated_at` field of the tasks in the `FangTaskState::New` state
+    /// that weren't updated for longer than `older_than`, for example after an outage of all workers.
+    /// If `notify` is `true`, a notification about every bumped task type is sent to the listening workers,
+    /// see `Queueable::listen`. Returns the number of bumped tasks.
+    fn bump_stale_new(&self, older_than: Duration, notify: bool) -> Result<usize, QueueError>;
+
+    /// Move all the tasks in the `from` state to the `to` state in one statement, for example to cancel
+    /// the tasks that failed long ago. If `older_than` is set, only the tasks that weren't updated
+    /// for longer than `older_than` are moved. Soft-deleted tasks are not changed. Returns the number of moved tasks.
+    fn bulk_transition(
+        &self,
+        from: FangTaskState,
+        to: FangTaskState,
+        older_than: Option<Duration>,
+    ) -> Result<usize, QueueError>;
+
+    /// Move all the tasks in the `FangTaskState::Retried` state back to the `FangTaskState::New` state
+    /// and clear their error messages, for example after a deploy that fixed the cause of the errors.
+    /// The number of retries of the tasks is kept. Returns the number of reset tasks.
+    fn reset_retried(&self) -> Result<usize, QueueError>;
+
+    /// Rewrite the JSON metadata of the tasks of the `task_type` type in the `FangTaskState::New` or
+    /// `FangTaskState::Retried` states with `transform` in one transaction, for example to migrate
+    /// queued tasks after a change of the fields of a job. Tasks with binary metadata are not changed,
+    /// neither is the `uniq_hash` of the tasks. Returns the number of tasks whose metadata changed.
+    fn transform_metadata(
+        &self,
+        task_type: &str,
+        transform: &dyn Fn(serde_json::Value) -> serde_json::Value,
+    ) -> Result<usize, QueueError>;
+
+    /// How long the oldest runnable task has been waiting since its `scheduled_at`. It's a direct measure of the queue lag.
+    /// The age is measured from `scheduled_at` rather than from `created_at`: a task created long before it's due
+    /// is not waiting until then, and the tasks scheduled in the future are not counted.
+    /// Returns `None` if there are no runnable tasks.
+    fn oldest_pending_age(&self) -> Result<Option<Duration>, QueueError>;
+
+    /// The earliest `scheduled_at` of the pending tasks that are scheduled in the future.
+    /// A scheduler can sleep until that moment instead of polling. Returns `None` if there are no such tasks.
+    /// Soft-deleted and expired tasks, and the tasks of paused types are not considered.
+    fn next_scheduled_at(&self) -> Result<Option<DateTime<Utc>>, QueueError>;
+
+    /// The oldest task in the `FangTaskState::New` or `FangTaskState::Retried` states of every task type,
+    /// ordered by `task_type`.
+    fn oldest_per_type(&self) -> Result<Vec<Task>, QueueError>;
+
+    /// Up to `limit` tasks in the `FangTaskState::InProgress` state, the ones running the longest come first,
+    /// for example to find hung tasks. The `updated_at` field of tasks in progress is the time they were fetched.
+    fn longest_running(&self, limit: i64) -> Result<Vec<Task>, QueueError>;
+
+    /// Up to `limit` tasks updated at or after `since`, ordered by `updated_at`, for change-feed consumers.
+    /// Pass the `updated_at` of the last returned task as the next `since` to page through state changes,
+    /// the last task is returned again. Soft-deleted tasks are not returned.
+    fn tasks_updated_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError>;
+
+    /// The number of tasks in the `FangTaskState::Failed` state grouped by `error_message`,
+    /// the most common errors come first.
+    fn failed_error_counts(&self) -> Result<Vec<(String, i64)>, QueueError>;
+
+    /// The number of tasks of every task type in every state, ordered by `task_type` and `state`.
+    fn task_counts(&self) -> Result<Vec<(String, FangTaskState, i64)>, QueueError>;
+
+    /// The task types of the tasks in any state that are not soft-deleted, ordered by `task_type`.
+    fn distinct_task_types(&self) -> Result<Vec<String>, QueueError>;
+
+    /// The number of tasks of every task type that reached the `FangTaskState::Finished` state
+    /// not earlier than `since`, ordered by `task_type`. The `updated_at` field of finished tasks is their finish time.
+    fn processed_counts_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, i64)>, QueueError>;
+
+    /// Returns an iterator that lazily fetches tasks of the `task_type` type.
+    /// Every call to `next` fetches and touches one task, so it behaves like `fetch_and_touch_task`
+    /// called in a loop. The iteration ends when there are no tasks left to fetch.
+    fn task_stream(&self, task_type: String) -> TaskStream<'_>
+    where
+        Self: Sized,
+    {
+        TaskStream {
+            queue: self,
+            task_type,
+        }
+    }
+
+    /// The codec used to encode and decode the metadata of tasks.
+    /// By default, the metadata is stored as JSON.
+    fn codec(&self) -> MetadataCodec {
+        MetadataCodec::default()
+    }
+
+    /// Start listening for notifications about new tasks, see [`TaskListener`].
+    /// Returns `None` if the queue doesn't support notifications.
+    fn listen(&self) -> Result<Option<TaskListener>, QueueError> {
+        Ok(None)
+    }
+
+    /// Take the advisory lock of the `task_type` type, see [`TaskTypeLock`].
+    /// Returns `None` if the lock is held by someone else.
+    fn try_lock_task_type(&self, task_type: &str) -> Result<Option<TaskTypeLock>, QueueError>;
+}
+
+/// An iterator over the tasks of a queue, created by [`Queueable::task_stream`].
+pub struct TaskStream<'a> {
+    queue: &'a dyn Queueable,
+    task_type: String,
+}
+
+impl<'a> Iterator for TaskStream<'a> {
+    type Item = Result<Task, QueueError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.queue
+            .fetch_and_touch_task(self.task_type.clone())
+            .transpose()
+    }
+}
+
+/// Receives the notifications that the `fang_tasks_notify` trigger sends when tasks are inserted,
+/// created by [`Queueable::listen`]. It holds a connection of the pool until it's dropped.
+pub struct TaskListener {
+    connection: PoolConnection,
+}
+
+impl TaskListener {
+    const CHANNEL: &'static str = "fang_tasks";
+    const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(50);
+
+    pub fn new(mut connection: PoolConnection) -> Result<Self, QueueError> {
+        diesel::sql_query(format!("LISTEN {}", Self::CHANNEL)).execute(&mut connection)?;
+
+        Ok(Self { connection })
+    }
+
+    /// Block until a task of the `task_type` type is inserted or the `timeout` elapses.
+    /// Returns `true` if a task was inserted.
+    pub fn wait(
+        &mut self,
+        task_type: &str,
+        timeout: std::time::Duration,
+    ) -> Result<bool, QueueError> {
+        let deadline = std::time::Instant::now() + timeout;
+
+        loop {
+            for notification in self.connection.notifications_iter() {
+                if notification?.payload == task_type {
+                    return Ok(true);
+                }
+            }
+
+            let now = std::time::Instant::now();
+
+            if now >= deadline {
+                return Ok(false);
+            }
+
+            std::thread::sleep(Self::POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+/// A Postgres advisory lock keyed on a hash of a task type, created by [`Queueable::try_lock_task_type`].
+/// Only one holder of the lock of a type can exist at a time, so it can be used to process the tasks
+/// of a type serially while other types are processed in parallel.
+/// It holds a connection of the pool, the lock is released when it's dropped.
+pub struct TaskTypeLock {
+    connection: PoolConnection,
+    key: String,
+}
+
+impl TaskTypeLock {
+    pub fn try_new(
+        mut connection: PoolConnection,
+        task_type: &str,
+    ) -> Result<Option<Self>, QueueError> {
+        let key = format!("fang_serial:{}", task_type);
+
+        let locked = diesel::select(
+            sql::<Bool>("pg_try_advisory_lock(hashtext(")
+                .bind::<Text, _>(&key)
+                .sql("))"),
+        )
+        .get_result::<bool>(&mut connection)?;
+
+        if locked {
+            Ok(Some(Self { connection, key }))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+impl Drop for TaskTypeLock {
+    fn drop(&mut self) {
+        let unlocked = diesel::select(
+            sql::<Bool>("pg_advisory_unlock(hashtext(")
+                .bind::<Text, _>(&self.key)
+                .sql("))"),
+        )
+        .get_result::<bool>(&mut self.connection);
+
+        if let Err(error) = unlocked {
+            log::error!(
+                "Failed to release the advisory lock {}: {:?}",
+                self.key,
+                error
+            );
+        }
+    }
 }
 
 /// An async queue that can be used to enqueue tasks.
@@ -137,352 +981,4039 @@ pub trait Queueable {
 pub struct Queue {
     #[builder(setter(into))]
     pub connection_pool: r2d2::Pool<r2d2::ConnectionManager<PgConnection>>,
+    /// the codec used to store the metadata of tasks, JSON by default.
+    #[builder(default, setter(into))]
+    pub codec: MetadataCodec,
+    /// the isolation level of the transaction used to fetch tasks, `READ COMMITTED` by default.
+    #[builder(default, setter(into))]
+    pub isolation_level: IsolationLevel,
+    /// the type of the enqueued tasks that don't define their own type, `common` by default.
+    /// Set it to the `task_type` of your worker if it executes only one type of tasks.
+    #[builder(default=COMMON_TYPE.to_string(), setter(into))]
+    pub default_task_type: String,
+    /// the JSON Schemas used to validate the metadata of inserted tasks, no tasks are validated by default.
+    #[cfg(feature = "json-schema")]
+    #[builder(default, setter(into))]
+    pub schemas: SchemaRegistry,
+    /// the generator of the ids of inserted tasks, the ids are generated by the database (random UUIDv4) by default.
+    /// Time-sortable ids, for example UUIDv7, keep the order of ids close to the order of insertion.
+    #[builder(default, setter(strip_option))]
+    pub id_generator: Option<IdGenerator>,
+    /// the clock of the queue, see [`Clock`]. All the times the queue stamps and compares tasks with come from it.
+    /// A queue without a clock compares `scheduled_at` and `expires_at` with the time of the database
+    /// and leaves the creation time of tasks to it, the other times are stamped with the system time.
+    #[builder(default, setter(strip_option))]
+    pub clock: Option<Arc<dyn Clock>>,
+    /// reject the tasks with the JSON `null` metadata with `QueueError::NullMetadataError`, workers can't decode them.
+    /// Tasks with binary metadata are not checked.
+    #[builder(default, setter(into))]
+    pub reject_null_metadata: bool,
 }
 
 impl Queueable for Queue {
     fn fetch_and_touch_task(&self, task_type: String) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::fetch_and_touch_query(&mut connection, task_type)
+        Self::fetch_and_touch_with_isolation_at_query(
+            &mut connection,
+            &self.isolation_level,
+            &task_type,
+            self.clock_now(),
+        )
     }
 
-    fn insert_task(&self, params: &dyn Runnable) -> Result<Task, QueueError> {
+    fn fetch_and_touch_random_task(&self, task_type: String) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::insert_query(&mut connection, params, Utc::now())
+        Self::fetch_and_touch_random_at_query(&mut connection, &task_type, self.clock_now())
     }
-    fn schedule_task(&self, params: &dyn Runnable) -> Result<Task, QueueError> {
+
+    fn fetch_and_touch_lowest_priority_task(
+        &self,
+        task_type: String,
+    ) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::schedule_task_query(&mut connection, params)
+        Self::fetch_and_touch_lowest_priority_at_query(
+            &mut connection,
+            &task_type,
+            self.clock_now(),
+        )
     }
 
-    fn remove_all_scheduled_tasks(&self) -> Result<usize, QueueError> {
+    fn fetch_and_touch_longest_backlog_task(&self) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::remove_all_scheduled_tasks_query(&mut connection)
+        Self::fetch_and_touch_longest_backlog_at_query(&mut connection, self.clock_now())
     }
 
-    fn remove_all_tasks(&self) -> Result<usize, QueueError> {
+    fn peek_next_task(&self, task_type: String) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::remove_all_tasks_query(&mut connection)
+        Self::fetch_task_with_lock_at_query(
+            &mut connection,
+            &task_type,
+            &LockMode::None,
+            self.clock_now(),
+        )
     }
 
-    fn remove_tasks_of_type(&self, task_type: &str) -> Result<usize, QueueError> {
+    fn count_runnable_tasks(&self, task_type: String) -> Result<i64, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::remove_tasks_of_type_query(&mut connection, task_type)
+        Self::count_runnable_tasks_at_query(&mut connection, &task_type, self.clock_now())
     }
 
-    fn remove_task(&self, id: Uuid) -> Result<usize, QueueError> {
+    fn task_position(&self, id: Uuid) -> Result<Option<i64>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::remove_task_query(&mut connection, id)
+        Self::task_position_at_query(&mut connection, id, self.clock_now())
     }
 
-    /// To use this function task has to be uniq. uniq() has to return true.
-    /// If task is not uniq this function will not do anything.
-    fn remove_task_by_metadata(&self, task: &dyn Runnable) -> Result<usize, QueueError> {
-        if task.uniq() {
-            let mut connection = self.get_connection()?;
+    fn fetch_task_with_lock(
+        &self,
+        task_type: String,
+        lock_mode: LockMode,
+    ) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-            Self::remove_task_by_metadata_query(&mut connection, task)
-        } else {
-            Err(QueueError::TaskNotUniqError)
-        }
+        Self::fetch_task_with_lock_at_query(
+            &mut connection,
+            &task_type,
+            &lock_mode,
+            self.clock_now(),
+        )
     }
 
-    fn update_task_state(&self, task: &Task, state: FangTaskState) -> Result<Task, QueueError> {
+    fn fetch_and_touch_tasks(
+        &self,
+        task_type: String,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::update_task_state_query(&mut connection, task, state)
+        Self::fetch_and_touch_tasks_at_query(&mut connection, &task_type, limit, self.clock_now())
     }
 
-    fn fail_task(&self, task: &Task, error: &str) -> Result<Task, QueueError> {
+    fn fetch_task_at(
+        &self,
+        task_type: String,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::fail_task_query(&mut connection, task, error)
+        Self::fetch_and_touch_at_query(&mut connection, &task_type, now)
     }
 
-    fn find_task_by_id(&self, id: Uuid) -> Option<Task> {
-        let mut connection = self.get_connection().unwrap();
+    fn fetch_task_of_types(&self, task_types: &[&str]) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        Self::find_task_by_id_query(&mut connection, id)
+        Self::fetch_task_of_types_at_query(&mut connection, task_types, self.clock_now())
     }
 
-    fn schedule_retry(
+    fn fetch_task_by_tag(
         &self,
-        task: &Task,
-        backoff_seconds: u32,
-        error: &str,
-    ) -> Result<Task, QueueError> {
+        task_type: String,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<Option<Task>, QueueError> {
         let mut connection = self.get_connection()?;
 
-        Self::schedule_retry_query(&mut connection, task, backoff_seconds, error)
+        Self::fetch_task_by_tag_at_query(&mut connection, &task_type, key, value, self.clock_now())
     }
-}
 
-impl Queue {
-    /// Provides a Queue that does not commit to the DB
-    #[cfg(test)]
-    pub fn test() -> Self {
-        let pool = Self::connection_pool(1);
-        pool.get()
-            .map(|mut conn| conn.begin_test_transaction())
-            .expect("Could not get a connection from the pool")
-            .expect("Could not begin test transaction");
+    fn fetch_and_touch_task_in_order(&self, task_type: String) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        Self::builder().connection_pool(pool).build()
+        Self::fetch_and_touch_in_order_at_query(&mut connection, &task_type, self.clock_now())
     }
 
-    /// Connect to the db if not connected
-    pub fn get_connection(&self) -> Result<PoolConnection, QueueError> {
-        let result = self.connection_pool.get();
+    fn fetch_and_finish_task(&self, task_type: String) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        if let Err(err) = result {
-            log::error!("Failed to get a db connection {:?}", err);
-            return Err(QueueError::PoolError(err));
-        }
+        Self::fetch_and_finish_at_query(&mut connection, &task_type, self.clock_now())
+    }
 
-        Ok(result.unwrap())
+    fn fetch_with_lease(
+        &self,
+        task_type: &str,
+        lease: Duration,
+    ) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::fetch_with_lease_at_query(&mut connection, task_type, lease, self.now())
     }
 
-    pub fn schedule_task_query(
-        connection: &mut PgConnection,
-        params: &dyn Runnable,
-    ) -> Result<Task, QueueError> {
-        let scheduled_at = match params.cron() {
-            Some(scheduled) => match scheduled {
-                CronPattern(cron_pattern) => {
-                    let schedule = Schedule::from_str(&cron_pattern)?;
-                    let mut iterator = schedule.upcoming(Utc);
+    fn requeue_expired_leases(&self) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
 
-                    iterator
-                        .next()
-                        .ok_or(QueueError::CronError(CronError::NoTimestampsError))?
-                }
-                ScheduleOnce(datetime) => datetime,
-            },
-            None => {
-                return Err(QueueError::CronError(CronError::TaskNotSchedulableError));
-            }
-        };
+        Self::requeue_expired_leases_at_query(&mut connection, self.now())
+    }
 
-        Self::insert_query(connection, params, scheduled_at)
+    fn release_lease(&self, task: &Task) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::release_lease_at_query(&mut connection, task, self.now())
     }
 
-    fn calculate_hash(json: String) -> String {
-        let mut hasher = Sha256::new();
-        hasher.update(json.as_bytes());
-        let result = hasher.finalize();
-        hex::encode(result)
+    fn requeue_task(&self, task: &Task) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::requeue_task_at_query(&mut connection, task, self.now())
     }
 
-    pub fn insert_query(
-        connection: &mut PgConnection,
-        params: &dyn Runnable,
-        scheduled_at: DateTime<Utc>,
+    fn insert_task(&self, params: &dyn Runnable) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        let new_task = self.new_task(params, self.now())?;
+        self.validate_new_task(&new_task)?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
+    }
+
+    fn insert_full(
+        &self,
+        metadata: serde_json::Value,
+        task_type: Option<String>,
+        priority: Option<i32>,
+        scheduled_at: Option<DateTime<Utc>>,
     ) -> Result<Task, QueueError> {
-        if !params.uniq() {
-            let new_task = NewTask::builder()
-                .scheduled_at(scheduled_at)
-                .uniq_hash(None)
-                .task_type(params.task_type())
-                .metadata(serde_json::to_value(params).unwrap())
-                .build();
-
-            Ok(diesel::insert_into(fang_tasks::table)
-                .values(new_task)
-                .get_result::<Task>(connection)?)
-        } else {
-            let metadata = serde_json::to_value(params).unwrap();
+        let mut connection = self.get_connection()?;
 
-            let uniq_hash = Self::calculate_hash(metadata.to_string());
+        let new_task = NewTask::builder()
+            .metadata(metadata)
+            .task_type(task_type.unwrap_or_else(|| self.default_task_type.clone()))
+            .uniq_hash(None)
+            .scheduled_at(scheduled_at.unwrap_or_else(|| self.now()))
+            .binary_metadata(None)
+            .priority(priority)
+            .id(self.generate_id())
+            .created_at(self.clock.as_ref().map(|clock| clock.now()))
+            .build();
+        self.validate_new_task(&new_task)?;
 
-            match Self::find_task_by_uniq_hash_query(connection, &uniq_hash) {
-                Some(task) => Ok(task),
-                None => {
-                    let new_task = NewTask::builder()
-                        .scheduled_at(scheduled_at)
-                        .uniq_hash(Some(uniq_hash))
-                        .task_type(params.task_type())
-                        .metadata(serde_json::to_value(params).unwrap())
-                        .build();
-
-                    Ok(diesel::insert_into(fang_tasks::table)
-                        .values(new_task)
-                        .get_result::<Task>(connection)?)
-                }
-            }
-        }
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
 
-    pub fn fetch_task_query(connection: &mut PgConnection, task_type: String) -> Option<Task> {
-        Self::fetch_task_of_type_query(connection, &task_type)
+    fn insert_new_task(&self, new_task: &NewTask) -> Result<Task, QueueError> {
+        let new_task = self.prepare_new_task(new_task)?;
+        let mut connection = self.get_connection()?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
 
-    pub fn fetch_and_touch_query(
-        connection: &mut PgConnection,
-        task_type: String,
-    ) -> Result<Option<Task>, QueueError> {
-        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
-            let found_task = Self::fetch_task_query(conn, task_type);
+    fn insert_returning_id(&self, new_task: &NewTask) -> Result<Uuid, QueueError> {
+        let new_task = self.prepare_new_task(new_task)?;
+        let mut connection = self.get_connection()?;
 
-            if found_task.is_none() {
-                return Ok(None);
-            }
+        Self::insert_returning_id_query(&mut connection, &new_task)
+    }
 
-            match Self::update_task_state_query(
-                conn,
-                &found_task.unwrap(),
-                FangTaskState::InProgress,
-            ) {
-                Ok(updated_task) => Ok(Some(updated_task)),
-                Err(err) => Err(err),
-            }
-        })
+    fn insert_child(&self, parent: &Task, task: &dyn Runnable) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        let new_task = self
+            .new_task(task, self.now())?
+            .with_correlation_id(parent.correlation_id.unwrap_or(parent.id));
+        self.validate_new_task(&new_task)?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
 
-    pub fn find_task_by_id_query(connection: &mut PgConnection, id: Uuid) -> Option<Task> {
-        fang_tasks::table
-            .filter(fang_tasks::id.eq(id))
-            .first::<Task>(connection)
-            .ok()
+    fn insert_after(&self, task: &dyn Runnable, depends_on: Uuid) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        let new_task = self.new_task(task, self.now())?.with_depends_on(depends_on);
+        self.validate_new_task(&new_task)?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
 
-    pub fn remove_all_tasks_query(connection: &mut PgConnection) -> Result<usize, QueueError> {
-        Ok(diesel::delete(fang_tasks::table).execute(connection)?)
+    fn insert_with_ttl(&self, task: &dyn Runnable, ttl: Duration) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        let now = self.now();
+        let new_task = self.new_task(task, now)?.with_expires_at(now + ttl);
+        self.validate_new_task(&new_task)?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
 
-    pub fn remove_all_scheduled_tasks_query(
-        connection: &mut PgConnection,
-    ) -> Result<usize, QueueError> {
-        let query = fang_tasks::table.filter(fang_tasks::scheduled_at.gt(Utc::now()));
+    fn expire_tasks(&self) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        Ok(diesel::delete(query).execute(connection)?)
+        Self::expire_tasks_at_query(&mut connection, self.now())
     }
 
-    pub fn remove_tasks_of_type_query(
-        connection: &mut PgConnection,
-        task_type: &str,
-    ) -> Result<usize, QueueError> {
-        let query = fang_tasks::table.filter(fang_tasks::task_type.eq(task_type));
+    fn fetch_tasks_by_correlation(&self, correlation_id: Uuid) -> Result<Vec<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        Ok(diesel::delete(query).execute(connection)?)
+        Self::fetch_tasks_by_correlation_query(&mut connection, correlation_id)
     }
 
-    pub fn remove_task_by_metadata_query(
-        connection: &mut PgConnection,
-        task: &dyn Runnable,
-    ) -> Result<usize, QueueError> {
-        let metadata = serde_json::to_value(task).unwrap();
+    fn insert_coalesce(&self, key: &str, task: &dyn Runnable) -> Result<Task, QueueError> {
+        let mut new_task = self.new_task(task, self.now())?;
+        new_task.uniq_hash = Some(Self::calculate_hash(key.as_bytes()));
+        self.validate_new_task(&new_task)?;
+
+        let mut connection = self.get_connection()?;
 
-        let uniq_hash = Self::calculate_hash(metadata.to_string());
+        Self::insert_coalesce_at_query(&mut connection, &new_task, self.now())
+    }
 
-        let query = fang_tasks::table.filter(fang_tasks::uniq_hash.eq(uniq_hash));
+    fn insert_throttled(&self, task: &dyn Runnable, window: Duration) -> Result<Task, QueueError> {
+        let mut new_task = self.new_task(task, self.now())?;
 
-        Ok(diesel::delete(query).execute(connection)?)
+        if new_task.uniq_hash.is_none() {
+            let encoded = self.codec.encode(task)?;
+            new_task.uniq_hash = Some(Self::calculate_hash(&encoded.hash_input()));
+        }
+
+        self.validate_new_task(&new_task)?;
+
+        let mut connection = self.get_connection()?;
+
+        Self::insert_throttled_at_query(&mut connection, &new_task, window, self.now())
     }
 
-    pub fn remove_task_query(connection: &mut PgConnection, id: Uuid) -> Result<usize, QueueError> {
-        let query = fang_tasks::table.filter(fang_tasks::id.eq(id));
+    fn insert_stream<I>(&self, tasks: I, chunk_size: usize) -> Result<usize, QueueError>
+    where
+        I: Iterator<Item = NewTask>,
+    {
+        let mut connection = self.get_connection()?;
 
-        Ok(diesel::delete(query).execute(connection)?)
+        let tasks = tasks.map(|new_task| self.with_defaults(&new_task).into_owned());
+
+        Self::insert_chunks_query(&mut connection, tasks, chunk_size, &|new_task| {
+            self.validate_new_task(new_task)
+        })
     }
 
-    pub fn update_task_state_query(
-        connection: &mut PgConnection,
-        task: &Task,
-        state: FangTaskState,
-    ) -> Result<Task, QueueError> {
-        Ok(diesel::update(task)
-            .set((
-                fang_tasks::state.eq(state),
-                fang_tasks::updated_at.eq(Self::current_time()),
-            ))
-            .get_result::<Task>(connection)?)
+    fn pause_inserts(&self) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::set_inserts_paused_at_query(&mut connection, true, self.now())
     }
 
-    pub fn fail_task_query(
-        connection: &mut PgConnection,
-        task: &Task,
-        error: &str,
-    ) -> Result<Task, QueueError> {
-        Ok(diesel::update(task)
-            .set((
-                fang_tasks::state.eq(FangTaskState::Failed),
-                fang_tasks::error_message.eq(error),
-                fang_tasks::updated_at.eq(Self::current_time()),
-            ))
-            .get_result::<Task>(connection)?)
+    fn resume_inserts(&self) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::set_inserts_paused_at_query(&mut connection, false, self.now())
     }
 
-    fn current_time() -> DateTime<Utc> {
-        Utc::now()
+    fn inserts_paused(&self) -> Result<bool, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::inserts_paused_query(&mut connection)
     }
 
-    #[cfg(test)]
-    pub fn connection_pool(pool_size: u32) -> r2d2::Pool<r2d2::ConnectionManager<PgConnection>> {
-        dotenv().ok();
+    fn pause_task_type(&self, task_type: &str) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
 
-        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        Self::pause_task_type_at_query(&mut connection, task_type, self.now())
+    }
 
-        let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
+    fn resume_task_type(&self, task_type: &str) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
 
-        r2d2::Pool::builder()
-            .max_size(pool_size)
-            .build(manager)
-            .unwrap()
+        Self::resume_task_type_query(&mut connection, task_type)
     }
 
-    fn fetch_task_of_type_query(connection: &mut PgConnection, task_type: &str) -> Option<Task> {
-        fang_tasks::table
-            .order(fang_tasks::created_at.asc())
-            .order(fang_tasks::scheduled_at.asc())
-            .limit(1)
-            .filter(fang_tasks::scheduled_at.le(Utc::now()))
-            .filter(fang_tasks::state.eq_any(vec![FangTaskState::New, FangTaskState::Retried]))
-            .filter(fang_tasks::task_type.eq(task_type))
-            .for_update()
-            .skip_locked()
-            .get_result::<Task>(connection)
-            .ok()
+    fn task_type_paused(&self, task_type: &str) -> Result<bool, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::task_type_paused_query(&mut connection, task_type)
     }
 
-    fn find_task_by_uniq_hash_query(
-        connection: &mut PgConnection,
-        uniq_hash: &str,
-    ) -> Option<Task> {
-        fang_tasks::table
-            .filter(fang_tasks::uniq_hash.eq(uniq_hash))
-            .filter(fang_tasks::state.eq_any(vec![FangTaskState::New, FangTaskState::Retried]))
-            .first::<Task>(connection)
-            .ok()
+    fn record_interrupted(&self, tasks: &[Task]) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::record_interrupted_at_query(&mut connection, tasks, self.now())
     }
 
-    pub fn schedule_retry_query(
-        connection: &mut PgConnection,
-        task: &Task,
-        backoff_seconds: u32,
-        error: &str,
-    ) -> Result<Task, QueueError> {
-        let now = Self::current_time();
-        let scheduled_at = now + Duration::seconds(backoff_seconds as i64);
+    fn interrupted_tasks(&self) -> Result<Vec<InterruptedTask>, QueueError> {
+        let mut connection = self.get_connection()?;
 
-        let task = diesel::update(task)
-            .set((
-                fang_tasks::state.eq(FangTaskState::Retried),
-                fang_tasks::error_message.eq(error),
-                fang_tasks::retries.eq(task.retries + 1),
-                fang_tasks::scheduled_at.eq(scheduled_at),
-                fang_tasks::updated_at.eq(now),
-            ))
-            .get_result::<Task>(connection)?;
+        Self::interrupted_tasks_query(&mut connection)
+    }
 
-        Ok(task)
+    fn schedule_task(&self, params: &dyn Runnable) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        let scheduled_at = Self::calculate_scheduled_at(params, self.now())?;
+        let new_task = self.new_task(params, scheduled_at)?;
+        self.validate_new_task(&new_task)?;
+
+        Self::insert_new_task_query(&mut connection, &new_task)
     }
-}
 
-#[cfg(test)]
-queue_tests::test_queue! {postgres, crate::queue::Queue, crate::queue::Queue::test()}
+    fn remove_all_scheduled_tasks(&self) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::remove_all_scheduled_tasks_at_query(&mut connection, self.now())
+    }
+
+    fn create_task_type_partition(&self, task_type: &str) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::create_task_type_partition_query(&mut connection, task_type)
+    }
+
+    fn export_tasks(&self, state: FangTaskState) -> Result<String, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::export_tasks_query(&mut connection, state)
+    }
+
+    fn import_tasks(&self, json: &str) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::import_tasks_query(&mut connection, json)
+    }
+
+    fn remove_all_tasks(&self) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::remove_all_tasks_query(&mut connection)
+    }
+
+    fn remove_tasks_of_type(&self, task_type: &str) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::remove_tasks_of_type_query(&mut connection, task_type)
+    }
+
+    fn remove_task(&self, id: Uuid) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::remove_task_query(&mut connection, id)
+    }
+
+    fn prune_finished_tasks(&self, threshold: i64, target: i64) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::prune_finished_tasks_query(&mut connection, threshold, target)
+    }
+
+    /// To use this function task has to be uniq. uniq() has to return true.
+    /// If task is not uniq this function will not do anything.
+    fn remove_task_by_metadata(&self, task: &dyn Runnable) -> Result<usize, QueueError> {
+        if task.uniq() {
+            let mut connection = self.get_connection()?;
+
+            Self::remove_task_by_metadata_with_codec_query(&mut connection, &self.codec, task)
+        } else {
+            Err(QueueError::TaskNotUniqError)
+        }
+    }
+
+    fn update_task_state(&self, task: &Task, state: FangTaskState) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::update_task_state_at_query(&mut connection, task, state, self.now())
+    }
+
+    fn fail_task(&self, task: &Task, error: &str) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::fail_task_at_query(&mut connection, task, error, self.now())
+    }
+
+    fn fail_task_with_details(
+        &self,
+        task: &Task,
+        error: &str,
+        details: serde_json::Value,
+    ) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::fail_task_with_details_at_query(&mut connection, task, error, details, self.now())
+    }
+
+    fn finish_task_with_result(
+        &self,
+        task: &Task,
+        result: serde_json::Value,
+    ) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::finish_task_with_result_at_query(&mut connection, task, result, self.now())
+    }
+
+    fn finish_tasks(&self, ids: &[Uuid]) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::finish_tasks_at_query(&mut connection, ids, self.now())
+    }
+
+    fn fail_tasks(&self, ids: &[Uuid], error: &str) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::fail_tasks_at_query(&mut connection, ids, error, self.now())
+    }
+
+    fn quarantine_task(&self, task: &Task, error: &str) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::quarantine_task_at_query(&mut connection, task, error, self.now())
+    }
+
+    fn find_task_by_id(&self, id: Uuid) -> Option<Task> {
+        let mut connection = self.get_connection().unwrap();
+
+        Self::find_task_by_id_query(&mut connection, id)
+    }
+
+    fn find_task_by_id_with_deleted(&self, id: Uuid, include_deleted: bool) -> Option<Task> {
+        let mut connection = self.get_connection().unwrap();
+
+        Self::find_task_by_id_with_deleted_query(&mut connection, id, include_deleted)
+    }
+
+    fn pending_task_exists(&self, uniq_hash: &str) -> Result<bool, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::pending_task_exists_query(&mut connection, uniq_hash)
+    }
+
+    fn task_status(&self, id: Uuid) -> Result<Option<TaskStatus>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::task_status_query(&mut connection, id)
+    }
+
+    fn move_task_to(&self, id: Uuid, target_table: &str) -> Result<Option<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::move_task_to_query(&mut connection, id, target_table)
+    }
+
+    fn soft_delete_task(&self, id: Uuid) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::soft_delete_task_at_query(&mut connection, id, self.now())
+    }
+
+    fn schedule_retry(
+        &self,
+        task: &Task,
+        backoff_seconds: u32,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::schedule_retry_after_at_query(
+            &mut connection,
+            task,
+            Duration::seconds(backoff_seconds as i64),
+            error,
+            self.now(),
+        )
+    }
+
+    fn schedule_retry_after(
+        &self,
+        task: &Task,
+        delay: Duration,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::schedule_retry_after_at_query(&mut connection, task, delay, error, self.now())
+    }
+
+    fn cancel_task(&self, id: Uuid) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::cancel_task_at_query(&mut connection, id, self.now())
+    }
+
+    fn bump_task(&self, id: Uuid) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::bump_task_at_query(&mut connection, id, self.now())
+    }
+
+    fn reschedule_task(&self, id: Uuid, scheduled_at: DateTime<Utc>) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::reschedule_task_at_query(&mut connection, id, scheduled_at, self.now())
+    }
+
+    fn reassign_task(&self, id: Uuid, new_type: &str) -> Result<Task, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::reassign_task_at_query(&mut connection, id, new_type, self.now())
+    }
+
+    fn increment_retries(&self, id: Uuid) -> Result<i32, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::increment_retries_at_query(&mut connection, id, self.now())
+    }
+
+    fn bump_stale_new(&self, older_than: Duration, notify: bool) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::bump_stale_new_at_query(&mut connection, older_than, notify, self.now())
+    }
+
+    fn bulk_transition(
+        &self,
+        from: FangTaskState,
+        to: FangTaskState,
+        older_than: Option<Duration>,
+    ) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::bulk_transition_at_query(&mut connection, from, to, older_than, self.now())
+    }
+
+    fn reset_retried(&self) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::reset_retried_at_query(&mut connection, self.now())
+    }
+
+    fn transform_metadata(
+        &self,
+        task_type: &str,
+        transform: &dyn Fn(serde_json::Value) -> serde_json::Value,
+    ) -> Result<usize, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::transform_metadata_at_query(&mut connection, task_type, transform, self.now())
+    }
+
+    fn oldest_pending_age(&self) -> Result<Option<Duration>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::oldest_pending_age_at_query(&mut connection, self.clock_now())
+    }
+
+    fn next_scheduled_at(&self) -> Result<Option<DateTime<Utc>>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::next_scheduled_at_after_query(&mut connection, self.clock_now())
+    }
+
+    fn oldest_per_type(&self) -> Result<Vec<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::oldest_per_type_query(&mut connection)
+    }
+
+    fn longest_running(&self, limit: i64) -> Result<Vec<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::longest_running_query(&mut connection, limit)
+    }
+
+    fn tasks_updated_since(
+        &self,
+        since: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::tasks_updated_since_query(&mut connection, since, limit)
+    }
+
+    fn failed_error_counts(&self) -> Result<Vec<(String, i64)>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::failed_error_counts_query(&mut connection)
+    }
+
+    fn task_counts(&self) -> Result<Vec<(String, FangTaskState, i64)>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::task_counts_query(&mut connection)
+    }
+
+    fn distinct_task_types(&self) -> Result<Vec<String>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::distinct_task_types_query(&mut connection)
+    }
+
+    fn processed_counts_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, i64)>, QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::processed_counts_since_query(&mut connection, since)
+    }
+
+    fn codec(&self) -> MetadataCodec {
+        self.codec.clone()
+    }
+
+    fn listen(&self) -> Result<Option<TaskListener>, QueueError> {
+        let connection = self.get_connection()?;
+
+        Ok(Some(TaskListener::new(connection)?))
+    }
+
+    fn try_lock_task_type(&self, task_type: &str) -> Result<Option<TaskTypeLock>, QueueError> {
+        let connection = self.get_connection()?;
+
+        TaskTypeLock::try_new(connection, task_type)
+    }
+}
+
+impl Queue {
+    /// Provides a Queue that does not commit to the DB
+    #[cfg(test)]
+    pub fn test() -> Self {
+        let pool = Self::connection_pool(1);
+        pool.get()
+            .map(|mut conn| conn.begin_test_transaction())
+            .expect("Could not get a connection from the pool")
+            .expect("Could not begin test transaction");
+
+        Self::builder().connection_pool(pool).build()
+    }
+
+    /// Create a queue with a connection pool that runs `setup` on every freshly established connection,
+    /// for example to set the `search_path` or the time zone of the session.
+    ///
+    ///    ```rust
+    ///         let queue = Queue::new_with_setup(database_url, |connection| {
+    ///             diesel::sql_query("SET search_path TO tenant_1").execute(connection)?;
+    ///
+    ///             Ok(())
+    ///         })?;
+    ///    ```
+    pub fn new_with_setup<F>(database_url: impl Into<String>, setup: F) -> Result<Self, QueueError>
+    where
+        F: Fn(&mut PgConnection) -> Result<(), DieselError> + Send + Sync + 'static,
+    {
+        let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
+
+        let pool = r2d2::Pool::builder()
+            .connection_customizer(Box::new(ConnectionSetup { setup }))
+            .build(manager)?;
+
+        Ok(Self::builder().connection_pool(pool).build())
+    }
+
+    /// Create a queue connected to the `url` database. If the `url` is `None`, the `DATABASE_URL`
+    /// environment variable is used and if it's not set either, the `fallback` URL is used,
+    /// so embedded and test setups don't have to check the environment themselves.
+    /// `QueueError::PoolError` is returned if the pool can not connect to the database.
+    pub fn new_or_default(url: Option<String>, fallback: &str) -> Result<Self, QueueError> {
+        let database_url =
+            Self::resolve_database_url(url, std::env::var("DATABASE_URL").ok(), fallback);
+
+        let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
+
+        let pool = r2d2::Pool::builder().build(manager)?;
+
+        Ok(Self::builder().connection_pool(pool).build())
+    }
+
+    fn resolve_database_url(
+        url: Option<String>,
+        env_url: Option<String>,
+        fallback: &str,
+    ) -> String {
+        url.or(env_url).unwrap_or_else(|| fallback.to_string())
+    }
+
+    /// Create a queue for the `fang_tasks` table in a non-public Postgres schema.
+    /// The `search_path` of every connection of the pool is set to the `schema` followed by `public`,
+    /// so the tables, types and functions of fang are looked up in the `schema` first.
+    pub fn with_schema(database_url: impl Into<String>, schema: &str) -> Result<Self, QueueError> {
+        let search_path = format!(
+            "SET search_path TO \"{}\", public",
+            schema.replace('"', "\"\"")
+        );
+
+        Self::new_with_setup(database_url, move |connection| {
+            diesel::sql_query(&search_path).execute(connection)?;
+
+            Ok(())
+        })
+    }
+
+    fn new_task(
+        &self,
+        params: &dyn Runnable,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<NewTask, QueueError> {
+        let mut new_task = NewTask::from_runnable_with_codec(params, &self.codec, scheduled_at)?;
+
+        if new_task.task_type == COMMON_TYPE {
+            new_task.task_type = self.default_task_type.clone();
+        }
+
+        new_task.id = self.generate_id();
+
+        // a queue without a clock leaves the creation time to the database
+        match &self.clock {
+            Some(clock) => Ok(new_task.with_created_at(clock.now())),
+            None => Ok(new_task),
+        }
+    }
+
+    /// The current time of the clock of the queue, the system time if the queue doesn't have a clock.
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock_now().unwrap_or_else(Self::current_time)
+    }
+
+    /// The time the tasks are compared with, `None` if the queue doesn't have a clock:
+    /// the `scheduled_at` and `expires_at` of tasks are compared with the time of the database then,
+    /// the clocks of the workers may be skewed.
+    fn clock_now(&self) -> Option<DateTime<Utc>> {
+        self.clock.as_ref().map(|clock| clock.now())
+    }
+
+    fn generate_id(&self) -> Option<Uuid> {
+        self.id_generator
+            .as_ref()
+            .map(|id_generator| id_generator())
+    }
+
+    /// The `new_task` with an id of the id generator and the creation time of the clock,
+    /// if it doesn't have them yet
+    fn with_defaults<'a>(&self, new_task: &'a NewTask) -> Cow<'a, NewTask> {
+        let mut new_task = Cow::Borrowed(new_task);
+
+        if let (None, Some(id)) = (new_task.id, self.generate_id()) {
+            new_task = Cow::Owned(new_task.into_owned().with_id(id));
+        }
+
+        if let (None, Some(now)) = (new_task.created_at, self.clock_now()) {
+            new_task = Cow::Owned(new_task.into_owned().with_created_at(now));
+        }
+
+        new_task
+    }
+
+    /// Validate the `new_task` and fill in its defaults before it's inserted, see [`Queue::with_defaults`].
+    fn prepare_new_task<'a>(&self, new_task: &'a NewTask) -> Result<Cow<'a, NewTask>, QueueError> {
+        self.validate_new_task(new_task)?;
+
+        Ok(self.with_defaults(new_task))
+    }
+
+    /// Validate the metadata of a task: it must not be null if `reject_null_metadata` is set
+    /// and it must match the schema of its type, see `SchemaRegistry`.
+    fn validate_new_task(&self, new_task: &NewTask) -> Result<(), QueueError> {
+        if new_task.binary_metadata.is_some() {
+            return Ok(());
+        }
+
+        if self.reject_null_metadata && new_task.metadata.is_null() {
+            return Err(QueueError::NullMetadataError(new_task.task_type.clone()));
+        }
+
+        #[cfg(feature = "json-schema")]
+        self.schemas
+            .validate(&new_task.task_type, &new_task.metadata)?;
+
+        Ok(())
+    }
+
+    /// The state of the connection pool, for monitoring its saturation.
+    pub fn pool_state(&self) -> PoolState {
+        let state = self.connection_pool.state();
+
+        PoolState {
+            connections: state.connections,
+            idle_connections: state.idle_connections,
+        }
+    }
+
+    /// Open all the connections of the pool, up to its `max_size`, so the first tasks after the start
+    /// don't pay the cost of establishing connections. Returns the state of the pool after the warmup.
+    pub fn warmup_pool(&self) -> Result<PoolState, QueueError> {
+        let connections = (0..self.connection_pool.max_size())
+            .map(|_| self.get_connection())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        drop(connections);
+
+        Ok(self.pool_state())
+    }
+
+    /// Run `f` in a transaction that is rolled back afterwards, so nothing done through the queue inside `f`
+    /// is committed. It's meant for tests of workers, see `Worker::run_once_in_test_mode`.
+    /// The queue must have a pool of one connection, so all the queries of `f` use the connection of the transaction.
+    pub fn in_test_transaction<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, FangError>,
+    ) -> Result<T, FangError> {
+        if self.connection_pool.max_size() != 1 {
+            return Err(FangError {
+                description: "The test transaction requires a connection pool of one connection"
+                    .to_string(),
+            });
+        }
+
+        let mut connection = self.get_connection()?;
+        let depth = Self::transaction_depth(&mut connection)?;
+
+        // the pool keeps a connection in a test transaction, a connection in a regular transaction is discarded
+        // when it's returned. A queue that is already in a test transaction, like `Queue::test`, gets a savepoint.
+        match depth {
+            None => connection.begin_test_transaction()?,
+            Some(_) => AnsiTransactionManager::begin_transaction(&mut *connection)?,
+        }
+        drop(connection);
+
+        let result = f();
+
+        let mut connection = self.get_connection()?;
+
+        if Self::transaction_depth(&mut connection)? != Some(depth.unwrap_or(0) + 1) {
+            return Err(FangError {
+                description: "The connection of the test transaction was lost".to_string(),
+            });
+        }
+
+        AnsiTransactionManager::rollback_transaction(&mut *connection)?;
+
+        result
+    }
+
+    fn transaction_depth(connection: &mut PoolConnection) -> Result<Option<u32>, QueueError> {
+        let depth = AnsiTransactionManager::transaction_manager_status_mut(&mut **connection)
+            .transaction_depth()?;
+
+        Ok(depth.map(|depth| depth.get()))
+    }
+
+    /// Run `VACUUM ANALYZE` on the table of tasks to reclaim the space of the removed tasks
+    /// and refresh the statistics of the planner. Tables with a high churn benefit from running it regularly.
+    pub fn vacuum_tasks(&self) -> Result<(), QueueError> {
+        let mut connection = self.get_connection()?;
+
+        Self::vacuum_tasks_query(&mut connection)
+    }
+
+    /// `VACUUM` can't run inside a transaction block, the `connection` must not be in a transaction.
+    pub fn vacuum_tasks_query(connection: &mut PgConnection) -> Result<(), QueueError> {
+        diesel::sql_query("VACUUM ANALYZE fang_tasks").execute(connection)?;
+
+        Ok(())
+    }
+
+    /// Connect to the db if not connected
+    pub fn get_connection(&self) -> Result<PoolConnection, QueueError> {
+        let result = self.connection_pool.get();
+
+        if let Err(err) = result {
+            log::error!("Failed to get a db connection {:?}", err);
+            return Err(QueueError::PoolError(err));
+        }
+
+        Ok(result.unwrap())
+    }
+
+    /// Enqueue a task using a connection owned by the caller instead of a connection from the pool.
+    /// If the connection is inside of a transaction, the task is inserted only if the transaction commits,
+    /// so tasks can be enqueued atomically with other changes.
+    /// The task is validated and completed the same way as by `Queueable::insert_new_task`.
+    ///
+    ///    ```rust
+    ///         connection.transaction::<_, QueueError, _>(|conn| {
+    ///             diesel::insert_into(users::table).values(&new_user).execute(conn)?;
+    ///
+    ///             queue.insert_in(conn, &NewTask::from_runnable(&WelcomeEmail { user_id })?)?;
+    ///
+    ///             Ok(())
+    ///         })?;
+    ///     ```
+    pub fn insert_in(
+        &self,
+        connection: &mut PgConnection,
+        params: &NewTask,
+    ) -> Result<Task, QueueError> {
+        let new_task = self.prepare_new_task(params)?;
+
+        Self::insert_new_task_query(connection, &new_task)
+    }
+
+    pub fn schedule_task_query(
+        connection: &mut PgConnection,
+        params: &dyn Runnable,
+    ) -> Result<Task, QueueError> {
+        let scheduled_at = Self::calculate_scheduled_at(params, Self::current_time())?;
+
+        Self::insert_query(connection, params, scheduled_at)
+    }
+
+    fn calculate_scheduled_at(
+        params: &dyn Runnable,
+        now: DateTime<Utc>,
+    ) -> Result<DateTime<Utc>, QueueError> {
+        let scheduled_at = match params.cron() {
+            Some(scheduled) => match scheduled {
+                CronPattern(cron_pattern) => {
+                    let schedule = Schedule::from_str(&cron_pattern)?;
+                    let mut iterator = schedule.after(&now);
+
+                    iterator
+                        .next()
+                        .ok_or(QueueError::CronError(CronError::NoTimestampsError))?
+                }
+                ScheduleOnce(datetime) => datetime,
+            },
+            None => {
+                return Err(QueueError::CronError(CronError::TaskNotSchedulableError));
+            }
+        };
+
+        Ok(scheduled_at)
+    }
+
+    fn calculate_hash(bytes: &[u8]) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(bytes);
+        let result = hasher.finalize();
+        hex::encode(result)
+    }
+
+    pub fn insert_query(
+        connection: &mut PgConnection,
+        params: &dyn Runnable,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Self::insert_with_codec_query(connection, &MetadataCodec::default(), params, scheduled_at)
+    }
+
+    pub fn insert_with_codec_query(
+        connection: &mut PgConnection,
+        codec: &MetadataCodec,
+        params: &dyn Runnable,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        let new_task = NewTask::from_runnable_with_codec(params, codec, scheduled_at)?;
+
+        Self::insert_new_task_query(connection, &new_task)
+    }
+
+    pub fn insert_new_task_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+    ) -> Result<Task, QueueError> {
+        Self::check_inserts_not_paused_query(connection)?;
+
+        if let Some(uniq_hash) = &new_task.uniq_hash {
+            if let Some(task) = Self::find_task_by_uniq_hash_query(connection, uniq_hash) {
+                return Ok(task);
+            }
+        }
+
+        Ok(diesel::insert_into(fang_tasks::table)
+            .values(new_task)
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn insert_stream_query<I>(
+        connection: &mut PgConnection,
+        tasks: I,
+        chunk_size: usize,
+    ) -> Result<usize, QueueError>
+    where
+        I: Iterator<Item = NewTask>,
+    {
+        Self::insert_chunks_query(connection, tasks, chunk_size, &|_| Ok(()))
+    }
+
+    /// Insert the tasks in chunks, a chunk is inserted only if `validate` accepts all its tasks.
+    fn insert_chunks_query<I>(
+        connection: &mut PgConnection,
+        tasks: I,
+        chunk_size: usize,
+        validate: &dyn Fn(&NewTask) -> Result<(), QueueError>,
+    ) -> Result<usize, QueueError>
+    where
+        I: Iterator<Item = NewTask>,
+    {
+        let mut tasks = tasks.peekable();
+        let mut inserted = 0;
+
+        while tasks.peek().is_some() {
+            let chunk: Vec<NewTask> = tasks.by_ref().take(chunk_size.max(1)).collect();
+
+            chunk.iter().try_for_each(validate)?;
+
+            inserted += connection.transaction::<usize, QueueError, _>(|conn| {
+                Self::check_inserts_not_paused_query(conn)?;
+
+                Ok(diesel::insert_into(fang_tasks::table)
+                    .values(&chunk)
+                    .execute(conn)?)
+            })?;
+        }
+
+        Ok(inserted)
+    }
+
+    /// Replace the metadata of the pending task with the same `uniq_hash` and type as the `new_task`,
+    /// or insert the `new_task` if there is no such task.
+    pub fn insert_coalesce_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+    ) -> Result<Task, QueueError> {
+        Self::insert_coalesce_at_query(connection, new_task, Self::current_time())
+    }
+
+    pub fn insert_coalesce_at_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        connection.transaction::<Task, QueueError, _>(|conn| {
+            Self::check_inserts_not_paused_query(conn)?;
+            Self::lock_uniq_task_query(conn, new_task)?;
+
+            let existing_task = fang_tasks::table
+                .filter(fang_tasks::uniq_hash.eq(&new_task.uniq_hash))
+                .filter(fang_tasks::task_type.eq(&new_task.task_type))
+                .filter(Self::pending())
+                .for_update()
+                .first::<Task>(conn)
+                .optional()?;
+
+            match existing_task {
+                Some(task) => Ok(diesel::update(&task)
+                    .set((
+                        fang_tasks::metadata.eq(&new_task.metadata),
+                        fang_tasks::binary_metadata.eq(&new_task.binary_metadata),
+                        fang_tasks::updated_at.eq(now),
+                    ))
+                    .get_result::<Task>(conn)?),
+                None => Ok(diesel::insert_into(fang_tasks::table)
+                    .values(new_task)
+                    .get_result::<Task>(conn)?),
+            }
+        })
+    }
+
+    /// Serializes the concurrent inserts of the same task until the end of the transaction,
+    /// the task inserted by the previous one may not be committed yet.
+    fn lock_uniq_task_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+    ) -> Result<(), QueueError> {
+        diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+            .bind::<Text, _>(format!(
+                "fang_tasks:{}:{}",
+                new_task.task_type,
+                new_task.uniq_hash.as_deref().unwrap_or_default()
+            ))
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    pub fn insert_throttled_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+        window: Duration,
+    ) -> Result<Task, QueueError> {
+        Self::insert_throttled_at_query(connection, new_task, window, Self::current_time())
+    }
+
+    pub fn insert_throttled_at_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+        window: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        connection.transaction::<Task, QueueError, _>(|conn| {
+            Self::check_inserts_not_paused_query(conn)?;
+            Self::lock_uniq_task_query(conn, new_task)?;
+
+            let existing_task = fang_tasks::table
+                .filter(fang_tasks::uniq_hash.eq(&new_task.uniq_hash))
+                .filter(fang_tasks::task_type.eq(&new_task.task_type))
+                .filter(fang_tasks::deleted_at.is_null())
+                .filter(fang_tasks::created_at.gt(now - window))
+                .order(fang_tasks::created_at.desc())
+                .first::<Task>(conn)
+                .optional()?;
+
+            match existing_task {
+                Some(task) => Ok(task),
+                None => Ok(diesel::insert_into(fang_tasks::table)
+                    .values(new_task)
+                    .get_result::<Task>(conn)?),
+            }
+        })
+    }
+
+    pub fn insert_returning_id_query(
+        connection: &mut PgConnection,
+        new_task: &NewTask,
+    ) -> Result<Uuid, QueueError> {
+        Self::check_inserts_not_paused_query(connection)?;
+
+        if let Some(uniq_hash) = &new_task.uniq_hash {
+            let existing_id = fang_tasks::table
+                .select(fang_tasks::id)
+                .filter(fang_tasks::uniq_hash.eq(uniq_hash))
+                .filter(Self::pending())
+                .first::<Uuid>(connection)
+                .optional()?;
+
+            if let Some(id) = existing_id {
+                return Ok(id);
+            }
+        }
+
+        Ok(diesel::insert_into(fang_tasks::table)
+            .values(new_task)
+            .returning(fang_tasks::id)
+            .get_result::<Uuid>(connection)?)
+    }
+
+    pub fn set_inserts_paused_query(
+        connection: &mut PgConnection,
+        paused: bool,
+    ) -> Result<(), QueueError> {
+        Self::set_inserts_paused_at_query(connection, paused, Self::current_time())
+    }
+
+    pub fn set_inserts_paused_at_query(
+        connection: &mut PgConnection,
+        paused: bool,
+        now: DateTime<Utc>,
+    ) -> Result<(), QueueError> {
+        diesel::insert_into(fang_control::table)
+            .values((
+                fang_control::inserts_paused.eq(paused),
+                fang_control::updated_at.eq(now),
+            ))
+            .on_conflict(fang_control::id)
+            .do_update()
+            .set((
+                fang_control::inserts_paused.eq(paused),
+                fang_control::updated_at.eq(now),
+            ))
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    pub fn inserts_paused_query(connection: &mut PgConnection) -> Result<bool, QueueError> {
+        let paused = fang_control::table
+            .select(fang_control::inserts_paused)
+            .first::<bool>(connection)
+            .optional()?;
+
+        Ok(paused.unwrap_or(false))
+    }
+
+    pub fn pause_task_type_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<(), QueueError> {
+        Self::pause_task_type_at_query(connection, task_type, Self::current_time())
+    }
+
+    pub fn pause_task_type_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<(), QueueError> {
+        diesel::insert_into(fang_paused_task_types::table)
+            .values((
+                fang_paused_task_types::task_type.eq(task_type),
+                fang_paused_task_types::paused_at.eq(now),
+            ))
+            .on_conflict_do_nothing()
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    pub fn resume_task_type_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<(), QueueError> {
+        diesel::delete(
+            fang_paused_task_types::table.filter(fang_paused_task_types::task_type.eq(task_type)),
+        )
+        .execute(connection)?;
+
+        Ok(())
+    }
+
+    pub fn task_type_paused_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<bool, QueueError> {
+        Ok(diesel::select(diesel::dsl::exists(
+            fang_paused_task_types::table.filter(fang_paused_task_types::task_type.eq(task_type)),
+        ))
+        .get_result::<bool>(connection)?)
+    }
+
+    pub fn record_interrupted_query(
+        connection: &mut PgConnection,
+        tasks: &[Task],
+    ) -> Result<usize, QueueError> {
+        Self::record_interrupted_at_query(connection, tasks, Self::current_time())
+    }
+
+    pub fn record_interrupted_at_query(
+        connection: &mut PgConnection,
+        tasks: &[Task],
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let interrupted_at = now;
+
+        let rows: Vec<_> = tasks
+            .iter()
+            .map(|task| {
+                (
+                    fang_interrupted::task_id.eq(task.id),
+                    fang_interrupted::task_type.eq(&task.task_type),
+                    fang_interrupted::interrupted_at.eq(interrupted_at),
+                )
+            })
+            .collect();
+
+        Ok(diesel::insert_into(fang_interrupted::table)
+            .values(rows)
+            .execute(connection)?)
+    }
+
+    pub fn interrupted_tasks_query(
+        connection: &mut PgConnection,
+    ) -> Result<Vec<InterruptedTask>, QueueError> {
+        Ok(fang_interrupted::table
+            .order(fang_interrupted::interrupted_at.asc())
+            .load::<InterruptedTask>(connection)?)
+    }
+
+    fn check_inserts_not_paused_query(connection: &mut PgConnection) -> Result<(), QueueError> {
+        if Self::inserts_paused_query(connection)? {
+            Err(QueueError::QueuePausedError)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn fetch_task_query(connection: &mut PgConnection, task_type: String) -> Option<Task> {
+        Self::fetch_task_at_query(connection, &task_type, Self::current_time())
+            .ok()
+            .flatten()
+    }
+
+    pub fn fetch_and_touch_tasks_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError> {
+        Self::fetch_and_touch_tasks_at_query(connection, task_type, limit, None)
+    }
+
+    pub fn fetch_and_touch_tasks_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        limit: i64,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Task>, QueueError> {
+        connection.transaction::<Vec<Task>, QueueError, _>(|conn| {
+            if Self::task_type_paused_query(conn, task_type)? {
+                return Ok(Vec::new());
+            }
+
+            let ids = fang_tasks::table
+                .select(fang_tasks::id)
+                .order((
+                    fang_tasks::priority.desc(),
+                    fang_tasks::created_at.asc(),
+                    fang_tasks::scheduled_at.asc(),
+                ))
+                .limit(limit)
+                .filter(Self::runnable(now))
+                .filter(fang_tasks::task_type.eq(task_type))
+                .for_update()
+                .skip_locked()
+                .load::<Uuid>(conn)?;
+
+            let mut tasks = diesel::update(fang_tasks::table.filter(fang_tasks::id.eq_any(ids)))
+                .set((
+                    fang_tasks::state.eq(FangTaskState::InProgress),
+                    fang_tasks::updated_at.eq(now.unwrap_or_else(Self::current_time)),
+                ))
+                .get_results::<Task>(conn)?;
+
+            tasks.sort_by_key(|task| (Reverse(task.priority), task.created_at, task.scheduled_at));
+
+            Ok(tasks)
+        })
+    }
+
+    /// Fetch and lock the next task of the `task_type` type that is scheduled not later than `now`.
+    pub fn fetch_task_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_next_task_query(connection, task_type, Some(now))
+    }
+
+    /// Fetch and lock the next runnable task of the `task_type` type at `now`, see [`Queue::time_of`].
+    fn fetch_next_task_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        if Self::task_type_paused_query(connection, task_type)? {
+            return Ok(None);
+        }
+
+        Ok(fang_tasks::table
+            .order((
+                fang_tasks::priority.desc(),
+                fang_tasks::created_at.asc(),
+                fang_tasks::scheduled_at.asc(),
+            ))
+            .limit(1)
+            .filter(Self::runnable(now))
+            .filter(fang_tasks::task_type.eq(task_type))
+            .for_update()
+            .skip_locked()
+            .get_result::<Task>(connection)
+            .optional()?)
+    }
+
+    pub fn fetch_and_touch_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            match Self::fetch_task_at_query(conn, task_type, now)? {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now,
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn fetch_with_lease_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        lease: Duration,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_with_lease_at_query(connection, task_type, lease, Self::current_time())
+    }
+
+    /// Fetch the next task of the `task_type` type that is scheduled not later than `now`, leased until `now + lease`.
+    pub fn fetch_with_lease_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        lease: Duration,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            match Self::fetch_task_at_query(conn, task_type, now)? {
+                Some(task) => Ok(Some(
+                    diesel::update(&task)
+                        .set((
+                            fang_tasks::state.eq(FangTaskState::InProgress),
+                            fang_tasks::locked_until.eq(now + lease),
+                            fang_tasks::updated_at.eq(now),
+                        ))
+                        .get_result::<Task>(conn)?,
+                )),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn requeue_expired_leases_query(
+        connection: &mut PgConnection,
+    ) -> Result<usize, QueueError> {
+        Self::requeue_expired_leases_at_query(connection, Self::current_time())
+    }
+
+    /// Requeue the in progress tasks with the leases that expired before `now`.
+    pub fn requeue_expired_leases_at_query(
+        connection: &mut PgConnection,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+            .filter(fang_tasks::deleted_at.is_null())
+            .filter(fang_tasks::locked_until.lt(now));
+
+        Ok(diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::locked_until.eq(None::<DateTime<Utc>>),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .execute(connection)?)
+    }
+
+    pub fn release_lease_query(
+        connection: &mut PgConnection,
+        task: &Task,
+    ) -> Result<(), QueueError> {
+        Self::release_lease_at_query(connection, task, Self::current_time())
+    }
+
+    /// Release the lease of the task if it's still the lease of its fetch and it doesn't expire before `now`.
+    pub fn release_lease_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        now: DateTime<Utc>,
+    ) -> Result<(), QueueError> {
+        let locked_until = match task.locked_until {
+            Some(locked_until) => locked_until,
+            None => return Ok(()),
+        };
+
+        // a requeued task has no lease or the lease of another fetch
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(task.id))
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+            .filter(fang_tasks::locked_until.eq(locked_until))
+            .filter(fang_tasks::locked_until.gt(now));
+
+        let released = diesel::update(query)
+            .set(fang_tasks::locked_until.eq(None::<DateTime<Utc>>))
+            .execute(connection)?;
+
+        if released == 0 {
+            return Err(QueueError::LeaseLostError(task.id));
+        }
+
+        Ok(())
+    }
+
+    pub fn requeue_task_query(
+        connection: &mut PgConnection,
+        task: &Task,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::requeue_task_at_query(connection, task, Self::current_time())
+    }
+
+    pub fn requeue_task_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(task.id))
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+            .filter(fang_tasks::updated_at.eq(task.updated_at));
+
+        Ok(diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Retried),
+                fang_tasks::locked_until.eq(None::<DateTime<Utc>>),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)
+            .optional()?)
+    }
+
+    pub fn count_runnable_tasks_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<i64, QueueError> {
+        Self::count_runnable_tasks_at_query(connection, task_type, None)
+    }
+
+    pub fn count_runnable_tasks_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<i64, QueueError> {
+        Ok(fang_tasks::table
+            .filter(Self::runnable(now))
+            .filter(fang_tasks::task_type.eq(task_type))
+            .count()
+            .get_result::<i64>(connection)?)
+    }
+
+    pub fn task_position_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<Option<i64>, QueueError> {
+        Self::task_position_at_query(connection, id, None)
+    }
+
+    pub fn task_position_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<i64>, QueueError> {
+        let task = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(Self::pending())
+            .first::<Task>(connection)
+            .optional()?;
+
+        let task = match task {
+            Some(task) => task,
+            None => return Ok(None),
+        };
+
+        let same_priority = fang_tasks::priority.eq(task.priority);
+        let same_created_at = fang_tasks::created_at.eq(task.created_at);
+
+        let ahead = fang_tasks::table
+            .filter(Self::runnable(now))
+            .filter(fang_tasks::task_type.eq(&task.task_type))
+            .filter(
+                fang_tasks::priority
+                    .gt(task.priority)
+                    .or(same_priority.and(fang_tasks::created_at.lt(task.created_at)))
+                    .or(same_priority
+                        .and(same_created_at)
+                        .and(fang_tasks::scheduled_at.lt(task.scheduled_at))),
+            )
+            .count()
+            .get_result::<i64>(connection)?;
+
+        Ok(Some(ahead))
+    }
+
+    pub fn fetch_task_with_lock_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        lock_mode: &LockMode,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_task_with_lock_at_query(connection, task_type, lock_mode, None)
+    }
+
+    pub fn fetch_task_with_lock_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        lock_mode: &LockMode,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        if Self::task_type_paused_query(connection, task_type)? {
+            return Ok(None);
+        }
+
+        let query = fang_tasks::table
+            .order((
+                fang_tasks::priority.desc(),
+                fang_tasks::created_at.asc(),
+                fang_tasks::scheduled_at.asc(),
+            ))
+            .limit(1)
+            .filter(Self::runnable(now))
+            .filter(fang_tasks::task_type.eq(task_type));
+
+        let task = match lock_mode {
+            LockMode::None => query.get_result::<Task>(connection),
+            LockMode::ForUpdate => query
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(connection),
+            LockMode::ForShare => query.for_share().get_result::<Task>(connection),
+            LockMode::NoWait => query.for_update().no_wait().get_result::<Task>(connection),
+        };
+
+        Ok(task.optional()?)
+    }
+
+    pub fn fetch_and_touch_query(
+        connection: &mut PgConnection,
+        task_type: String,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_with_isolation_query(
+            connection,
+            &IsolationLevel::default(),
+            task_type,
+        )
+    }
+
+    pub fn fetch_and_touch_with_isolation_query(
+        connection: &mut PgConnection,
+        isolation_level: &IsolationLevel,
+        task_type: String,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_in_transaction(connection, isolation_level, |conn| {
+            Self::fetch_and_touch_with_function_query(conn, &task_type)
+        })
+    }
+
+    /// Fetch and touch the next task of the `task_type` type that is scheduled not later than `now`
+    /// in a transaction with the `isolation_level`, the current time of the database is used if `now` is `None`.
+    pub fn fetch_and_touch_with_isolation_at_query(
+        connection: &mut PgConnection,
+        isolation_level: &IsolationLevel,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_in_transaction(connection, isolation_level, |conn| {
+            Self::fetch_and_touch_with_function_at_query(conn, task_type, now)
+        })
+    }
+
+    fn fetch_in_transaction(
+        connection: &mut PgConnection,
+        isolation_level: &IsolationLevel,
+        fetch_and_touch: impl FnOnce(&mut PgConnection) -> Result<Option<Task>, QueueError>,
+    ) -> Result<Option<Task>, QueueError> {
+        match isolation_level {
+            IsolationLevel::ReadCommitted => {
+                connection.transaction::<Option<Task>, QueueError, _>(fetch_and_touch)
+            }
+            IsolationLevel::RepeatableRead => connection
+                .build_transaction()
+                .repeatable_read()
+                .run::<Option<Task>, QueueError, _>(fetch_and_touch),
+            IsolationLevel::Serializable => connection
+                .build_transaction()
+                .serializable()
+                .run::<Option<Task>, QueueError, _>(fetch_and_touch),
+        }
+    }
+
+    /// Fetch and touch a task with the `fang_fetch_task` SQL function,
+    /// so the task is selected and marked as `FangTaskState::InProgress` in one round-trip.
+    pub fn fetch_and_touch_with_function_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        Ok(diesel::sql_query("SELECT * FROM fang_fetch_task($1)")
+            .bind::<Text, _>(task_type)
+            .get_result::<Task>(connection)
+            .optional()?)
+    }
+
+    /// Fetch and touch a task with the `fang_fetch_task` SQL function at `now`,
+    /// the current time of the database is used if `now` is `None`.
+    pub fn fetch_and_touch_with_function_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        Ok(diesel::sql_query(
+            "SELECT * FROM fang_fetch_task($1, COALESCE($2, statement_timestamp()))",
+        )
+        .bind::<Text, _>(task_type)
+        .bind::<Nullable<Timestamptz>, _>(now)
+        .get_result::<Task>(connection)
+        .optional()?)
+    }
+
+    pub fn fetch_task_of_types_query(
+        connection: &mut PgConnection,
+        task_types: &[&str],
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_task_of_types_at_query(connection, task_types, None)
+    }
+
+    pub fn fetch_task_of_types_at_query(
+        connection: &mut PgConnection,
+        task_types: &[&str],
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            let paused_task_types = fang_paused_task_types::table
+                .select(fang_paused_task_types::task_type)
+                .filter(fang_paused_task_types::task_type.eq_any(task_types))
+                .load::<String>(conn)?;
+
+            let task_types: Vec<&str> = task_types
+                .iter()
+                .copied()
+                .filter(|task_type| !paused_task_types.iter().any(|paused| paused == task_type))
+                .collect();
+
+            let task = fang_tasks::table
+                .order((
+                    fang_tasks::priority.desc(),
+                    fang_tasks::created_at.asc(),
+                    fang_tasks::scheduled_at.asc(),
+                ))
+                .limit(1)
+                .filter(Self::runnable(now))
+                .filter(fang_tasks::task_type.eq_any(task_types))
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(conn)
+                .optional()?;
+
+            match task {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now.unwrap_or_else(Self::current_time),
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn fetch_task_by_tag_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        key: &str,
+        value: serde_json::Value,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_task_by_tag_at_query(connection, task_type, key, value, None)
+    }
+
+    pub fn fetch_task_by_tag_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        key: &str,
+        value: serde_json::Value,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        let mut tag = serde_json::Map::new();
+        tag.insert(key.to_string(), value);
+
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            if Self::task_type_paused_query(conn, task_type)? {
+                return Ok(None);
+            }
+
+            let task = fang_tasks::table
+                .order((
+                    fang_tasks::priority.desc(),
+                    fang_tasks::created_at.asc(),
+                    fang_tasks::scheduled_at.asc(),
+                ))
+                .limit(1)
+                .filter(Self::runnable(now))
+                .filter(fang_tasks::task_type.eq(task_type))
+                .filter(fang_tasks::tags.contains(serde_json::Value::Object(tag)))
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(conn)
+                .optional()?;
+
+            match task {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now.unwrap_or_else(Self::current_time),
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// The runnable tasks of the `task_type` type, the same tasks workers fetch from.
+    /// Add your own filters to it and fetch a task with [`Queue::fetch_and_touch_with_query`],
+    /// which applies the ordering and the locking of workers.
+    ///
+    ///    ```rust
+    ///         let query = Queue::runnable_tasks_query("emails")
+    ///             .filter(fang_tasks::priority.gt(0));
+    ///
+    ///         let task = Queue::fetch_and_touch_with_query(&mut connection, query)?;
+    ///    ```
+    pub fn runnable_tasks_query(task_type: &str) -> BoxedTaskQuery<'_> {
+        Self::runnable_tasks_at_query(task_type, None)
+    }
+
+    pub fn runnable_tasks_at_query(
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> BoxedTaskQuery<'_> {
+        fang_tasks::table
+            .into_boxed()
+            .filter(Self::runnable(now))
+            .filter(fang_tasks::task_type.eq(task_type))
+    }
+
+    /// Fetch the next task selected by the `query`, usually built with [`Queue::runnable_tasks_query`],
+    /// lock it with `FOR UPDATE SKIP LOCKED` and mark it as `FangTaskState::InProgress` in the same transaction.
+    pub fn fetch_and_touch_with_query(
+        connection: &mut PgConnection,
+        query: BoxedTaskQuery<'_>,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_with_at_query(connection, query, Self::current_time())
+    }
+
+    pub fn fetch_and_touch_with_at_query(
+        connection: &mut PgConnection,
+        query: BoxedTaskQuery<'_>,
+        now: DateTime<Utc>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            let task = fang_tasks::table
+                .filter(fang_tasks::id.eq_any(query.select(fang_tasks::id)))
+                .order((
+                    fang_tasks::priority.desc(),
+                    fang_tasks::created_at.asc(),
+                    fang_tasks::scheduled_at.asc(),
+                ))
+                .limit(1)
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(conn)
+                .optional()?;
+
+            match task {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now,
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn fetch_and_touch_random_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_random_at_query(connection, task_type, None)
+    }
+
+    pub fn fetch_and_touch_random_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            if Self::task_type_paused_query(conn, task_type)? {
+                return Ok(None);
+            }
+
+            let task = fang_tasks::table
+                .order(sql::<Double>("random()"))
+                .limit(1)
+                .filter(Self::runnable(now))
+                .filter(fang_tasks::task_type.eq(task_type))
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(conn)
+                .optional()?;
+
+            match task {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now.unwrap_or_else(Self::current_time),
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn fetch_and_touch_lowest_priority_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_lowest_priority_at_query(connection, task_type, None)
+    }
+
+    pub fn fetch_and_touch_lowest_priority_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            if Self::task_type_paused_query(conn, task_type)? {
+                return Ok(None);
+            }
+
+            let task = fang_tasks::table
+                .order((
+                    fang_tasks::priority.asc(),
+                    fang_tasks::created_at.asc(),
+                    fang_tasks::scheduled_at.asc(),
+                ))
+                .limit(1)
+                .filter(Self::runnable(now))
+                .filter(fang_tasks::task_type.eq(task_type))
+                .for_update()
+                .skip_locked()
+                .get_result::<Task>(conn)
+                .optional()?;
+
+            match task {
+                Some(task) => Ok(Some(Self::update_task_state_at_query(
+                    conn,
+                    &task,
+                    FangTaskState::InProgress,
+                    now.unwrap_or_else(Self::current_time),
+                )?)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    pub fn fetch_and_touch_longest_backlog_query(
+        connection: &mut PgConnection,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_longest_backlog_at_query(connection, None)
+    }
+
+    pub fn fetch_and_touch_longest_backlog_at_query(
+        connection: &mut PgConnection,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            let backlogs = fang_tasks::table
+                .filter(Self::runnable(now))
+                .group_by(fang_tasks::task_type)
+                .select((fang_tasks::task_type, count_star()))
+                .order((count_star().desc(), fang_tasks::task_type.asc()))
+                .load::<(String, i64)>(conn)?;
+
+            // the tasks of the largest backlog can be locked by other workers or their type can be paused
+            for (task_type, _) in backlogs {
+                if let Some(task) = Self::fetch_next_task_query(conn, &task_type, now)? {
+                    return Ok(Some(Self::update_task_state_at_query(
+                        conn,
+                        &task,
+                        FangTaskState::InProgress,
+                        now.unwrap_or_else(Self::current_time),
+                    )?));
+                }
+            }
+
+            Ok(None)
+        })
+    }
+
+    pub fn fetch_and_touch_in_order_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_touch_in_order_at_query(connection, task_type, None)
+    }
+
+    pub fn fetch_and_touch_in_order_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        connection.transaction::<Option<Task>, QueueError, _>(|conn| {
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind::<Text, _>(format!("fang_tasks:{}", task_type))
+                .execute(conn)?;
+
+            if Self::task_type_paused_query(conn, task_type)? {
+                return Ok(None);
+            }
+
+            let in_progress = diesel::select(diesel::dsl::exists(
+                fang_tasks::table
+                    .filter(fang_tasks::task_type.eq(task_type))
+                    .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+                    .filter(fang_tasks::deleted_at.is_null()),
+            ))
+            .get_result::<bool>(conn)?;
+
+            if in_progress {
+                return Ok(None);
+            }
+
+            let task = fang_tasks::table
+                .order(fang_tasks::created_at.asc())
+                .filter(Self::pending())
+                .filter(Self::not_expired("fang_tasks", now))
+                .filter(fang_tasks::task_type.eq(task_type))
+                .for_update()
+                .first::<Task>(conn)
+                .optional()?;
+
+            let task = match task {
+                Some(task) => task,
+                None => return Ok(None),
+            };
+
+            // the oldest task waits for its schedule and its dependency, the younger tasks wait for it
+            let runnable = diesel::select(diesel::dsl::exists(
+                fang_tasks::table
+                    .filter(fang_tasks::id.eq(task.id))
+                    .filter(fang_tasks::scheduled_at.le(Self::time_of(now)))
+                    .filter(Self::dependency_finished("fang_tasks")),
+            ))
+            .get_result::<bool>(conn)?;
+
+            if !runnable {
+                return Ok(None);
+            }
+
+            Ok(Some(Self::update_task_state_at_query(
+                conn,
+                &task,
+                FangTaskState::InProgress,
+                now.unwrap_or_else(Self::current_time),
+            )?))
+        })
+    }
+
+    pub fn fetch_and_finish_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        Self::fetch_and_finish_at_query(connection, task_type, None)
+    }
+
+    pub fn fetch_and_finish_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Task>, QueueError> {
+        if Self::task_type_paused_query(connection, task_type)? {
+            return Ok(None);
+        }
+
+        let pending_tasks = diesel::alias!(fang_tasks as pending_tasks);
+
+        let task_id = pending_tasks
+            .select(pending_tasks.field(fang_tasks::id))
+            .order((
+                pending_tasks.field(fang_tasks::priority).desc(),
+                pending_tasks.field(fang_tasks::created_at).asc(),
+                pending_tasks.field(fang_tasks::scheduled_at).asc(),
+            ))
+            .limit(1)
+            .filter(
+                pending_tasks
+                    .field(fang_tasks::scheduled_at)
+                    .le(Self::time_of(now)),
+            )
+            .filter(
+                pending_tasks
+                    .field(fang_tasks::state)
+                    .eq_any(vec![FangTaskState::New, FangTaskState::Retried]),
+            )
+            .filter(pending_tasks.field(fang_tasks::deleted_at).is_null())
+            .filter(Self::dependency_finished("pending_tasks"))
+            .filter(Self::not_expired("pending_tasks", now))
+            .filter(pending_tasks.field(fang_tasks::task_type).eq(task_type))
+            .for_update()
+            .skip_locked();
+
+        Ok(diesel::update(fang_tasks::table)
+            .filter(fang_tasks::id.eq_any(task_id))
+            .set((
+                fang_tasks::state.eq(FangTaskState::Finished),
+                fang_tasks::updated_at.eq(now.unwrap_or_else(Self::current_time)),
+            ))
+            .get_result::<Task>(connection)
+            .optional()?)
+    }
+
+    pub fn find_task_by_id_query(connection: &mut PgConnection, id: Uuid) -> Option<Task> {
+        Self::find_task_by_id_with_deleted_query(connection, id, false)
+    }
+
+    pub fn task_status_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<Option<TaskStatus>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(fang_tasks::deleted_at.is_null())
+            .select((
+                fang_tasks::id,
+                fang_tasks::state,
+                fang_tasks::retries,
+                fang_tasks::error_message,
+                fang_tasks::created_at,
+                fang_tasks::updated_at,
+            ))
+            .first::<TaskStatus>(connection)
+            .optional()?)
+    }
+
+    pub fn find_task_by_id_with_deleted_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        include_deleted: bool,
+    ) -> Option<Task> {
+        let mut query = fang_tasks::table.filter(fang_tasks::id.eq(id)).into_boxed();
+
+        if !include_deleted {
+            query = query.filter(fang_tasks::deleted_at.is_null());
+        }
+
+        query.first::<Task>(connection).ok()
+    }
+
+    pub fn soft_delete_task_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<usize, QueueError> {
+        Self::soft_delete_task_at_query(connection, id, Self::current_time())
+    }
+
+    pub fn soft_delete_task_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(fang_tasks::deleted_at.is_null());
+
+        Ok(diesel::update(query)
+            .set((
+                fang_tasks::deleted_at.eq(now),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .execute(connection)?)
+    }
+
+    pub fn move_task_to_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        target_table: &str,
+    ) -> Result<Option<Task>, QueueError> {
+        let target_table = target_table
+            .split('.')
+            .map(|identifier| format!("\"{}\"", identifier.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(".");
+
+        // one statement, so the task is either in the source or in the target table
+        Ok(diesel::sql_query(format!(
+            "WITH moved AS (DELETE FROM fang_tasks WHERE id = $1 RETURNING *) \
+             INSERT INTO {target_table} SELECT * FROM moved RETURNING *"
+        ))
+        .bind::<diesel::sql_types::Uuid, _>(id)
+        .get_result::<Task>(connection)
+        .optional()?)
+    }
+
+    pub fn create_task_type_partition_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<(), QueueError> {
+        diesel::sql_query("SELECT fang_create_task_type_partition($1)")
+            .bind::<Text, _>(task_type)
+            .execute(connection)?;
+
+        Ok(())
+    }
+
+    pub fn export_tasks_query(
+        connection: &mut PgConnection,
+        state: FangTaskState,
+    ) -> Result<String, QueueError> {
+        let tasks = fang_tasks::table
+            .filter(fang_tasks::state.eq(state))
+            .filter(fang_tasks::deleted_at.is_null())
+            .order(fang_tasks::created_at.asc())
+            .load::<Task>(connection)?;
+
+        Ok(serde_json::to_string(&tasks)?)
+    }
+
+    pub fn import_tasks_query(
+        connection: &mut PgConnection,
+        json: &str,
+    ) -> Result<usize, QueueError> {
+        let tasks: Vec<Task> = serde_json::from_str(json)?;
+
+        let rows: Vec<_> = tasks
+            .into_iter()
+            .map(|task| {
+                (
+                    fang_tasks::id.eq(task.id),
+                    fang_tasks::metadata.eq(task.metadata),
+                    fang_tasks::error_message.eq(task.error_message),
+                    fang_tasks::state.eq(task.state),
+                    fang_tasks::task_type.eq(task.task_type),
+                    fang_tasks::uniq_hash.eq(task.uniq_hash),
+                    fang_tasks::retries.eq(task.retries),
+                    fang_tasks::scheduled_at.eq(task.scheduled_at),
+                    fang_tasks::created_at.eq(task.created_at),
+                    fang_tasks::updated_at.eq(task.updated_at),
+                    fang_tasks::binary_metadata.eq(task.binary_metadata),
+                    fang_tasks::error_details.eq(task.error_details),
+                    fang_tasks::max_retries.eq(task.max_retries),
+                    fang_tasks::deleted_at.eq(task.deleted_at),
+                    fang_tasks::priority.eq(task.priority),
+                    fang_tasks::tags.eq(task.tags),
+                    fang_tasks::correlation_id.eq(task.correlation_id),
+                    fang_tasks::locked_until.eq(task.locked_until),
+                    fang_tasks::depends_on.eq(task.depends_on),
+                    fang_tasks::result.eq(task.result),
+                    fang_tasks::expires_at.eq(task.expires_at),
+                )
+            })
+            .collect();
+
+        Ok(diesel::insert_into(fang_tasks::table)
+            .values(&rows)
+            .on_conflict_do_nothing()
+            .execute(connection)?)
+    }
+
+    pub fn remove_all_tasks_query(connection: &mut PgConnection) -> Result<usize, QueueError> {
+        Ok(diesel::delete(fang_tasks::table).execute(connection)?)
+    }
+
+    pub fn remove_all_scheduled_tasks_query(
+        connection: &mut PgConnection,
+    ) -> Result<usize, QueueError> {
+        Self::remove_all_scheduled_tasks_at_query(connection, Self::current_time())
+    }
+
+    pub fn remove_all_scheduled_tasks_at_query(
+        connection: &mut PgConnection,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table.filter(fang_tasks::scheduled_at.gt(now));
+
+        Ok(diesel::delete(query).execute(connection)?)
+    }
+
+    pub fn remove_tasks_of_type_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table.filter(fang_tasks::task_type.eq(task_type));
+
+        Ok(diesel::delete(query).execute(connection)?)
+    }
+
+    pub fn prune_finished_tasks_query(
+        connection: &mut PgConnection,
+        threshold: i64,
+        target: i64,
+    ) -> Result<usize, QueueError> {
+        connection.transaction::<usize, QueueError, _>(|conn| {
+            let count = fang_tasks::table
+                .filter(fang_tasks::deleted_at.is_null())
+                .count()
+                .get_result::<i64>(conn)?;
+
+            if count <= threshold {
+                return Ok(0);
+            }
+
+            let finished_tasks = diesel::alias!(fang_tasks as finished_tasks);
+
+            let oldest_finished_ids = finished_tasks
+                .select(finished_tasks.field(fang_tasks::id))
+                .filter(
+                    finished_tasks
+                        .field(fang_tasks::state)
+                        .eq(FangTaskState::Finished),
+                )
+                .filter(finished_tasks.field(fang_tasks::deleted_at).is_null())
+                .order(finished_tasks.field(fang_tasks::updated_at).asc())
+                .limit((count - target).max(0));
+
+            Ok(diesel::delete(fang_tasks::table)
+                .filter(fang_tasks::id.eq_any(oldest_finished_ids))
+                .execute(conn)?)
+        })
+    }
+
+    pub fn remove_task_by_metadata_query(
+        connection: &mut PgConnection,
+        task: &dyn Runnable,
+    ) -> Result<usize, QueueError> {
+        Self::remove_task_by_metadata_with_codec_query(connection, &MetadataCodec::default(), task)
+    }
+
+    pub fn remove_task_by_metadata_with_codec_query(
+        connection: &mut PgConnection,
+        codec: &MetadataCodec,
+        task: &dyn Runnable,
+    ) -> Result<usize, QueueError> {
+        let uniq_hash = Self::calculate_hash(&codec.encode(task)?.hash_input());
+
+        let query = fang_tasks::table.filter(fang_tasks::uniq_hash.eq(uniq_hash));
+
+        Ok(diesel::delete(query).execute(connection)?)
+    }
+
+    pub fn remove_task_query(connection: &mut PgConnection, id: Uuid) -> Result<usize, QueueError> {
+        let query = fang_tasks::table.filter(fang_tasks::id.eq(id));
+
+        Ok(diesel::delete(query).execute(connection)?)
+    }
+
+    pub fn update_task_state_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        state: FangTaskState,
+    ) -> Result<Task, QueueError> {
+        Self::update_task_state_at_query(connection, task, state, Self::current_time())
+    }
+
+    pub fn update_task_state_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        state: FangTaskState,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Ok(diesel::update(task)
+            .set((fang_tasks::state.eq(state), fang_tasks::updated_at.eq(now)))
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn finish_task_with_result_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        result: serde_json::Value,
+    ) -> Result<Task, QueueError> {
+        Self::finish_task_with_result_at_query(connection, task, result, Self::current_time())
+    }
+
+    pub fn finish_task_with_result_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        result: serde_json::Value,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Ok(diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Finished),
+                fang_tasks::result.eq(result),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn fail_task_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        Self::fail_task_at_query(connection, task, error, Self::current_time())
+    }
+
+    pub fn fail_task_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Ok(diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Failed),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn fail_task_with_details_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+        details: serde_json::Value,
+    ) -> Result<Task, QueueError> {
+        Self::fail_task_with_details_at_query(
+            connection,
+            task,
+            error,
+            details,
+            Self::current_time(),
+        )
+    }
+
+    pub fn fail_task_with_details_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+        details: serde_json::Value,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Ok(diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Failed),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::error_details.eq(details),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn finish_tasks_query(
+        connection: &mut PgConnection,
+        ids: &[Uuid],
+    ) -> Result<usize, QueueError> {
+        Self::finish_tasks_at_query(connection, ids, Self::current_time())
+    }
+
+    pub fn finish_tasks_at_query(
+        connection: &mut PgConnection,
+        ids: &[Uuid],
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        Ok(
+            diesel::update(fang_tasks::table.filter(fang_tasks::id.eq_any(ids)))
+                .set((
+                    fang_tasks::state.eq(FangTaskState::Finished),
+                    fang_tasks::updated_at.eq(now),
+                ))
+                .execute(connection)?,
+        )
+    }
+
+    pub fn fail_tasks_query(
+        connection: &mut PgConnection,
+        ids: &[Uuid],
+        error: &str,
+    ) -> Result<usize, QueueError> {
+        Self::fail_tasks_at_query(connection, ids, error, Self::current_time())
+    }
+
+    pub fn fail_tasks_at_query(
+        connection: &mut PgConnection,
+        ids: &[Uuid],
+        error: &str,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        Ok(
+            diesel::update(fang_tasks::table.filter(fang_tasks::id.eq_any(ids)))
+                .set((
+                    fang_tasks::state.eq(FangTaskState::Failed),
+                    fang_tasks::error_message.eq(error),
+                    fang_tasks::updated_at.eq(now),
+                ))
+                .execute(connection)?,
+        )
+    }
+
+    pub fn quarantine_task_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        Self::quarantine_task_at_query(connection, task, error, Self::current_time())
+    }
+
+    pub fn quarantine_task_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        error: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Ok(diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Quarantined),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)?)
+    }
+
+    pub fn cancel_task_query(connection: &mut PgConnection, id: Uuid) -> Result<Task, QueueError> {
+        Self::cancel_task_at_query(connection, id, Self::current_time())
+    }
+
+    pub fn cancel_task_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(Self::pending());
+
+        diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Cancelled),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)
+            .optional()?
+            .ok_or(QueueError::TaskNotPendingError(id))
+    }
+
+    pub fn bump_task_query(connection: &mut PgConnection, id: Uuid) -> Result<Task, QueueError> {
+        Self::bump_task_at_query(connection, id, Self::current_time())
+    }
+
+    pub fn bump_task_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        connection.transaction::<Task, QueueError, _>(|conn| {
+            let task = fang_tasks::table
+                .filter(fang_tasks::id.eq(id))
+                .filter(Self::pending())
+                .for_update()
+                .first::<Task>(conn)
+                .optional()?
+                .ok_or(QueueError::TaskNotPendingError(id))?;
+
+            let highest_priority = fang_tasks::table
+                .filter(Self::pending())
+                .filter(fang_tasks::task_type.eq(&task.task_type))
+                .filter(fang_tasks::id.ne(id))
+                .select(diesel::dsl::max(fang_tasks::priority))
+                .get_result::<Option<i32>>(conn)?;
+
+            // ahead of the tasks with the same priority too, whatever their creation time is
+            let priority = match highest_priority {
+                Some(highest_priority) => task.priority.max(highest_priority.saturating_add(1)),
+                None => task.priority,
+            };
+
+            Ok(diesel::update(&task)
+                .set((
+                    fang_tasks::priority.eq(priority),
+                    fang_tasks::updated_at.eq(now),
+                ))
+                .get_result::<Task>(conn)?)
+        })
+    }
+
+    pub fn reschedule_task_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        scheduled_at: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        Self::reschedule_task_at_query(connection, id, scheduled_at, Self::current_time())
+    }
+
+    pub fn reschedule_task_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        scheduled_at: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(Self::pending());
+
+        diesel::update(query)
+            .set((
+                fang_tasks::scheduled_at.eq(scheduled_at),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)
+            .optional()?
+            .ok_or(QueueError::TaskNotPendingError(id))
+    }
+
+    pub fn reassign_task_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        new_type: &str,
+    ) -> Result<Task, QueueError> {
+        Self::reassign_task_at_query(connection, id, new_type, Self::current_time())
+    }
+
+    pub fn reassign_task_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        new_type: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::id.eq(id))
+            .filter(Self::pending());
+
+        diesel::update(query)
+            .set((
+                fang_tasks::task_type.eq(new_type),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)
+            .optional()?
+            .ok_or(QueueError::TaskNotPendingError(id))
+    }
+
+    pub fn increment_retries_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+    ) -> Result<i32, QueueError> {
+        Self::increment_retries_at_query(connection, id, Self::current_time())
+    }
+
+    pub fn increment_retries_at_query(
+        connection: &mut PgConnection,
+        id: Uuid,
+        now: DateTime<Utc>,
+    ) -> Result<i32, QueueError> {
+        Ok(
+            diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(id)))
+                .set((
+                    fang_tasks::retries.eq(fang_tasks::retries + 1),
+                    fang_tasks::updated_at.eq(now),
+                ))
+                .returning(fang_tasks::retries)
+                .get_result::<i32>(connection)?,
+        )
+    }
+
+    pub fn reset_retried_query(connection: &mut PgConnection) -> Result<usize, QueueError> {
+        Self::reset_retried_at_query(connection, Self::current_time())
+    }
+
+    pub fn reset_retried_at_query(
+        connection: &mut PgConnection,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::Retried))
+            .filter(fang_tasks::deleted_at.is_null());
+
+        Ok(diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::New),
+                fang_tasks::error_message.eq(None::<String>),
+                fang_tasks::error_details.eq(None::<serde_json::Value>),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .execute(connection)?)
+    }
+
+    pub fn expire_tasks_query(connection: &mut PgConnection) -> Result<usize, QueueError> {
+        Self::expire_tasks_at_query(connection, Self::current_time())
+    }
+
+    /// Move the pending tasks that expired not later than `now` to the `expired` state.
+    pub fn expire_tasks_at_query(
+        connection: &mut PgConnection,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let query = fang_tasks::table
+            .filter(Self::pending())
+            .filter(fang_tasks::expires_at.le(now));
+
+        Ok(diesel::update(query)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Expired),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .execute(connection)?)
+    }
+
+    pub fn transform_metadata_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        transform: &dyn Fn(serde_json::Value) -> serde_json::Value,
+    ) -> Result<usize, QueueError> {
+        Self::transform_metadata_at_query(connection, task_type, transform, Self::current_time())
+    }
+
+    pub fn transform_metadata_at_query(
+        connection: &mut PgConnection,
+        task_type: &str,
+        transform: &dyn Fn(serde_json::Value) -> serde_json::Value,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        connection.transaction::<usize, QueueError, _>(|conn| {
+            let tasks = fang_tasks::table
+                .filter(fang_tasks::task_type.eq(task_type))
+                .filter(fang_tasks::binary_metadata.is_null())
+                .filter(Self::pending())
+                .select((fang_tasks::id, fang_tasks::metadata))
+                .for_update()
+                .load::<(Uuid, serde_json::Value)>(conn)?;
+            let mut transformed = 0;
+
+            for (id, metadata) in tasks {
+                let new_metadata = transform(metadata.clone());
+
+                if new_metadata == metadata {
+                    continue;
+                }
+
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(id)))
+                    .set((
+                        fang_tasks::metadata.eq(new_metadata),
+                        fang_tasks::updated_at.eq(now),
+                    ))
+                    .execute(conn)?;
+
+                transformed += 1;
+            }
+
+            Ok(transformed)
+        })
+    }
+
+    pub fn bulk_transition_query(
+        connection: &mut PgConnection,
+        from: FangTaskState,
+        to: FangTaskState,
+        older_than: Option<Duration>,
+    ) -> Result<usize, QueueError> {
+        Self::bulk_transition_at_query(connection, from, to, older_than, Self::current_time())
+    }
+
+    pub fn bulk_transition_at_query(
+        connection: &mut PgConnection,
+        from: FangTaskState,
+        to: FangTaskState,
+        older_than: Option<Duration>,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        let mut query = fang_tasks::table
+            .select(fang_tasks::id)
+            .filter(fang_tasks::state.eq(from))
+            .filter(fang_tasks::deleted_at.is_null())
+            .into_boxed();
+
+        if let Some(older_than) = older_than {
+            query = query.filter(fang_tasks::updated_at.lt(now - older_than));
+        }
+
+        Ok(
+            diesel::update(fang_tasks::table.filter(fang_tasks::id.eq_any(query)))
+                .set((fang_tasks::state.eq(to), fang_tasks::updated_at.eq(now)))
+                .execute(connection)?,
+        )
+    }
+
+    pub fn bump_stale_new_query(
+        connection: &mut PgConnection,
+        older_than: Duration,
+        notify: bool,
+    ) -> Result<usize, QueueError> {
+        Self::bump_stale_new_at_query(connection, older_than, notify, Self::current_time())
+    }
+
+    pub fn bump_stale_new_at_query(
+        connection: &mut PgConnection,
+        older_than: Duration,
+        notify: bool,
+        now: DateTime<Utc>,
+    ) -> Result<usize, QueueError> {
+        connection.transaction::<usize, QueueError, _>(|conn| {
+            let query = fang_tasks::table
+                .filter(fang_tasks::state.eq(FangTaskState::New))
+                .filter(fang_tasks::deleted_at.is_null())
+                .filter(fang_tasks::updated_at.lt(now - older_than));
+
+            let mut task_types = diesel::update(query)
+                .set(fang_tasks::updated_at.eq(now))
+                .returning(fang_tasks::task_type)
+                .get_results::<String>(conn)?;
+
+            let bumped = task_types.len();
+
+            if notify {
+                task_types.sort();
+                task_types.dedup();
+
+                for task_type in task_types {
+                    diesel::sql_query("SELECT pg_notify($1, $2)")
+                        .bind::<Text, _>(TaskListener::CHANNEL)
+                        .bind::<Text, _>(task_type)
+                        .execute(conn)?;
+                }
+            }
+
+            Ok(bumped)
+        })
+    }
+
+    pub fn oldest_pending_age_query(
+        connection: &mut PgConnection,
+    ) -> Result<Option<Duration>, QueueError> {
+        Self::oldest_pending_age_at_query(connection, None)
+    }
+
+    /// The age of the oldest runnable task at `now`, see [`Queue::time_of`].
+    pub fn oldest_pending_age_at_query(
+        connection: &mut PgConnection,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<Duration>, QueueError> {
+        let (oldest_scheduled_at, now) = fang_tasks::table
+            .filter(Self::runnable(now))
+            .select((
+                diesel::dsl::min(fang_tasks::scheduled_at),
+                Self::time_of(now),
+            ))
+            .get_result::<(Option<DateTime<Utc>>, DateTime<Utc>)>(connection)?;
+
+        Ok(oldest_scheduled_at.map(|scheduled_at| now - scheduled_at))
+    }
+
+    pub fn next_scheduled_at_query(
+        connection: &mut PgConnection,
+    ) -> Result<Option<DateTime<Utc>>, QueueError> {
+        Self::next_scheduled_at_after_query(connection, None)
+    }
+
+    /// The earliest `scheduled_at` after `now` of the pending tasks, see [`Queue::time_of`].
+    pub fn next_scheduled_at_after_query(
+        connection: &mut PgConnection,
+        now: Option<DateTime<Utc>>,
+    ) -> Result<Option<DateTime<Utc>>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(Self::pending())
+            .filter(fang_tasks::scheduled_at.gt(Self::time_of(now)))
+            .filter(Self::not_expired("fang_tasks", now))
+            .filter(Self::task_type_not_paused("fang_tasks"))
+            .select(diesel::dsl::min(fang_tasks::scheduled_at))
+            .get_result(connection)?)
+    }
+
+    pub fn oldest_per_type_query(connection: &mut PgConnection) -> Result<Vec<Task>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(Self::pending())
+            .distinct_on(fang_tasks::task_type)
+            .order((
+                fang_tasks::task_type.asc(),
+                fang_tasks::created_at.asc(),
+                fang_tasks::scheduled_at.asc(),
+            ))
+            .load::<Task>(connection)?)
+    }
+
+    pub fn failed_error_counts_query(
+        connection: &mut PgConnection,
+    ) -> Result<Vec<(String, i64)>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::Failed))
+            .filter(fang_tasks::error_message.is_not_null())
+            .filter(fang_tasks::deleted_at.is_null())
+            .group_by(fang_tasks::error_message)
+            .select((fang_tasks::error_message.assume_not_null(), count_star()))
+            .order((count_star().desc(), fang_tasks::error_message.asc()))
+            .load::<(String, i64)>(connection)?)
+    }
+
+    pub fn task_counts_query(
+        connection: &mut PgConnection,
+    ) -> Result<Vec<(String, FangTaskState, i64)>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::deleted_at.is_null())
+            .group_by((fang_tasks::task_type, fang_tasks::state))
+            .select((fang_tasks::task_type, fang_tasks::state, count_star()))
+            .order((fang_tasks::task_type.asc(), fang_tasks::state.asc()))
+            .load::<(String, FangTaskState, i64)>(connection)?)
+    }
+
+    pub fn tasks_updated_since_query(
+        connection: &mut PgConnection,
+        since: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::updated_at.ge(since))
+            .filter(fang_tasks::deleted_at.is_null())
+            .order((fang_tasks::updated_at.asc(), fang_tasks::id.asc()))
+            .limit(limit)
+            .load::<Task>(connection)?)
+    }
+
+    pub fn longest_running_query(
+        connection: &mut PgConnection,
+        limit: i64,
+    ) -> Result<Vec<Task>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::InProgress))
+            .filter(fang_tasks::deleted_at.is_null())
+            .order(fang_tasks::updated_at.asc())
+            .limit(limit)
+            .load::<Task>(connection)?)
+    }
+
+    pub fn distinct_task_types_query(
+        connection: &mut PgConnection,
+    ) -> Result<Vec<String>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::deleted_at.is_null())
+            .select(fang_tasks::task_type)
+            .distinct()
+            .order(fang_tasks::task_type.asc())
+            .load::<String>(connection)?)
+    }
+
+    pub fn fetch_tasks_by_correlation_query(
+        connection: &mut PgConnection,
+        correlation_id: Uuid,
+    ) -> Result<Vec<Task>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(
+                fang_tasks::correlation_id
+                    .eq(correlation_id)
+                    .or(fang_tasks::id.eq(correlation_id)),
+            )
+            .filter(fang_tasks::deleted_at.is_null())
+            .order(fang_tasks::created_at.asc())
+            .load::<Task>(connection)?)
+    }
+
+    pub fn processed_counts_since_query(
+        connection: &mut PgConnection,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, i64)>, QueueError> {
+        Ok(fang_tasks::table
+            .filter(fang_tasks::state.eq(FangTaskState::Finished))
+            .filter(fang_tasks::updated_at.ge(since))
+            .filter(fang_tasks::deleted_at.is_null())
+            .group_by(fang_tasks::task_type)
+            .select((fang_tasks::task_type, count_star()))
+            .order(fang_tasks::task_type.asc())
+            .load::<(String, i64)>(connection)?)
+    }
+
+    fn current_time() -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    #[cfg(test)]
+    pub fn connection_pool(pool_size: u32) -> r2d2::Pool<r2d2::ConnectionManager<PgConnection>> {
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let manager = r2d2::ConnectionManager::<PgConnection>::new(database_url);
+
+        r2d2::Pool::builder()
+            .max_size(pool_size)
+            .build(manager)
+            .unwrap()
+    }
+
+    /// The filter of the tasks that are waiting to be executed,
+    /// tasks in the `FangTaskState::New` or `FangTaskState::Retried` states which are not soft-deleted.
+    fn pending() -> PendingFilter {
+        fang_tasks::state
+            .eq_any(vec![FangTaskState::New, FangTaskState::Retried])
+            .and(fang_tasks::deleted_at.is_null())
+    }
+
+    /// The filter of the tasks that can be executed at `now`, see [`Queue::time_of`],
+    /// pending tasks that are not scheduled after `now`.
+    fn runnable(now: Option<DateTime<Utc>>) -> RunnableFilter {
+        Self::pending()
+            .and(fang_tasks::scheduled_at.le(Self::time_of(now)))
+            .and(Self::dependency_finished("fang_tasks"))
+            .and(Self::not_expired("fang_tasks", now))
+    }
+
+    /// Tasks without an expiration time or whose expiration time is after `now`, see [`Queue::time_of`].
+    /// `table` is the name or the alias of the `fang_tasks` table in the query.
+    fn not_expired(table: &str, now: Option<DateTime<Utc>>) -> WithTime<Bool> {
+        sql::<Bool>(&format!(
+            "({table}.expires_at IS NULL OR {table}.expires_at > COALESCE("
+        ))
+        .bind::<Nullable<Timestamptz>, _>(now)
+        .sql(", statement_timestamp()))")
+    }
+
+    /// The time the tasks are compared with: `now`, the time of the clock of the queue,
+    /// or the current time of the database if it's `None`.
+    ///
+    /// `statement_timestamp()` is used instead of `now()` because `now()` returns the start time of
+    /// the current transaction.
+    fn time_of(now: Option<DateTime<Utc>>) -> WithTime<Timestamptz> {
+        sql::<Timestamptz>("COALESCE(")
+            .bind::<Nullable<Timestamptz>, _>(now)
+            .sql(", statement_timestamp())")
+    }
+
+    /// Tasks whose type is not paused, see `Queueable::pause_task_type`,
+    /// `table` is the name or the alias of the `fang_tasks` table in the query.
+    fn task_type_not_paused(table: &str) -> SqlLiteral<Bool> {
+        sql::<Bool>(&format!(
+            "NOT EXISTS (SELECT 1 FROM fang_paused_task_types AS paused \
+             WHERE paused.task_type = {table}.task_type)"
+        ))
+    }
+
+    /// Tasks without a dependency or whose dependency is finished,
+    /// `table` is the name or the alias of the `fang_tasks` table in the query.
+    fn dependency_finished(table: &str) -> SqlLiteral<Bool> {
+        sql::<Bool>(&format!(
+            "({table}.depends_on IS NULL OR EXISTS (SELECT 1 FROM fang_tasks AS dependency \
+             WHERE dependency.id = {table}.depends_on AND dependency.state = 'finished'))"
+        ))
+    }
+
+    pub fn pending_task_exists_query(
+        connection: &mut PgConnection,
+        uniq_hash: &str,
+    ) -> Result<bool, QueueError> {
+        Ok(diesel::select(diesel::dsl::exists(
+            fang_tasks::table
+                .filter(fang_tasks::uniq_hash.eq(uniq_hash))
+                .filter(Self::pending()),
+        ))
+        .get_result::<bool>(connection)?)
+    }
+
+    fn find_task_by_uniq_hash_query(
+        connection: &mut PgConnection,
+        uniq_hash: &str,
+    ) -> Option<Task> {
+        fang_tasks::table
+            .filter(fang_tasks::uniq_hash.eq(uniq_hash))
+            .filter(Self::pending())
+            .first::<Task>(connection)
+            .ok()
+    }
+
+    pub fn schedule_retry_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        backoff_seconds: u32,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        Self::schedule_retry_after_query(
+            connection,
+            task,
+            Duration::seconds(backoff_seconds as i64),
+            error,
+        )
+    }
+
+    pub fn schedule_retry_after_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        delay: Duration,
+        error: &str,
+    ) -> Result<Task, QueueError> {
+        Self::schedule_retry_after_at_query(connection, task, delay, error, Self::current_time())
+    }
+
+    /// Schedule a retry of the task at `now + delay`.
+    pub fn schedule_retry_after_at_query(
+        connection: &mut PgConnection,
+        task: &Task,
+        delay: Duration,
+        error: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Task, QueueError> {
+        let scheduled_at = now + delay;
+
+        let task = diesel::update(task)
+            .set((
+                fang_tasks::state.eq(FangTaskState::Retried),
+                fang_tasks::error_message.eq(error),
+                fang_tasks::retries.eq(task.retries + 1),
+                fang_tasks::scheduled_at.eq(scheduled_at),
+                fang_tasks::updated_at.eq(now),
+            ))
+            .get_result::<Task>(connection)?;
+
+        Ok(task)
+    }
+}
+
+#[cfg(test)]
+queue_tests::test_queue! {postgres, crate::queue::Queue, crate::queue::Queue::test()}
+
+#[cfg(test)]
+mod connection_setup_tests {
+    use super::Queue;
+    use diesel::dsl::sql;
+    use diesel::prelude::*;
+    use diesel::sql_types::Text;
+    use dotenvy::dotenv;
+    use std::env;
+
+    #[test]
+    fn new_or_default_connects_to_the_passed_url() {
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let queue =
+            Queue::new_or_default(Some(database_url), "postgres://localhost/unknown").unwrap();
+
+        assert!(queue.get_connection().is_ok());
+    }
+
+    #[test]
+    fn database_url_falls_back_to_the_default() {
+        let fallback = "postgres://localhost/fallback";
+
+        assert_eq!(fallback, Queue::resolve_database_url(None, None, fallback));
+        assert_eq!(
+            "postgres://localhost/env",
+            Queue::resolve_database_url(
+                None,
+                Some("postgres://localhost/env".to_string()),
+                fallback
+            )
+        );
+        assert_eq!(
+            "postgres://localhost/passed",
+            Queue::resolve_database_url(
+                Some("postgres://localhost/passed".to_string()),
+                Some("postgres://localhost/env".to_string()),
+                fallback
+            )
+        );
+    }
+
+    #[test]
+    fn new_with_setup_runs_setup_on_new_connections() {
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let queue = Queue::new_with_setup(database_url, |connection| {
+            diesel::sql_query("SET application_name TO 'fang_setup_test'").execute(connection)?;
+
+            Ok(())
+        })
+        .unwrap();
+
+        let mut connection = queue.get_connection().unwrap();
+
+        let application_name = diesel::select(sql::<Text>("current_setting('application_name')"))
+            .get_result::<String>(&mut connection)
+            .unwrap();
+
+        assert_eq!("fang_setup_test", application_name);
+    }
+
+    #[test]
+    fn missing_tables_return_schema_not_initialized() {
+        use crate::queue::queue_tests::AyratTask;
+        use crate::queue::QueueError;
+        use crate::queue::Queueable;
+
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let queue = Queue::new_with_setup(database_url, |connection| {
+            diesel::sql_query("SET search_path TO fang_not_migrated").execute(connection)?;
+
+            Ok(())
+        })
+        .unwrap();
+
+        let result = queue.insert_task(&AyratTask { number: 1 });
+        assert!(matches!(result, Err(QueueError::SchemaNotInitialized)));
+
+        let result = queue.fetch_and_touch_task("weirdo".to_string());
+        assert!(matches!(result, Err(QueueError::SchemaNotInitialized)));
+    }
+
+    #[test]
+    fn other_missing_objects_are_not_schema_not_initialized() {
+        use crate::queue::QueueError;
+
+        let queue = Queue::test();
+        let mut connection = queue.get_connection().unwrap();
+
+        let result: Result<_, QueueError> = connection.transaction(|conn| {
+            Ok(diesel::sql_query("SELECT fang_missing_function()").execute(conn)?)
+        });
+        assert!(matches!(result, Err(QueueError::DieselError(_))));
+
+        let result: Result<_, QueueError> = connection.transaction(|conn| {
+            Ok(diesel::sql_query("SELECT fang_missing_column FROM fang_tasks").execute(conn)?)
+        });
+        assert!(matches!(result, Err(QueueError::DieselError(_))));
+
+        let result: Result<_, QueueError> = connection.transaction(|conn| {
+            Ok(diesel::sql_query("SELECT 1 FROM fang_tasks_missing").execute(conn)?)
+        });
+        assert!(matches!(result, Err(QueueError::DieselError(_))));
+    }
+
+    #[cfg(feature = "migrations_postgres")]
+    #[test]
+    #[ignore]
+    fn with_schema_targets_tables_of_the_schema() {
+        use crate::queue::queue_tests::AyratTask;
+        use crate::queue::Queueable;
+        use diesel::sql_types::BigInt;
+
+        dotenv().ok();
+
+        let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+
+        let queue = Queue::with_schema(database_url, "fang_schema_test").unwrap();
+
+        let mut connection = queue.get_connection().unwrap();
+        diesel::sql_query("CREATE SCHEMA IF NOT EXISTS fang_schema_test")
+            .execute(&mut connection)
+            .unwrap();
+        crate::run_migrations_postgres(&mut connection).unwrap();
+        drop(connection);
+
+        let task = queue.insert_task(&AyratTask { number: 1 }).unwrap();
+
+        let mut connection = queue.get_connection().unwrap();
+
+        let count_in_schema = |connection: &mut PgConnection, table: &str| {
+            diesel::select(sql::<BigInt>(&format!(
+                "(SELECT count(*) FROM {table} WHERE id = '{}')",
+                task.id
+            )))
+            .get_result::<i64>(connection)
+            .unwrap()
+        };
+
+        assert_eq!(
+            1,
+            count_in_schema(&mut connection, "fang_schema_test.fang_tasks")
+        );
+        assert_eq!(0, count_in_schema(&mut connection, "public.fang_tasks"));
+        drop(connection);
+
+        let fetched_task = queue
+            .fetch_and_touch_task("weirdo".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.id, fetched_task.id);
+
+        let mut connection = queue.get_connection().unwrap();
+        diesel::sql_query("DROP SCHEMA fang_schema_test CASCADE")
+            .execute(&mut connection)
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod pool_state_tests {
+    use super::PoolState;
+    use super::Queue;
+
+    #[test]
+    fn pool_state_counts_idle_connections() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(3))
+            .build();
+
+        assert_eq!(
+            PoolState {
+                connections: 3,
+                idle_connections: 3
+            },
+            queue.pool_state()
+        );
+
+        let connection1 = queue.get_connection().unwrap();
+        let connection2 = queue.get_connection().unwrap();
+
+        assert_eq!(1, queue.pool_state().idle_connections);
+
+        drop(connection1);
+        drop(connection2);
+
+        assert_eq!(3, queue.pool_state().idle_connections);
+    }
+
+    #[test]
+    fn warmup_pool_opens_all_connections() {
+        dotenvy::dotenv().ok();
+
+        let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+        let manager = diesel::r2d2::ConnectionManager::new(database_url);
+
+        let pool = diesel::r2d2::Pool::builder()
+            .max_size(3)
+            .min_idle(Some(0))
+            .build(manager)
+            .unwrap();
+
+        let queue = Queue::builder().connection_pool(pool).build();
+
+        assert_eq!(0, queue.pool_state().connections);
+
+        let expected = PoolState {
+            connections: 3,
+            idle_connections: 3,
+        };
+
+        assert_eq!(expected, queue.warmup_pool().unwrap());
+        assert_eq!(expected, queue.pool_state());
+    }
+}
+
+#[cfg(test)]
+mod vacuum_tests {
+    use super::Queue;
+    use super::Queueable;
+
+    #[test]
+    #[ignore]
+    fn vacuum_tasks_vacuums_a_populated_table() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(1))
+            .build();
+
+        for number in 0..10 {
+            queue
+                .insert_full(
+                    serde_json::json!({ "number": number }),
+                    Some("vacuum".to_string()),
+                    None,
+                    None,
+                )
+                .unwrap();
+        }
+        queue.remove_tasks_of_type("vacuum").unwrap();
+
+        queue
+            .insert_full(
+                serde_json::json!({ "number": 10 }),
+                Some("vacuum".to_string()),
+                None,
+                None,
+            )
+            .unwrap();
+
+        queue.vacuum_tasks().unwrap();
+
+        queue.remove_tasks_of_type("vacuum").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod lock_mode_tests {
+    use super::LockMode;
+    use super::Queue;
+    use super::QueueError;
+    use super::Queueable;
+    use crate::queue::queue_tests::AyratTask;
+    use diesel::prelude::*;
+
+    #[test]
+    #[ignore]
+    fn for_share_fetch_does_not_block_another_for_share_fetch() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+
+        let task = queue.insert_task(&AyratTask { number: 100 }).unwrap();
+
+        let mut connection1 = queue.get_connection().unwrap();
+        let mut connection2 = queue.get_connection().unwrap();
+
+        connection1
+            .transaction::<_, QueueError, _>(|conn1| {
+                let fetched_task1 =
+                    Queue::fetch_task_with_lock_query(conn1, "weirdo", &LockMode::ForShare)?;
+
+                connection2.transaction::<_, QueueError, _>(|conn2| {
+                    diesel::sql_query("SET LOCAL lock_timeout = '1s'").execute(conn2)?;
+
+                    let fetched_task2 =
+                        Queue::fetch_task_with_lock_query(conn2, "weirdo", &LockMode::ForShare)?;
+
+                    assert_eq!(Some(task.id), fetched_task1.map(|task| task.id));
+                    assert_eq!(Some(task.id), fetched_task2.map(|task| task.id));
+
+                    Ok(())
+                })
+            })
+            .unwrap();
+
+        queue.remove_tasks_of_type("weirdo").unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn no_wait_fetch_errors_on_locked_task() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+
+        let task = queue.insert_task(&AyratTask { number: 101 }).unwrap();
+
+        let mut connection1 = queue.get_connection().unwrap();
+        let mut connection2 = queue.get_connection().unwrap();
+
+        connection1
+            .transaction::<_, QueueError, _>(|conn1| {
+                let fetched_task1 =
+                    Queue::fetch_task_with_lock_query(conn1, "weirdo", &LockMode::ForUpdate)?;
+                assert_eq!(Some(task.id), fetched_task1.map(|task| task.id));
+
+                let result = connection2.transaction::<_, QueueError, _>(|conn2| {
+                    Queue::fetch_task_with_lock_query(conn2, "weirdo", &LockMode::NoWait)
+                });
+
+                assert!(matches!(result, Err(QueueError::DieselError(_))));
+
+                Ok(())
+            })
+            .unwrap();
+
+        queue.remove_tasks_of_type("weirdo").unwrap();
+    }
+}
+
+#[cfg(test)]
+mod coalesce_tests {
+    use super::NewTask;
+    use super::Queue;
+    use super::QueueError;
+    use super::Queueable;
+    use crate::queue::queue_tests::AyratTask;
+    use diesel::prelude::*;
+
+    #[test]
+    #[ignore]
+    fn concurrent_coalesced_inserts_wait_for_each_other() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+
+        let mut new_task = NewTask::from_runnable(&AyratTask { number: 102 }).unwrap();
+        new_task.uniq_hash = Some(Queue::calculate_hash(b"coalesce:concurrent"));
+
+        let mut connection1 = queue.get_connection().unwrap();
+        let mut connection2 = queue.get_connection().unwrap();
+
+        connection1
+            .transaction::<_, QueueError, _>(|conn1| {
+                Queue::insert_coalesce_query(conn1, &new_task)?;
+
+                // the first insert isn't committed yet, the second one can't see its task and has to wait
+                let result = connection2.transaction::<_, QueueError, _>(|conn2| {
+                    diesel::sql_query("SET LOCAL lock_timeout = '1s'").execute(conn2)?;
+
+                    Queue::insert_coalesce_query(conn2, &new_task)
+                });
+
+                assert!(matches!(result, Err(QueueError::DieselError(_))));
+
+                Ok(())
+            })
+            .unwrap();
+
+        drop(connection1);
+        drop(connection2);
+
+        assert_eq!(1, queue.remove_tasks_of_type("weirdo").unwrap());
+    }
+}
+
+#[cfg(test)]
+mod null_metadata_tests {
+    use super::Queue;
+    use super::QueueError;
+    use super::Queueable;
+    use crate::runnable::COMMON_TYPE;
+
+    #[test]
+    fn rejects_null_metadata() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .reject_null_metadata(true)
+            .build();
+
+        let result = queue.insert_full(serde_json::Value::Null, None, None, None);
+        assert!(matches!(
+            result,
+            Err(QueueError::NullMetadataError(task_type)) if task_type == COMMON_TYPE
+        ));
+
+        let task = queue
+            .insert_full(serde_json::json!({"number": 10}), None, None, None)
+            .unwrap();
+        assert_eq!(serde_json::json!({"number": 10}), task.metadata);
+    }
+
+    #[test]
+    fn accepts_null_metadata_by_default() {
+        let queue = Queue::test();
+
+        let task = queue
+            .insert_full(serde_json::Value::Null, None, None, None)
+            .unwrap();
+        assert!(task.metadata.is_null());
+    }
+}
+
+#[cfg(test)]
+mod id_generator_tests {
+    use super::fang_tasks;
+    use super::NewTask;
+    use super::Queue;
+    use super::Queueable;
+    use crate::queue::queue_tests::AyratTask;
+    use crate::queue::queue_tests::PepeTask;
+    use diesel::prelude::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[test]
+    fn inserts_tasks_with_generated_ids() {
+        // time-sortable ids: a counter in the most significant bits, a random tail
+        let counter = Arc::new(AtomicU64::new(1));
+        let id_generator = move || {
+            let tail = Uuid::new_v4().as_u128() & u128::from(u64::MAX);
+            Uuid::from_u128((u128::from(counter.fetch_add(1, Ordering::SeqCst)) << 64) | tail)
+        };
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .id_generator(Arc::new(id_generator))
+            .build();
+
+        let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+        let task2 = queue
+            .insert_new_task(&NewTask::from_runnable(&AyratTask { number: 11 }).unwrap())
+            .unwrap();
+        let task3 = queue
+            .insert_full(serde_json::json!({"number": 12}), None, None, None)
+            .unwrap();
+
+        let ids: Vec<Uuid> = fang_tasks::table
+            .select(fang_tasks::id)
+            .order(fang_tasks::id.asc())
+            .load(&mut queue.get_connection().unwrap())
+            .unwrap();
+
+        assert_eq!(vec![task1.id, task2.id, task3.id], ids);
+        assert_eq!(2, task2.id.as_u128() >> 64);
+
+        let id = Uuid::new_v4();
+        let new_task = NewTask::from_runnable(&AyratTask { number: 13 })
+            .unwrap()
+            .with_id(id);
+        let task = queue.insert_new_task(&new_task).unwrap();
+
+        assert_eq!(id, task.id);
+    }
+}
+
+#[cfg(test)]
+mod task_type_lock_tests {
+    use super::Queue;
+    use super::Queueable;
+
+    #[test]
+    fn only_one_holder_of_the_lock_of_a_type_exists() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(3))
+            .build();
+
+        let lock = queue.try_lock_task_type("serial_lock").unwrap();
+        assert!(lock.is_some());
+
+        assert!(queue.try_lock_task_type("serial_lock").unwrap().is_none());
+        assert!(queue.try_lock_task_type("other_lock").unwrap().is_some());
+
+        drop(lock);
+
+        assert!(queue.try_lock_task_type("serial_lock").unwrap().is_some());
+    }
+}
+
+#[cfg(test)]
+mod clock_tests {
+    use super::IsolationLevel;
+    use super::Queue;
+    use super::QueueError;
+    use super::Queueable;
+    use crate::clock::Clock;
+    use crate::clock::MockClock;
+    use crate::queue::queue_tests::AyratTask;
+    use crate::queue::queue_tests::PepeTask;
+    use crate::runnable::Runnable;
+    use crate::FangError;
+    use crate::FangTaskState;
+    use crate::Scheduled;
+    use chrono::Duration;
+    use chrono::TimeZone;
+    use chrono::Utc;
+    use serde::{Deserialize, Serialize};
+    use std::sync::Arc;
+
+    #[derive(Serialize, Deserialize)]
+    struct HourlyTask {}
+
+    #[typetag::serde]
+    impl Runnable for HourlyTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn cron(&self) -> Option<Scheduled> {
+            Some(Scheduled::CronPattern("0 0 * * * *".to_string()))
+        }
+    }
+
+    // far from the system time, so only the clock explains the results
+    fn queue_with_clock() -> (Queue, MockClock) {
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2040, 1, 1, 0, 30, 0).unwrap());
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .clock(Arc::new(clock.clone()))
+            .build();
+
+        (queue, clock)
+    }
+
+    #[test]
+    fn fetch_and_touch_task_fetches_at_the_time_of_the_clock() {
+        let (queue, clock) = queue_with_clock();
+
+        let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+        assert_eq!(clock.now(), task.created_at);
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.set(task.scheduled_at - Duration::minutes(1));
+        assert_eq!(
+            None,
+            queue.fetch_and_touch_task("common".to_string()).unwrap()
+        );
+
+        clock.advance(Duration::minutes(2));
+
+        let fetched = queue
+            .fetch_and_touch_task("common".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.id, fetched.id);
+        assert_eq!(clock.now(), fetched.updated_at);
+    }
+
+    #[test]
+    #[ignore]
+    fn fetch_and_touch_task_keeps_the_isolation_level_with_a_clock() {
+        let clock = MockClock::new(Utc.with_ymd_and_hms(2040, 1, 1, 0, 30, 0).unwrap());
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(2))
+            .isolation_level(IsolationLevel::Serializable)
+            .clock(Arc::new(clock.clone()))
+            .build();
+
+        let task = queue
+            .insert_full(
+                serde_json::json!({}),
+                Some("clock_task".to_string()),
+                None,
+                None,
+            )
+            .unwrap();
+
+        let fetched = queue
+            .fetch_and_touch_task("clock_task".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.id, fetched.id);
+        assert_eq!(clock.now(), fetched.updated_at);
+
+        queue.remove_tasks_of_type("clock_task").unwrap();
+    }
+
+    #[test]
+    fn runnable_tasks_are_selected_at_the_time_of_the_clock() {
+        let (queue, clock) = queue_with_clock();
+
+        let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+        clock.set(task.scheduled_at - Duration::minutes(1));
+
+        assert_eq!(0, queue.count_runnable_tasks("common".to_string()).unwrap());
+        assert_eq!(None, queue.peek_next_task("common".to_string()).unwrap());
+        assert_eq!(None, queue.fetch_task_of_types(&["common"]).unwrap());
+        assert_eq!(
+            None,
+            queue
+                .fetch_and_touch_random_task("common".to_string())
+                .unwrap()
+        );
+        assert_eq!(
+            None,
+            queue
+                .fetch_and_touch_lowest_priority_task("common".to_string())
+                .unwrap()
+        );
+        assert!(queue
+            .fetch_and_touch_tasks("common".to_string(), 10)
+            .unwrap()
+            .is_empty());
+
+        clock.advance(Duration::minutes(2));
+
+        assert_eq!(1, queue.count_runnable_tasks("common".to_string()).unwrap());
+
+        let peeked = queue.peek_next_task("common".to_string()).unwrap().unwrap();
+        assert_eq!(task.id, peeked.id);
+
+        let fetches: Vec<Box<dyn Fn() -> Option<super::Task>>> = vec![
+            Box::new(|| queue.fetch_task_of_types(&["common"]).unwrap()),
+            Box::new(|| {
+                queue
+                    .fetch_and_touch_random_task("common".to_string())
+                    .unwrap()
+            }),
+            Box::new(|| {
+                queue
+                    .fetch_and_touch_lowest_priority_task("common".to_string())
+                    .unwrap()
+            }),
+            Box::new(|| {
+                queue
+                    .fetch_and_touch_tasks("common".to_string(), 10)
+                    .unwrap()
+                    .pop()
+            }),
+        ];
+
+        for fetch in fetches {
+            let fetched = fetch().unwrap();
+            assert_eq!(task.id, fetched.id);
+            assert_eq!(clock.now(), fetched.updated_at);
+
+            queue
+                .update_task_state(&fetched, FangTaskState::New)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn updates_are_stamped_with_the_time_of_the_clock() {
+        let (queue, clock) = queue_with_clock();
+
+        let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+        clock.advance(Duration::minutes(1));
+        let task = queue.bump_task(task.id).unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        let task = queue
+            .reschedule_task(task.id, clock.now() + Duration::hours(1))
+            .unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        let task = queue.cancel_task(task.id).unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        let task = queue
+            .update_task_state(&task, FangTaskState::InProgress)
+            .unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        let task = queue
+            .finish_task_with_result(&task, serde_json::json!(42))
+            .unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        let task = queue.fail_task(&task, "boom").unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        queue.finish_tasks(&[task.id]).unwrap();
+        let task = queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(clock.now(), task.updated_at);
+
+        clock.advance(Duration::minutes(1));
+        queue
+            .record_interrupted(std::slice::from_ref(&task))
+            .unwrap();
+        assert_eq!(
+            vec![clock.now()],
+            queue
+                .interrupted_tasks()
+                .unwrap()
+                .into_iter()
+                .filter(|interrupted| interrupted.task_id == task.id)
+                .map(|interrupted| interrupted.interrupted_at)
+                .collect::<Vec<_>>()
+        );
+
+        clock.advance(Duration::minutes(1));
+        queue.soft_delete_task(task.id).unwrap();
+        let task = queue.find_task_by_id_with_deleted(task.id, true).unwrap();
+        assert_eq!(Some(clock.now()), task.deleted_at);
+        assert_eq!(clock.now(), task.updated_at);
+    }
+
+    #[test]
+    fn time_windows_use_the_time_of_the_clock() {
+        let (queue, clock) = queue_with_clock();
+
+        let task = queue
+            .insert_throttled(&AyratTask { number: 10 }, Duration::hours(1))
+            .unwrap();
+        assert_eq!(clock.now(), task.created_at);
+
+        clock.advance(Duration::minutes(30));
+        assert_eq!(
+            Some(Duration::minutes(30)),
+            queue.oldest_pending_age().unwrap()
+        );
+        assert_eq!(0, queue.bump_stale_new(Duration::hours(1), false).unwrap());
+        assert_eq!(
+            task.id,
+            queue
+                .insert_throttled(&AyratTask { number: 10 }, Duration::hours(1))
+                .unwrap()
+                .id
+        );
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(1, queue.bump_stale_new(Duration::hours(1), false).unwrap());
+        assert_eq!(
+            clock.now(),
+            queue.find_task_by_id(task.id).unwrap().updated_at
+        );
+
+        let throttled_task = queue
+            .insert_throttled(&AyratTask { number: 10 }, Duration::hours(1))
+            .unwrap();
+        assert_ne!(task.id, throttled_task.id);
+
+        clock.advance(Duration::minutes(30));
+        assert_eq!(
+            0,
+            queue
+                .bulk_transition(
+                    FangTaskState::New,
+                    FangTaskState::Cancelled,
+                    Some(Duration::hours(1))
+                )
+                .unwrap()
+        );
+
+        clock.advance(Duration::hours(1));
+        assert_eq!(
+            2,
+            queue
+                .bulk_transition(
+                    FangTaskState::New,
+                    FangTaskState::Cancelled,
+                    Some(Duration::hours(1))
+                )
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn scheduled_tasks_are_relative_to_the_time_of_the_clock() {
+        let (queue, _) = queue_with_clock();
+
+        let scheduled_task = queue.schedule_task(&HourlyTask {}).unwrap();
+        assert_eq!(
+            Utc.with_ymd_and_hms(2040, 1, 1, 1, 0, 0).unwrap(),
+            scheduled_task.scheduled_at
+        );
+
+        let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+        assert_eq!(
+            Some(scheduled_task.scheduled_at),
+            queue.next_scheduled_at().unwrap()
+        );
+
+        assert_eq!(1, queue.remove_all_scheduled_tasks().unwrap());
+        assert_eq!(None, queue.find_task_by_id(scheduled_task.id));
+        assert!(queue.find_task_by_id(task.id).is_some());
+    }
+
+    #[test]
+    fn leases_expire_by_the_time_of_the_clock() {
+        let (queue, clock) = queue_with_clock();
+
+        queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+        let task = queue
+            .fetch_with_lease("weirdo", Duration::minutes(5))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(clock.now() + Duration::minutes(5)), task.locked_until);
+
+        clock.advance(Duration::minutes(10));
+
+        assert!(matches!(
+            queue.release_lease(&task),
+            Err(QueueError::LeaseLostError(id)) if id == task.id
+        ));
+        assert_eq!(1, queue.requeue_expired_leases().unwrap());
+    }
+
+    #[test]
+    fn fetches_tasks_by_the_time_of_the_clock() {
+        // whole seconds, the database stores microseconds
+        let clock = MockClock::new(Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap());
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .clock(Arc::new(clock.clone()))
+            .build();
+
+        let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+        assert_eq!(clock.now(), task.scheduled_at);
+
+        let task = queue
+            .schedule_retry_after(&task, Duration::hours(1), "later")
+            .unwrap();
+        assert_eq!(clock.now() + Duration::hours(1), task.scheduled_at);
+
+        assert_eq!(
+            None,
+            queue.fetch_and_touch_task("common".to_string()).unwrap()
+        );
+
+        clock.advance(Duration::hours(2));
+
+        let fetched = queue
+            .fetch_and_touch_task("common".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.id, fetched.id);
+
+        let task = queue
+            .insert_with_ttl(&AyratTask { number: 11 }, Duration::minutes(30))
+            .unwrap();
+
+        clock.advance(Duration::hours(1));
+
+        assert_eq!(
+            None,
+            queue.fetch_and_touch_task("weirdo".to_string()).unwrap()
+        );
+        assert_eq!(1, queue.expire_tasks().unwrap());
+        assert_eq!(
+            FangTaskState::Expired,
+            queue.find_task_by_id(task.id).unwrap().state
+        );
+    }
+
+    #[test]
+    fn queues_without_a_clock_compare_scheduled_at_with_db_time() {
+        // a client whose clock is hours ahead of the database
+        let clock = MockClock::new(Utc::now() + Duration::hours(3));
+        let pool = Queue::test().connection_pool;
+        let skewed_queue = Queue::builder()
+            .connection_pool(pool.clone())
+            .clock(Arc::new(clock.clone()))
+            .build();
+        let queue = Queue::builder().connection_pool(pool).build();
+
+        // due by the skewed clock, due later by the time of the database
+        let task = skewed_queue.insert_task(&PepeTask { number: 10 }).unwrap();
+        assert!(task.scheduled_at > Utc::now() + Duration::hours(2));
+
+        assert_eq!(0, queue.count_runnable_tasks("common".to_string()).unwrap());
+        assert_eq!(None, queue.peek_next_task("common".to_string()).unwrap());
+        assert_eq!(
+            None,
+            queue.fetch_and_touch_task("common".to_string()).unwrap()
+        );
+        assert_eq!(Some(task.scheduled_at), queue.next_scheduled_at().unwrap());
+
+        let fetched = skewed_queue
+            .fetch_and_touch_task("common".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.id, fetched.id);
+    }
+}
@@ -85,10 +85,18 @@ macro_rules! test_queue {
             use chrono::Duration;
             use chrono::SubsecRound;
             use chrono::Utc;
+            use diesel::prelude::*;
             use $crate::blocking::queue::queue_tests::{AyratTask, PepeTask, ScheduledPepeTask};
+            use $crate::postgres_schema::fang_tasks;
+            use $crate::queue::NewTask;
+            use $crate::queue::Queue;
+            use $crate::queue::QueueError;
             use $crate::queue::Queueable;
+            use $crate::runnable::Runnable;
             use $crate::runnable::COMMON_TYPE;
             use $crate::FangTaskState;
+            use $crate::Task;
+            use uuid::Uuid;
 
             #[test]
             fn insert_task_test() {
@@ -108,6 +116,201 @@ macro_rules! test_queue {
                 assert_eq!(Some("PepeTask"), type_task);
             }
 
+            #[test]
+            fn inserted_task_was_never_updated() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                assert_eq!(task.created_at, task.updated_at);
+
+                let created_at = (Utc::now() - Duration::days(3)).round_subsecs(0);
+                let new_task = NewTask::builder()
+                    .metadata(serde_json::json!({"type": "AyratTask", "number": 10}))
+                    .task_type("weirdo")
+                    .uniq_hash(None)
+                    .scheduled_at(Utc::now())
+                    .binary_metadata(None)
+                    .created_at(created_at)
+                    .build();
+                let task = queue.insert_new_task(&new_task).unwrap();
+                assert_eq!(created_at, task.created_at);
+                assert_eq!(task.created_at, task.updated_at);
+
+                let new_task = NewTask::from_runnable(&AyratTask { number: 11 })
+                    .unwrap()
+                    .with_created_at(created_at);
+                let task = queue.insert_new_task(&new_task).unwrap();
+                assert_eq!(created_at, task.updated_at);
+
+                let task = queue
+                    .update_task_state(&task, FangTaskState::InProgress)
+                    .unwrap();
+                assert_ne!(task.created_at, task.updated_at);
+            }
+
+            #[test]
+            fn insert_new_task_from_runnable_sets_task_type() {
+                let task = AyratTask { number: 10 };
+
+                let queue: $q = $e;
+
+                let new_task = NewTask::from_runnable(&task).unwrap();
+                let inserted_task = queue.insert_new_task(&new_task).unwrap();
+
+                assert_eq!(task.task_type(), inserted_task.task_type);
+                assert_eq!(Some(10), inserted_task.metadata["number"].as_u64());
+
+                let found_task = queue
+                    .fetch_and_touch_task(task.task_type())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(inserted_task.id, found_task.id);
+            }
+
+            #[test]
+            fn insert_in_rolls_back_with_the_surrounding_transaction() {
+                let queue: $q = $e;
+
+                let new_task = NewTask::from_runnable(&PepeTask { number: 10 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+
+                let result = connection.transaction::<(), QueueError, _>(|conn| {
+                    let task = queue.insert_in(conn, &new_task)?;
+
+                    assert!(Queue::find_task_by_id_query(conn, task.id).is_some());
+
+                    Err(QueueError::DieselError(diesel::result::Error::RollbackTransaction))
+                });
+
+                assert!(result.is_err());
+
+                let committed = connection
+                    .transaction::<Task, QueueError, _>(|conn| queue.insert_in(conn, &new_task))
+                    .unwrap();
+
+                drop(connection);
+
+                assert_eq!(
+                    committed.id,
+                    queue
+                        .fetch_and_touch_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .unwrap()
+                        .id
+                );
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
+
+            #[test]
+            fn insert_returning_id_returns_the_id_of_the_task() {
+                let queue: $q = $e;
+
+                let new_task = NewTask::from_runnable(&PepeTask { number: 10 }).unwrap();
+
+                let id = queue.insert_returning_id(&new_task).unwrap();
+
+                let found_task = queue.find_task_by_id(id).unwrap();
+
+                assert_eq!(id, found_task.id);
+                assert_eq!(Some(10), found_task.metadata["number"].as_u64());
+                assert_eq!(id, queue.insert_returning_id(&new_task).unwrap());
+            }
+
+            #[test]
+            fn paused_queue_rejects_inserts() {
+                let queue: $q = $e;
+
+                queue.pause_inserts().unwrap();
+
+                assert!(queue.inserts_paused().unwrap());
+                assert!(matches!(
+                    queue.insert_task(&PepeTask { number: 10 }),
+                    Err(QueueError::QueuePausedError)
+                ));
+
+                queue.resume_inserts().unwrap();
+
+                assert!(!queue.inserts_paused().unwrap());
+                assert!(queue.insert_task(&PepeTask { number: 10 }).is_ok());
+            }
+
+            #[test]
+            fn task_position_counts_tasks_ahead_in_fetch_order() {
+                let queue: $q = $e;
+
+                let first_task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let middle_task = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                queue.insert_task(&PepeTask { number: 12 }).unwrap();
+                queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+                assert_eq!(Some(0), queue.task_position(first_task.id).unwrap());
+                assert_eq!(Some(1), queue.task_position(middle_task.id).unwrap());
+
+                let urgent_task = queue
+                    .insert_full(
+                        serde_json::json!({"type": "PepeTask", "number": 13}),
+                        None,
+                        Some(5),
+                        None,
+                    )
+                    .unwrap();
+
+                assert_eq!(Some(0), queue.task_position(urgent_task.id).unwrap());
+                assert_eq!(Some(2), queue.task_position(middle_task.id).unwrap());
+
+                queue.fail_task(&first_task, "failed").unwrap();
+
+                assert_eq!(None, queue.task_position(first_task.id).unwrap());
+                assert_eq!(Some(1), queue.task_position(middle_task.id).unwrap());
+                assert_eq!(None, queue.task_position(Uuid::new_v4()).unwrap());
+            }
+
+            #[test]
+            fn paused_task_type_is_not_fetched() {
+                let queue: $q = $e;
+
+                let weirdo_task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let common_task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                queue.pause_task_type("weirdo").unwrap();
+
+                assert!(queue.task_type_paused("weirdo").unwrap());
+                assert!(!queue.task_type_paused(COMMON_TYPE).unwrap());
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task("weirdo".to_string()).unwrap()
+                );
+                assert!(queue
+                    .fetch_and_touch_tasks("weirdo".to_string(), 10)
+                    .unwrap()
+                    .is_empty());
+                assert_eq!(
+                    common_task.id,
+                    queue
+                        .fetch_and_touch_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .unwrap()
+                        .id
+                );
+
+                queue.resume_task_type("weirdo").unwrap();
+
+                assert!(!queue.task_type_paused("weirdo").unwrap());
+                assert_eq!(
+                    weirdo_task.id,
+                    queue
+                        .fetch_and_touch_task("weirdo".to_string())
+                        .unwrap()
+                        .unwrap()
+                        .id
+                );
+            }
+
             #[test]
             fn fetch_task_fetches_the_oldest_task() {
                 let task1 = PepeTask { number: 10 };
@@ -126,6 +329,26 @@ macro_rules! test_queue {
                 assert_eq!(found_task.id, task1.id);
             }
 
+            #[test]
+            fn task_stream_fetches_tasks_until_none() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task3 = queue.insert_task(&PepeTask { number: 12 }).unwrap();
+
+                let ids: Vec<_> = queue
+                    .task_stream(COMMON_TYPE.to_string())
+                    .map(|task| task.unwrap().id)
+                    .collect();
+
+                assert_eq!(vec![task1.id, task2.id, task3.id], ids);
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
+
             #[test]
             fn update_task_state_test() {
                 let task = PepeTask { number: 10 };
@@ -204,34 +427,1750 @@ macro_rules! test_queue {
             }
 
             #[test]
-            fn insert_task_uniq_test() {
-                let task = PepeTask { number: 10 };
+            fn cancelled_task_is_not_fetched() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let cancelled_task = queue.cancel_task(task.id).unwrap();
+
+                assert_eq!(FangTaskState::Cancelled, cancelled_task.state);
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
 
+            #[test]
+            fn cancel_task_does_not_cancel_running_task() {
                 let queue: $q = $e;
 
-                let task1 = queue.insert_task(&task).unwrap();
-                let task2 = queue.insert_task(&task).unwrap();
-                assert_eq!(task2.id, task1.id);
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let task = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert!(matches!(
+                    queue.cancel_task(task.id),
+                    Err(QueueError::TaskNotPendingError(id)) if id == task.id
+                ));
+                assert_eq!(
+                    FangTaskState::InProgress,
+                    queue.find_task_by_id(task.id).unwrap().state
+                );
             }
 
             #[test]
-            fn schedule_task_test() {
+            fn bumped_task_is_fetched_first() {
                 let queue: $q = $e;
-                let datetime = (Utc::now() + Duration::seconds(7)).round_subsecs(0);
 
-                let task = &ScheduledPepeTask {
-                    number: 10,
-                    datetime: datetime.to_string(),
+                let _task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let other_type_task = queue
+                    .insert_full(serde_json::json!({"number": 12}), Some("weirdo".to_string()), Some(10), None)
+                    .unwrap();
+
+                let bumped_task = queue.bump_task(task2.id).unwrap();
+
+                assert_eq!(1, bumped_task.priority);
+                assert_eq!(task2.created_at, bumped_task.created_at);
+                assert_eq!(10, queue.find_task_by_id(other_type_task.id).unwrap().priority);
+
+                let found_task = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task2.id, found_task.id);
+            }
+
+            #[test]
+            fn oldest_pending_age_test() {
+                let queue: $q = $e;
+
+                assert_eq!(None, queue.oldest_pending_age().unwrap());
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let scheduled_task = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(scheduled_task.id)))
+                    .set((
+                        fang_tasks::created_at.eq(Utc::now() - Duration::hours(3)),
+                        fang_tasks::scheduled_at.eq(Utc::now() + Duration::hours(1)),
+                    ))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                // the task scheduled in the future is not waiting yet
+                assert!(queue.oldest_pending_age().unwrap().unwrap() < Duration::minutes(1));
+
+                // created long before it was due, it has been waiting only since it's due
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(task.id)))
+                    .set((
+                        fang_tasks::created_at.eq(Utc::now() - Duration::days(1)),
+                        fang_tasks::scheduled_at.eq(Utc::now() - Duration::hours(1)),
+                    ))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                let age = queue.oldest_pending_age().unwrap().unwrap();
+
+                assert!(age >= Duration::hours(1));
+                assert!(age < Duration::hours(1) + Duration::minutes(1));
+            }
+
+            #[test]
+            fn reassign_task_moves_task_to_another_type() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let reassigned_task = queue.reassign_task(task.id, "weirdo").unwrap();
+                assert_eq!("weirdo", reassigned_task.task_type);
+
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+
+                let found_task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task.id, found_task.id);
+
+                let result = queue.reassign_task(task.id, COMMON_TYPE);
+                assert!(matches!(result, Err(QueueError::TaskNotPendingError(id)) if id == task.id));
+
+                let in_progress_task = queue.find_task_by_id(task.id).unwrap();
+                assert_eq!("weirdo", in_progress_task.task_type);
+            }
+
+            #[test]
+            fn increment_retries_returns_the_new_number_of_retries() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                assert_eq!(1, queue.increment_retries(task.id).unwrap());
+                assert_eq!(2, queue.increment_retries(task.id).unwrap());
+
+                let found_task = queue.find_task_by_id(task.id).unwrap();
+                assert_eq!(2, found_task.retries);
+                assert_eq!(FangTaskState::New, found_task.state);
+
+                let result = queue.increment_retries(Uuid::new_v4());
+                assert!(matches!(
+                    result,
+                    Err(QueueError::DieselError(diesel::result::Error::NotFound))
+                ));
+            }
+
+            #[test]
+            fn bump_stale_new_refreshes_updated_at_of_old_new_tasks() {
+                let queue: $q = $e;
+
+                let stale_task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let fresh_task = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let failed_task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let failed_task = queue.fail_task(&failed_task, "failed").unwrap();
+
+                let an_hour_ago = Utc::now() - Duration::hours(1);
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(
+                    fang_tasks::table
+                        .filter(fang_tasks::id.eq_any(vec![stale_task.id, failed_task.id])),
+                )
+                .set(fang_tasks::updated_at.eq(an_hour_ago))
+                .execute(&mut connection)
+                .unwrap();
+                drop(connection);
+
+                assert_eq!(
+                    1,
+                    queue
+                        .bump_stale_new(Duration::minutes(30), true)
+                        .unwrap()
+                );
+
+                let stale_task = queue.find_task_by_id(stale_task.id).unwrap();
+                assert!(stale_task.updated_at > an_hour_ago + Duration::minutes(30));
+
+                let fresh_task_after = queue.find_task_by_id(fresh_task.id).unwrap();
+                assert_eq!(fresh_task.updated_at, fresh_task_after.updated_at);
+
+                let failed_task = queue.find_task_by_id(failed_task.id).unwrap();
+                assert!(failed_task.updated_at < an_hour_ago + Duration::seconds(1));
+            }
+
+            #[test]
+            fn bulk_transition_moves_old_tasks_in_the_from_state() {
+                let queue: $q = $e;
+
+                let old_task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let old_task = queue.fail_task(&old_task, "failed").unwrap();
+                let recent_task = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let recent_task = queue.fail_task(&recent_task, "failed").unwrap();
+                let new_task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(
+                    fang_tasks::table.filter(fang_tasks::id.eq_any(vec![old_task.id, new_task.id])),
+                )
+                .set(fang_tasks::updated_at.eq(Utc::now() - Duration::days(2)))
+                .execute(&mut connection)
+                .unwrap();
+                drop(connection);
+
+                assert_eq!(
+                    1,
+                    queue
+                        .bulk_transition(
+                            FangTaskState::Failed,
+                            FangTaskState::Cancelled,
+                            Some(Duration::days(1))
+                        )
+                        .unwrap()
+                );
+
+                let old_task = queue.find_task_by_id(old_task.id).unwrap();
+                assert_eq!(FangTaskState::Cancelled, old_task.state);
+
+                let recent_task = queue.find_task_by_id(recent_task.id).unwrap();
+                assert_eq!(FangTaskState::Failed, recent_task.state);
+
+                let new_task = queue.find_task_by_id(new_task.id).unwrap();
+                assert_eq!(FangTaskState::New, new_task.state);
+
+                assert_eq!(
+                    1,
+                    queue
+                        .bulk_transition(FangTaskState::Failed, FangTaskState::Cancelled, None)
+                        .unwrap()
+                );
+
+                let recent_task = queue.find_task_by_id(recent_task.id).unwrap();
+                assert_eq!(FangTaskState::Cancelled, recent_task.state);
+            }
+
+            #[test]
+            fn oldest_per_type_returns_one_task_per_type() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let _task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task3 = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let _task4 = queue.insert_task(&AyratTask { number: 11 }).unwrap();
+
+                let ids: Vec<_> = queue
+                    .oldest_per_type()
+                    .unwrap()
+                    .into_iter()
+                    .map(|task| task.id)
+                    .collect();
+
+                assert_eq!(vec![task1.id, task3.id], ids);
+            }
+
+            #[test]
+            fn tasks_updated_since_returns_changed_tasks_in_order() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let _task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task3 = queue.insert_task(&AyratTask { number: 12 }).unwrap();
+
+                let since = Utc::now();
+
+                let task3 = queue
+                    .update_task_state(&task3, FangTaskState::Finished)
+                    .unwrap();
+                let task1 = queue.fail_task(&task1, "failed").unwrap();
+
+                let ids = |tasks: Vec<Task>| tasks.into_iter().map(|task| task.id).collect::<Vec<_>>();
+
+                assert_eq!(
+                    vec![task3.id, task1.id],
+                    ids(queue.tasks_updated_since(since, 10).unwrap())
+                );
+                assert_eq!(
+                    vec![task3.id],
+                    ids(queue.tasks_updated_since(since, 1).unwrap())
+                );
+                assert_eq!(
+                    vec![task1.id],
+                    ids(queue.tasks_updated_since(task1.updated_at, 10).unwrap())
+                );
+            }
+
+            #[test]
+            fn longest_running_returns_oldest_in_progress_tasks_first() {
+                let queue: $q = $e;
+
+                let mut ids = Vec::new();
+
+                for (number, minutes) in [(10, 5), (11, 30), (12, 15)] {
+                    let task = queue.insert_task(&PepeTask { number }).unwrap();
+
+                    let mut connection = queue.get_connection().unwrap();
+                    diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(task.id)))
+                        .set((
+                            fang_tasks::state.eq(FangTaskState::InProgress),
+                            fang_tasks::updated_at.eq(Utc::now() - Duration::minutes(minutes)),
+                        ))
+                        .execute(&mut connection)
+                        .unwrap();
+
+                    ids.push(task.id);
+                }
+
+                queue.insert_task(&PepeTask { number: 13 }).unwrap();
+
+                let longest_running: Vec<_> = queue
+                    .longest_running(10)
+                    .unwrap()
+                    .into_iter()
+                    .map(|task| task.id)
+                    .collect();
+
+                assert_eq!(vec![ids[1], ids[2], ids[0]], longest_running);
+
+                let longest_running: Vec<_> = queue
+                    .longest_running(1)
+                    .unwrap()
+                    .into_iter()
+                    .map(|task| task.id)
+                    .collect();
+
+                assert_eq!(vec![ids[1]], longest_running);
+            }
+
+            #[test]
+            fn failed_error_counts_groups_failed_tasks_by_error() {
+                let queue: $q = $e;
+
+                for number in 1..=3 {
+                    let task = queue.insert_task(&PepeTask { number }).unwrap();
+                    queue.fail_task(&task, "timeout").unwrap();
+                }
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue.fail_task(&task, "connection refused").unwrap();
+
+                queue.insert_task(&AyratTask { number: 11 }).unwrap();
+
+                assert_eq!(
+                    vec![
+                        ("timeout".to_string(), 3),
+                        ("connection refused".to_string(), 1)
+                    ],
+                    queue.failed_error_counts().unwrap()
+                );
+            }
+
+            #[test]
+            fn processed_counts_since_counts_finished_tasks_by_type() {
+                let queue: $q = $e;
+
+                let an_hour_ago = Utc::now() - Duration::hours(1);
+
+                let old_task = queue.insert_task(&PepeTask { number: 1 }).unwrap();
+                queue
+                    .update_task_state(&old_task, FangTaskState::Finished)
+                    .unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(old_task.id)))
+                    .set(fang_tasks::updated_at.eq(an_hour_ago - Duration::minutes(1)))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                for number in 2..=3 {
+                    let task = queue.insert_task(&PepeTask { number }).unwrap();
+                    queue
+                        .update_task_state(&task, FangTaskState::Finished)
+                        .unwrap();
+                }
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue
+                    .update_task_state(&task, FangTaskState::Finished)
+                    .unwrap();
+
+                let task = queue.insert_task(&AyratTask { number: 11 }).unwrap();
+                queue.fail_task(&task, "failed").unwrap();
+
+                queue.insert_task(&AyratTask { number: 12 }).unwrap();
+
+                assert_eq!(
+                    vec![(COMMON_TYPE.to_string(), 2), ("weirdo".to_string(), 1)],
+                    queue.processed_counts_since(an_hour_ago).unwrap()
+                );
+            }
+
+            #[test]
+            fn fetch_and_finish_task_returns_a_finished_task() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let finished_task = queue
+                    .fetch_and_finish_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task.id, finished_task.id);
+                assert_eq!(FangTaskState::Finished, finished_task.state);
+                assert_eq!(
+                    FangTaskState::Finished,
+                    queue.find_task_by_id(task.id).unwrap().state
+                );
+                assert_eq!(
+                    None,
+                    queue.fetch_and_finish_task(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
+
+            #[test]
+            fn inserts_tasks_into_the_partition_of_their_type() {
+                let queue: $q = $e;
+
+                queue.create_task_type_partition("weirdo").unwrap();
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+
+                let count = |table: &str, connection: &mut _| {
+                    diesel::select(diesel::dsl::sql::<diesel::sql_types::BigInt>(&format!(
+                        "(SELECT count(*) FROM {})",
+                        table
+                    )))
+                    .get_result::<i64>(connection)
+                    .unwrap()
                 };
-                let task = queue.schedule_task(task).unwrap();
 
-                let metadata = task.metadata.as_object().unwrap();
-                let number = metadata["number"].as_u64();
-                let type_task = metadata["type"].as_str();
+                assert_eq!(1, count("fang_tasks_weirdo", &mut connection));
+                assert_eq!(1, count("fang_tasks_default", &mut connection));
 
-                assert_eq!(Some(10), number);
-                assert_eq!(Some("ScheduledPepeTask"), type_task);
-                assert_eq!(task.scheduled_at, datetime);
+                drop(connection);
+
+                let fetched_task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task.id, fetched_task.id);
+                assert_eq!(FangTaskState::InProgress, fetched_task.state);
+            }
+
+            #[test]
+            fn fetch_and_touch_task_in_order_waits_for_the_task_in_progress() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+
+                let fetched_task = queue
+                    .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task1.id, fetched_task.id);
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                        .unwrap()
+                );
+
+                queue
+                    .update_task_state(&fetched_task, FangTaskState::Finished)
+                    .unwrap();
+
+                let fetched_task = queue
+                    .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task2.id, fetched_task.id);
+            }
+
+            #[test]
+            fn fetch_and_touch_task_in_order_respects_dependencies_and_expiration() {
+                let queue: $q = $e;
+
+                let expired = queue
+                    .insert_with_ttl(&PepeTask { number: 10 }, Duration::hours(-1))
+                    .unwrap();
+                let dependency = queue.insert_task(&AyratTask { number: 11 }).unwrap();
+                let task1 = queue
+                    .insert_after(&PepeTask { number: 12 }, dependency.id)
+                    .unwrap();
+                let task2 = queue.insert_task(&PepeTask { number: 13 }).unwrap();
+
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                        .unwrap()
+                );
+
+                queue
+                    .update_task_state(&dependency, FangTaskState::Finished)
+                    .unwrap();
+
+                let fetched_task = queue
+                    .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task1.id, fetched_task.id);
+
+                queue.soft_delete_task(fetched_task.id).unwrap();
+
+                let fetched_task = queue
+                    .fetch_and_touch_task_in_order(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task2.id, fetched_task.id);
+                assert_eq!(
+                    FangTaskState::New,
+                    queue.find_task_by_id(expired.id).unwrap().state
+                );
+            }
+
+            #[test]
+            fn insert_new_task_honors_created_at() {
+                let queue: $q = $e;
+
+                let created_at = (Utc::now() - Duration::minutes(10)).round_subsecs(0);
+
+                let task1 = queue
+                    .insert_new_task(
+                        &NewTask::from_runnable(&AyratTask { number: 10 })
+                            .unwrap()
+                            .with_created_at(created_at),
+                    )
+                    .unwrap();
+                let task2 = queue
+                    .insert_new_task(
+                        &NewTask::from_runnable(&AyratTask { number: 11 })
+                            .unwrap()
+                            .with_created_at(created_at - Duration::minutes(10)),
+                    )
+                    .unwrap();
+
+                assert_eq!(created_at, task1.created_at);
+
+                let fetched_task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task2.id, fetched_task.id);
+
+                let fetched_task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task1.id, fetched_task.id);
+            }
+
+            #[test]
+            fn insert_throttled_skips_identical_tasks_within_the_window() {
+                let queue: $q = $e;
+
+                let task = queue
+                    .insert_throttled(&PepeTask { number: 10 }, Duration::minutes(1))
+                    .unwrap();
+                let task = queue.update_task_state(&task, FangTaskState::Finished).unwrap();
+
+                let throttled_task = queue
+                    .insert_throttled(&PepeTask { number: 10 }, Duration::minutes(1))
+                    .unwrap();
+                assert_eq!(task.id, throttled_task.id);
+
+                let other_task = queue
+                    .insert_throttled(&PepeTask { number: 11 }, Duration::minutes(1))
+                    .unwrap();
+                assert_ne!(task.id, other_task.id);
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(task.id)))
+                    .set(fang_tasks::created_at.eq(Utc::now() - Duration::minutes(2)))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                let new_task = queue
+                    .insert_throttled(&PepeTask { number: 10 }, Duration::minutes(1))
+                    .unwrap();
+                assert_ne!(task.id, new_task.id);
+                assert_eq!(task.uniq_hash, new_task.uniq_hash);
+            }
+
+            #[test]
+            fn distinct_task_types_returns_every_type_once() {
+                let queue: $q = $e;
+
+                assert!(queue.distinct_task_types().unwrap().is_empty());
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue.fail_task(&task, "error").unwrap();
+                queue
+                    .insert_task(&ScheduledPepeTask {
+                        number: 10,
+                        datetime: (Utc::now() + Duration::hours(1)).to_string(),
+                    })
+                    .unwrap();
+
+                assert_eq!(
+                    vec![
+                        COMMON_TYPE.to_string(),
+                        "scheduled".to_string(),
+                        "weirdo".to_string()
+                    ],
+                    queue.distinct_task_types().unwrap()
+                );
+            }
+
+            #[test]
+            fn task_counts_groups_tasks_by_type_and_state() {
+                let queue: $q = $e;
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task = queue.insert_task(&PepeTask { number: 12 }).unwrap();
+                queue.fail_task(&task, "error").unwrap();
+                queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+                assert_eq!(
+                    vec![
+                        (COMMON_TYPE.to_string(), FangTaskState::New, 2),
+                        (COMMON_TYPE.to_string(), FangTaskState::Failed, 1),
+                        ("weirdo".to_string(), FangTaskState::New, 1),
+                    ],
+                    queue.task_counts().unwrap()
+                );
+            }
+
+            #[test]
+            fn deserialize_metadata_returns_the_job() {
+                let queue: $q = $e;
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let task = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                let job: PepeTask = task.deserialize_metadata().unwrap();
+                assert_eq!(10, job.number);
+            }
+
+            #[test]
+            fn prune_finished_tasks_removes_oldest_finished_tasks_over_threshold() {
+                let queue: $q = $e;
+
+                let mut tasks = Vec::new();
+
+                for number in 1..=5 {
+                    tasks.push(queue.insert_task(&PepeTask { number }).unwrap());
+                }
+
+                for task in &tasks[..4] {
+                    queue.update_task_state(task, FangTaskState::Finished).unwrap();
+                }
+
+                assert_eq!(0, queue.prune_finished_tasks(5, 2).unwrap());
+                assert_eq!(3, queue.prune_finished_tasks(4, 2).unwrap());
+
+                let remaining_ids: Vec<_> = tasks
+                    .iter()
+                    .map(|task| task.id)
+                    .filter(|id| queue.find_task_by_id(*id).is_some())
+                    .collect();
+
+                assert_eq!(vec![tasks[3].id, tasks[4].id], remaining_ids);
+                assert_eq!(0, queue.prune_finished_tasks(1, 10).unwrap());
+
+                queue.soft_delete_task(tasks[4].id).unwrap();
+
+                assert_eq!(0, queue.prune_finished_tasks(1, 0).unwrap());
+                assert!(queue.find_task_by_id(tasks[3].id).is_some());
+            }
+
+            #[test]
+            fn fail_task_with_details_stores_error_details() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let details = serde_json::json!({"code": 503, "service": "payments"});
+
+                let failed_task = queue
+                    .fail_task_with_details(&task, "service unavailable", details.clone())
+                    .unwrap();
+
+                assert_eq!(FangTaskState::Failed, failed_task.state);
+
+                let found_task = queue.find_task_by_id(task.id).unwrap();
+
+                assert_eq!(
+                    Some("service unavailable".to_string()),
+                    found_task.error_message
+                );
+                assert_eq!(Some(details), found_task.error_details);
+            }
+
+            #[test]
+            fn move_task_to_moves_task_to_target_table() {
+                let queue: $q = $e;
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::sql_query("CREATE TABLE staging_fang_tasks (LIKE fang_tasks INCLUDING DEFAULTS)")
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let moved_task = queue
+                    .move_task_to(task.id, "public.staging_fang_tasks")
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task, moved_task);
+                assert_eq!(None, queue.find_task_by_id(task.id));
+
+                let mut connection = queue.get_connection().unwrap();
+                let staged_task = diesel::sql_query("SELECT * FROM staging_fang_tasks WHERE id = $1")
+                    .bind::<diesel::sql_types::Uuid, _>(task.id)
+                    .get_result::<Task>(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                assert_eq!(task.metadata, staged_task.metadata);
+                assert_eq!(None, queue.move_task_to(task.id, "staging_fang_tasks").unwrap());
+            }
+
+            #[test]
+            fn task_status_returns_lifecycle_of_task() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task = queue.schedule_retry(&task, 60, "error").unwrap();
+
+                let status = queue.task_status(task.id).unwrap().unwrap();
+
+                assert_eq!(task.id, status.id);
+                assert_eq!(FangTaskState::Retried, status.state);
+                assert_eq!(1, status.retries);
+                assert_eq!(Some("error".to_string()), status.error_message);
+                assert_eq!(task.created_at, status.created_at);
+                assert_eq!(task.updated_at, status.updated_at);
+
+                queue.soft_delete_task(task.id).unwrap();
+
+                assert_eq!(None, queue.task_status(task.id).unwrap());
+                assert_eq!(None, queue.task_status(Uuid::new_v4()).unwrap());
+            }
+
+            #[test]
+            fn pending_task_exists_checks_pending_tasks_by_uniq_hash() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let uniq_hash = task.uniq_hash.clone().unwrap();
+
+                assert!(queue.pending_task_exists(&uniq_hash).unwrap());
+                assert!(!queue.pending_task_exists("unknown").unwrap());
+
+                queue
+                    .update_task_state(&task, FangTaskState::Finished)
+                    .unwrap();
+
+                assert!(!queue.pending_task_exists(&uniq_hash).unwrap());
+            }
+
+            #[test]
+            fn fetch_and_touch_random_task_spreads_fetches() {
+                let queue: $q = $e;
+
+                let mut ids = Vec::new();
+
+                for number in 1..=20 {
+                    ids.push(queue.insert_task(&PepeTask { number }).unwrap().id);
+                }
+
+                let mut fetched_ids = Vec::new();
+
+                for _ in 0..10 {
+                    let task = queue
+                        .fetch_and_touch_random_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .unwrap();
+
+                    assert_eq!(FangTaskState::InProgress, task.state);
+                    fetched_ids.push(task.id);
+                }
+
+                fetched_ids.sort();
+                fetched_ids.dedup();
+                assert_eq!(10, fetched_ids.len());
+
+                let mut oldest_ids = ids[..10].to_vec();
+                oldest_ids.sort();
+                assert_ne!(oldest_ids, fetched_ids);
+            }
+
+            #[test]
+            fn fetch_and_touch_lowest_priority_task_fetches_the_lowest_priority_first() {
+                let queue: $q = $e;
+
+                let mut tasks = Vec::new();
+
+                for (number, priority) in [(10, 5), (11, -3), (12, 0)] {
+                    let task = queue
+                        .insert_new_task(
+                            &NewTask::from_runnable(&PepeTask { number })
+                                .unwrap()
+                                .with_priority(priority),
+                        )
+                        .unwrap();
+                    tasks.push(task);
+                }
+
+                let ordered_ids = [tasks[1].id, tasks[2].id, tasks[0].id];
+
+                for id in ordered_ids {
+                    let task = queue
+                        .fetch_and_touch_lowest_priority_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .unwrap();
+
+                    assert_eq!(id, task.id);
+                    assert_eq!(FangTaskState::InProgress, task.state);
+                }
+
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_and_touch_lowest_priority_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                );
+            }
+
+            #[test]
+            fn fetch_and_touch_longest_backlog_task_fetches_from_the_largest_backlog() {
+                let queue: $q = $e;
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                for number in 1..=3 {
+                    queue.insert_task(&AyratTask { number }).unwrap();
+                }
+
+                let mut fetched_types = Vec::new();
+
+                while let Some(task) = queue.fetch_and_touch_longest_backlog_task().unwrap() {
+                    assert_eq!(FangTaskState::InProgress, task.state);
+                    fetched_types.push(task.task_type);
+                }
+
+                // the backlogs are even after the second fetch, ties are broken by the name of the type
+                assert_eq!(vec!["weirdo", "weirdo", "common", "weirdo"], fetched_types);
+            }
+
+            #[test]
+            fn export_tasks_round_trips_through_import_tasks() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task1 = queue.fail_task(&task1, "error 1").unwrap();
+                let task2 = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let task2 = queue.fail_task(&task2, "error 2").unwrap();
+                queue.insert_task(&PepeTask { number: 11 }).unwrap();
+
+                let json = queue.export_tasks(FangTaskState::Failed).unwrap();
+
+                // both tasks are created in the same test transaction, so they have the same `created_at`
+                let mut exported_tasks: Vec<Task> = serde_json::from_str(&json).unwrap();
+                exported_tasks.sort_by_key(|task| task.task_type.clone());
+                assert_eq!(vec![task1.clone(), task2.clone()], exported_tasks);
+
+                queue.remove_task(task1.id).unwrap();
+
+                assert_eq!(1, queue.import_tasks(&json).unwrap());
+                assert_eq!(Some(task1.clone()), queue.find_task_by_id(task1.id));
+                assert_eq!(Some(task2.clone()), queue.find_task_by_id(task2.id));
+            }
+
+            #[test]
+            fn fang_fetch_task_function_fetches_and_touches_a_task() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+
+                let fetched_task = diesel::sql_query("SELECT * FROM fang_fetch_task($1)")
+                    .bind::<diesel::sql_types::Text, _>("weirdo")
+                    .get_result::<Task>(&mut connection)
+                    .unwrap();
+
+                assert_eq!(task.id, fetched_task.id);
+                assert_eq!(FangTaskState::InProgress, fetched_task.state);
+
+                let fetched_tasks = diesel::sql_query("SELECT * FROM fang_fetch_task($1)")
+                    .bind::<diesel::sql_types::Text, _>("weirdo")
+                    .load::<Task>(&mut connection)
+                    .unwrap();
+
+                assert!(fetched_tasks.is_empty());
+
+                drop(connection);
+
+                let found_task = queue.find_task_by_id(task.id).unwrap();
+                assert_eq!(FangTaskState::InProgress, found_task.state);
+            }
+
+            #[test]
+            fn retried_tasks_scheduled_in_the_future_are_not_runnable() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                queue.schedule_retry(&task1, 3600, "error").unwrap();
+
+                let task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+
+                let task3 = queue.insert_task(&PepeTask { number: 12 }).unwrap();
+                queue.cancel_task(task3.id).unwrap();
+
+                assert_eq!(
+                    1,
+                    queue.count_runnable_tasks(COMMON_TYPE.to_string()).unwrap()
+                );
+                assert_eq!(
+                    Some(task2.id),
+                    queue
+                        .peek_next_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .map(|task| task.id)
+                );
+                assert_eq!(
+                    Some(task2.id),
+                    queue
+                        .fetch_and_touch_task(COMMON_TYPE.to_string())
+                        .unwrap()
+                        .map(|task| task.id)
+                );
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
+
+            #[test]
+            fn soft_deleted_task_is_not_fetched() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                assert_eq!(1, queue.soft_delete_task(task.id).unwrap());
+
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+                assert_eq!(None, queue.find_task_by_id(task.id));
+
+                let found_task = queue.find_task_by_id_with_deleted(task.id, true).unwrap();
+
+                assert_eq!(FangTaskState::New, found_task.state);
+                assert!(found_task.deleted_at.is_some());
+            }
+
+            #[test]
+            fn insert_full_persists_all_fields() {
+                let queue: $q = $e;
+
+                let metadata = serde_json::json!({"type": "AyratTask", "number": 10});
+                let scheduled_at = (Utc::now() + Duration::minutes(5)).round_subsecs(0);
+
+                let task = queue
+                    .insert_full(
+                        metadata.clone(),
+                        Some("weirdo".to_string()),
+                        Some(7),
+                        Some(scheduled_at),
+                    )
+                    .unwrap();
+
+                let found_task = queue.find_task_by_id(task.id).unwrap();
+
+                assert_eq!(metadata, found_task.metadata);
+                assert_eq!("weirdo", found_task.task_type);
+                assert_eq!(7, found_task.priority);
+                assert_eq!(scheduled_at, found_task.scheduled_at);
+
+                let task = queue.insert_full(metadata, None, None, None).unwrap();
+
+                assert_eq!(COMMON_TYPE, task.task_type);
+                assert_eq!(0, task.priority);
+                assert!(task.scheduled_at <= Utc::now());
+            }
+
+            #[test]
+            fn fetch_and_touch_task_fetches_higher_priority_first() {
+                let queue: $q = $e;
+
+                let metadata = serde_json::json!({"type": "AyratTask", "number": 10});
+                let weirdo = Some("weirdo".to_string());
+
+                let default_task = queue
+                    .insert_full(metadata.clone(), weirdo.clone(), None, None)
+                    .unwrap();
+                let low_task = queue
+                    .insert_full(metadata.clone(), weirdo.clone(), Some(1), None)
+                    .unwrap();
+                let high_task = queue
+                    .insert_full(metadata, weirdo, Some(5), None)
+                    .unwrap();
+
+                let fetched_ids: Vec<_> = (0..3)
+                    .map(|_| {
+                        queue
+                            .fetch_and_touch_task("weirdo".to_string())
+                            .unwrap()
+                            .unwrap()
+                            .id
+                    })
+                    .collect();
+
+                assert_eq!(vec![high_task.id, low_task.id, default_task.id], fetched_ids);
+            }
+
+            #[test]
+            fn priority_fetch_uses_the_priority_index() {
+                let queue: $q = $e;
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+
+                // the table of the test is too small for the planner to prefer an index on its own,
+                // without sorts only an index in the order of the fetch can be used
+                for setting in ["enable_seqscan", "enable_bitmapscan", "enable_sort"] {
+                    diesel::sql_query(format!("SET LOCAL {setting} = off"))
+                        .execute(&mut connection)
+                        .unwrap();
+                }
+
+                #[derive(QueryableByName)]
+                struct PlanRow {
+                    #[diesel(sql_type = diesel::sql_types::Text, column_name = "QUERY PLAN")]
+                    line: String,
+                }
+
+                let plan = diesel::sql_query(
+                    "EXPLAIN SELECT id FROM fang_tasks \
+                     WHERE task_type = 'common' AND state IN ('new', 'retried') \
+                       AND deleted_at IS NULL AND scheduled_at <= statement_timestamp() \
+                     ORDER BY priority DESC, created_at ASC, scheduled_at ASC \
+                     LIMIT 1 FOR UPDATE SKIP LOCKED",
+                )
+                .load::<PlanRow>(&mut connection)
+                .unwrap()
+                .into_iter()
+                .map(|row| row.line)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+                assert!(plan.contains("priority_created_at"), "{plan}");
+                assert!(!plan.contains("Sort"), "{plan}");
+            }
+
+            #[test]
+            fn insert_child_propagates_the_correlation_id() {
+                let queue: $q = $e;
+
+                let parent = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                assert_eq!(None, parent.correlation_id);
+
+                let child = queue
+                    .insert_child(&parent, &AyratTask { number: 11 })
+                    .unwrap();
+                assert_eq!(Some(parent.id), child.correlation_id);
+
+                let grandchild = queue
+                    .insert_child(&child, &PepeTask { number: 12 })
+                    .unwrap();
+                assert_eq!(Some(parent.id), grandchild.correlation_id);
+
+                queue.insert_task(&PepeTask { number: 13 }).unwrap();
+
+                let workflow_ids: Vec<_> = queue
+                    .fetch_tasks_by_correlation(parent.id)
+                    .unwrap()
+                    .into_iter()
+                    .map(|task| task.id)
+                    .collect();
+
+                assert_eq!(vec![parent.id, child.id, grandchild.id], workflow_ids);
+            }
+
+            #[test]
+            fn fetch_task_of_types_fetches_only_allowed_types() {
+                let queue: $q = $e;
+
+                let common_task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let weirdo_task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue
+                    .insert_task(&ScheduledPepeTask {
+                        number: 10,
+                        datetime: Utc::now().to_string(),
+                    })
+                    .unwrap();
+
+                let allowed = [COMMON_TYPE, "weirdo"];
+
+                let mut fetched_ids = vec![
+                    queue.fetch_task_of_types(&allowed).unwrap().unwrap().id,
+                    queue.fetch_task_of_types(&allowed).unwrap().unwrap().id,
+                ];
+                fetched_ids.sort();
+
+                let mut expected_ids = vec![common_task.id, weirdo_task.id];
+                expected_ids.sort();
+
+                assert_eq!(expected_ids, fetched_ids);
+                assert_eq!(None, queue.fetch_task_of_types(&allowed).unwrap());
+                assert!(queue
+                    .fetch_task_of_types(&["scheduled"])
+                    .unwrap()
+                    .is_some());
+            }
+
+            #[test]
+            fn reset_retried_moves_retried_tasks_back_to_new() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task1 = queue.schedule_retry(&task1, 0, "error 1").unwrap();
+                let task2 = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let task2 = queue.schedule_retry(&task2, 60, "error 2").unwrap();
+                let task3 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let task3 = queue.fail_task(&task3, "error 3").unwrap();
+
+                assert_eq!(FangTaskState::Retried, task1.state);
+                assert_eq!(2, queue.reset_retried().unwrap());
+
+                for task in [task1, task2] {
+                    let reset_task = queue.find_task_by_id(task.id).unwrap();
+
+                    assert_eq!(FangTaskState::New, reset_task.state);
+                    assert_eq!(None, reset_task.error_message);
+                    assert_eq!(task.retries, reset_task.retries);
+                }
+
+                let failed_task = queue.find_task_by_id(task3.id).unwrap();
+                assert_eq!(FangTaskState::Failed, failed_task.state);
+                assert_eq!(0, queue.reset_retried().unwrap());
+            }
+
+            #[test]
+            fn transform_metadata_rewrites_pending_tasks_of_the_type() {
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task2 = queue.insert_task(&PepeTask { number: 11 }).unwrap();
+                let finished_task = queue.insert_task(&PepeTask { number: 12 }).unwrap();
+                let finished_task = queue
+                    .update_task_state(&finished_task, FangTaskState::Finished)
+                    .unwrap();
+                let other_task = queue.insert_task(&AyratTask { number: 13 }).unwrap();
+
+                let add_language = |mut metadata: serde_json::Value| {
+                    metadata["language"] = serde_json::json!("en");
+                    metadata
+                };
+
+                assert_eq!(
+                    2,
+                    queue
+                        .transform_metadata(COMMON_TYPE, &add_language)
+                        .unwrap()
+                );
+
+                for (task, number) in [(task1, 10), (task2, 11)] {
+                    let found_task = queue.find_task_by_id(task.id).unwrap();
+
+                    assert_eq!(serde_json::json!("en"), found_task.metadata["language"]);
+                    assert_eq!(serde_json::json!(number), found_task.metadata["number"]);
+                }
+
+                for task in [finished_task, other_task] {
+                    let found_task = queue.find_task_by_id(task.id).unwrap();
+
+                    assert_eq!(task.metadata, found_task.metadata);
+                }
+
+                assert_eq!(
+                    0,
+                    queue
+                        .transform_metadata(COMMON_TYPE, &add_language)
+                        .unwrap()
+                );
+            }
+
+            #[test]
+            fn expired_task_is_not_fetched_and_expire_tasks_marks_it_expired() {
+                let queue: $q = $e;
+
+                let expired_task = queue
+                    .insert_new_task(
+                        &NewTask::from_runnable(&PepeTask { number: 10 })
+                            .unwrap()
+                            .with_expires_at(Utc::now() - Duration::minutes(1)),
+                    )
+                    .unwrap();
+                let fresh_task = queue
+                    .insert_with_ttl(&PepeTask { number: 11 }, Duration::hours(1))
+                    .unwrap();
+
+                assert!(fresh_task.expires_at.unwrap() > Utc::now());
+
+                let fetched_task = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(fresh_task.id, fetched_task.id);
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+
+                assert_eq!(1, queue.expire_tasks().unwrap());
+
+                let expired_task = queue.find_task_by_id(expired_task.id).unwrap();
+                assert_eq!(FangTaskState::Expired, expired_task.state);
+
+                let fetched_task = queue.find_task_by_id(fetched_task.id).unwrap();
+                assert_eq!(FangTaskState::InProgress, fetched_task.state);
+
+                assert_eq!(0, queue.expire_tasks().unwrap());
+            }
+
+            #[test]
+            fn task_is_not_fetched_before_its_dependency_is_finished() {
+                let queue: $q = $e;
+
+                let task_a = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let task_b = queue
+                    .insert_after(&AyratTask { number: 10 }, task_a.id)
+                    .unwrap();
+
+                assert_eq!(Some(task_a.id), task_b.depends_on);
+                assert_eq!(None, queue.fetch_and_touch_task("weirdo".to_string()).unwrap());
+                assert_eq!(0, queue.count_runnable_tasks("weirdo".to_string()).unwrap());
+
+                let task_a = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(None, queue.fetch_and_touch_task("weirdo".to_string()).unwrap());
+
+                queue
+                    .update_task_state(&task_a, FangTaskState::Finished)
+                    .unwrap();
+
+                let fetched_task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task_b.id, fetched_task.id);
+            }
+
+            #[test]
+            fn task_with_expired_lease_is_fetchable_again() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+
+                let leased_task = queue
+                    .fetch_with_lease("weirdo", Duration::minutes(5))
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task.id, leased_task.id);
+                assert_eq!(FangTaskState::InProgress, leased_task.state);
+                assert!(leased_task.locked_until.unwrap() > Utc::now() + Duration::minutes(4));
+                assert_eq!(0, queue.requeue_expired_leases().unwrap());
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_with_lease("weirdo", Duration::minutes(5))
+                        .unwrap()
+                );
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(task.id)))
+                    .set(fang_tasks::locked_until.eq(Utc::now() - Duration::seconds(1)))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                assert_eq!(1, queue.requeue_expired_leases().unwrap());
+
+                let requeued_task = queue.find_task_by_id(task.id).unwrap();
+                assert_eq!(FangTaskState::New, requeued_task.state);
+                assert_eq!(None, requeued_task.locked_until);
+
+                let leased_task = queue
+                    .fetch_with_lease("weirdo", Duration::minutes(5))
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(task.id, leased_task.id);
+            }
+
+            #[test]
+            fn release_lease_fails_once_the_task_is_requeued() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                queue.release_lease(&task).unwrap();
+
+                let first_lease = queue
+                    .fetch_with_lease("weirdo", Duration::minutes(5))
+                    .unwrap()
+                    .unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+                diesel::update(fang_tasks::table.filter(fang_tasks::id.eq(task.id)))
+                    .set(fang_tasks::locked_until.eq(Utc::now() - Duration::seconds(1)))
+                    .execute(&mut connection)
+                    .unwrap();
+                drop(connection);
+
+                assert_eq!(1, queue.requeue_expired_leases().unwrap());
+
+                let second_lease = queue
+                    .fetch_with_lease("weirdo", Duration::minutes(5))
+                    .unwrap()
+                    .unwrap();
+
+                assert!(matches!(
+                    queue.release_lease(&first_lease),
+                    Err(QueueError::LeaseLostError(id)) if id == task.id
+                ));
+
+                queue.release_lease(&second_lease).unwrap();
+
+                let released_task = queue.find_task_by_id(task.id).unwrap();
+                assert_eq!(FangTaskState::InProgress, released_task.state);
+                assert_eq!(None, released_task.locked_until);
+                assert_eq!(0, queue.requeue_expired_leases().unwrap());
+            }
+
+            #[test]
+            fn requeue_task_requeues_only_tasks_not_updated_since_the_fetch() {
+                let queue: $q = $e;
+
+                queue.insert_task(&AyratTask { number: 10 }).unwrap();
+                let task = queue
+                    .fetch_and_touch_task("weirdo".to_string())
+                    .unwrap()
+                    .unwrap();
+
+                let touched = queue
+                    .update_task_state(&task, FangTaskState::InProgress)
+                    .unwrap();
+                assert_eq!(None, queue.requeue_task(&task).unwrap());
+
+                let finished = queue
+                    .update_task_state(&touched, FangTaskState::Finished)
+                    .unwrap();
+                assert_eq!(None, queue.requeue_task(&finished).unwrap());
+                assert_eq!(
+                    FangTaskState::Finished,
+                    queue.find_task_by_id(task.id).unwrap().state
+                );
+
+                let in_progress = queue
+                    .update_task_state(&finished, FangTaskState::InProgress)
+                    .unwrap();
+                let requeued = queue.requeue_task(&in_progress).unwrap().unwrap();
+                assert_eq!(FangTaskState::Retried, requeued.state);
+                assert_eq!(None, queue.requeue_task(&in_progress).unwrap());
+            }
+
+            #[test]
+            fn fetch_task_by_tag_filters_tasks_by_tag() {
+                let queue: $q = $e;
+
+                let tenant_41 = NewTask::from_runnable(&PepeTask { number: 10 })
+                    .unwrap()
+                    .with_tags(serde_json::json!({"tenant": 41, "source": "import"}));
+                let tenant_42 = NewTask::from_runnable(&PepeTask { number: 11 })
+                    .unwrap()
+                    .with_tags(serde_json::json!({"tenant": 42, "source": "import"}));
+                let untagged = NewTask::from_runnable(&PepeTask { number: 12 }).unwrap();
+
+                queue.insert_new_task(&tenant_41).unwrap();
+                let tenant_42 = queue.insert_new_task(&tenant_42).unwrap();
+                queue.insert_new_task(&untagged).unwrap();
+
+                assert_eq!(
+                    Some(serde_json::json!({"tenant": 42, "source": "import"})),
+                    tenant_42.tags
+                );
+
+                let found_task = queue
+                    .fetch_task_by_tag(COMMON_TYPE.to_string(), "tenant", serde_json::json!(42))
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(tenant_42.id, found_task.id);
+                assert_eq!(FangTaskState::InProgress, found_task.state);
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_task_by_tag(COMMON_TYPE.to_string(), "tenant", serde_json::json!(42))
+                        .unwrap()
+                );
+                assert_eq!(
+                    None,
+                    queue
+                        .fetch_task_by_tag(COMMON_TYPE.to_string(), "tenant", serde_json::json!("41"))
+                        .unwrap()
+                );
+            }
+
+            #[test]
+            fn insert_stream_inserts_tasks_in_chunks() {
+                let queue: $q = $e;
+
+                let tasks = (0..25)
+                    .map(|number| NewTask::from_runnable(&PepeTask { number }).unwrap());
+
+                assert_eq!(25, queue.insert_stream(tasks, 10).unwrap());
+                assert_eq!(
+                    25,
+                    queue.count_runnable_tasks(COMMON_TYPE.to_string()).unwrap()
+                );
+                assert_eq!(0, queue.insert_stream(std::iter::empty(), 10).unwrap());
+            }
+
+            #[test]
+            fn fetch_and_touch_with_custom_query() {
+                let queue: $q = $e;
+
+                let low = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let high = queue
+                    .insert_new_task(
+                        &NewTask::from_runnable(&PepeTask { number: 11 })
+                            .unwrap()
+                            .with_priority(5),
+                    )
+                    .unwrap();
+
+                let mut connection = queue.get_connection().unwrap();
+
+                let custom_query = || {
+                    Queue::runnable_tasks_query(COMMON_TYPE).filter(fang_tasks::priority.gt(0))
+                };
+
+                let found_task = Queue::fetch_and_touch_with_query(&mut connection, custom_query())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(high.id, found_task.id);
+                assert_eq!(FangTaskState::InProgress, found_task.state);
+                assert_eq!(
+                    None,
+                    Queue::fetch_and_touch_with_query(&mut connection, custom_query()).unwrap()
+                );
+
+                let found_task = Queue::fetch_and_touch_with_query(
+                    &mut connection,
+                    Queue::runnable_tasks_query(COMMON_TYPE),
+                )
+                .unwrap()
+                .unwrap();
+
+                assert_eq!(low.id, found_task.id);
+            }
+
+            #[test]
+            fn claim_tasks_returns_typed_batch_in_order() {
+                let queue: $q = $e;
+
+                let mut ids = Vec::new();
+
+                for number in 1..=4 {
+                    let created_at = Utc::now() - Duration::minutes(10 - number as i64);
+                    let task = queue
+                        .insert_new_task(
+                            &NewTask::from_runnable(&PepeTask { number })
+                                .unwrap()
+                                .with_created_at(created_at),
+                        )
+                        .unwrap();
+
+                    ids.push(task.id);
+                }
+
+                queue
+                    .insert_full(serde_json::json!({"number": "four"}), None, None, None)
+                    .unwrap();
+
+                let claimed = queue
+                    .claim_tasks::<PepeTask>(COMMON_TYPE.to_string(), 3)
+                    .unwrap();
+
+                assert_eq!(
+                    ids[..3].to_vec(),
+                    claimed.iter().map(|(task, _)| task.id).collect::<Vec<_>>()
+                );
+                assert_eq!(
+                    vec![1, 2, 3],
+                    claimed.iter().map(|(_, job)| job.number).collect::<Vec<_>>()
+                );
+                assert!(claimed
+                    .iter()
+                    .all(|(task, _)| task.state == FangTaskState::InProgress));
+
+                let claimed = queue
+                    .claim_tasks::<PepeTask>(COMMON_TYPE.to_string(), 3)
+                    .unwrap();
+
+                assert_eq!(1, claimed.len());
+                assert_eq!(4, claimed[0].1.number);
+                assert_eq!(
+                    0,
+                    queue.count_runnable_tasks(COMMON_TYPE.to_string()).unwrap()
+                );
+            }
+
+            #[test]
+            fn insert_coalesce_keeps_one_task_with_latest_metadata() {
+                let queue: $q = $e;
+
+                let task1 = queue
+                    .insert_coalesce("user:1", &PepeTask { number: 10 })
+                    .unwrap();
+                let task2 = queue
+                    .insert_coalesce("user:1", &PepeTask { number: 11 })
+                    .unwrap();
+                let other_task = queue
+                    .insert_coalesce("user:2", &PepeTask { number: 12 })
+                    .unwrap();
+
+                assert_eq!(task1.id, task2.id);
+                assert_ne!(task1.id, other_task.id);
+                assert_eq!(Some(11), task2.metadata["number"].as_u64());
+                assert_eq!(
+                    2,
+                    queue.count_runnable_tasks(COMMON_TYPE.to_string()).unwrap()
+                );
+
+                let found_task = queue.find_task_by_id(task1.id).unwrap();
+                assert_eq!(Some(11), found_task.metadata["number"].as_u64());
+            }
+
+            #[test]
+            fn insert_task_uniq_test() {
+                let task = PepeTask { number: 10 };
+
+                let queue: $q = $e;
+
+                let task1 = queue.insert_task(&task).unwrap();
+                let task2 = queue.insert_task(&task).unwrap();
+                assert_eq!(task2.id, task1.id);
+            }
+
+            #[test]
+            fn schedule_task_test() {
+                let queue: $q = $e;
+                let datetime = (Utc::now() + Duration::seconds(7)).round_subsecs(0);
+
+                let task = &ScheduledPepeTask {
+                    number: 10,
+                    datetime: datetime.to_string(),
+                };
+                let task = queue.schedule_task(task).unwrap();
+
+                let metadata = task.metadata.as_object().unwrap();
+                let number = metadata["number"].as_u64();
+                let type_task = metadata["type"].as_str();
+
+                assert_eq!(Some(10), number);
+                assert_eq!(Some("ScheduledPepeTask"), type_task);
+                assert_eq!(task.scheduled_at, datetime);
+            }
+
+            #[test]
+            fn fetch_task_compares_scheduled_at_with_db_time() {
+                let queue: $q = $e;
+
+                let past_task = ScheduledPepeTask {
+                    number: 10,
+                    datetime: (Utc::now() - Duration::hours(1)).to_string(),
+                };
+                let future_task = ScheduledPepeTask {
+                    number: 11,
+                    datetime: (Utc::now() + Duration::hours(1)).to_string(),
+                };
+
+                let past_task = queue.schedule_task(&past_task).unwrap();
+                queue.schedule_task(&future_task).unwrap();
+
+                let found_task = queue
+                    .fetch_and_touch_task("scheduled".to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(past_task.id, found_task.id);
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task("scheduled".to_string()).unwrap()
+                );
+            }
+
+            #[test]
+            fn fetch_task_at_uses_the_supplied_time() {
+                let queue: $q = $e;
+
+                let now = Utc::now();
+                let task = ScheduledPepeTask {
+                    number: 10,
+                    datetime: (now + Duration::hours(1)).to_string(),
+                };
+                let task = queue.schedule_task(&task).unwrap();
+
+                assert_eq!(
+                    None,
+                    queue.fetch_task_at("scheduled".to_string(), now).unwrap()
+                );
+
+                let found_task = queue
+                    .fetch_task_at("scheduled".to_string(), now + Duration::hours(2))
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task.id, found_task.id);
+                assert_eq!(FangTaskState::InProgress, found_task.state);
+            }
+
+            #[test]
+            fn next_scheduled_at_returns_the_earliest_future_task() {
+                let queue: $q = $e;
+
+                assert_eq!(None, queue.next_scheduled_at().unwrap());
+
+                let soon = (Utc::now() + Duration::minutes(5)).round_subsecs(0);
+                let later = (Utc::now() + Duration::hours(1)).round_subsecs(0);
+
+                queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                queue
+                    .schedule_task(&ScheduledPepeTask {
+                        number: 11,
+                        datetime: later.to_string(),
+                    })
+                    .unwrap();
+                queue
+                    .schedule_task(&ScheduledPepeTask {
+                        number: 12,
+                        datetime: soon.to_string(),
+                    })
+                    .unwrap();
+
+                assert_eq!(Some(soon), queue.next_scheduled_at().unwrap());
+            }
+
+            #[test]
+            fn next_scheduled_at_skips_deleted_expired_and_paused_tasks() {
+                let queue: $q = $e;
+
+                let soon = (Utc::now() + Duration::minutes(5)).round_subsecs(0);
+                let later = (Utc::now() + Duration::hours(1)).round_subsecs(0);
+
+                queue
+                    .schedule_task(&ScheduledPepeTask {
+                        number: 10,
+                        datetime: later.to_string(),
+                    })
+                    .unwrap();
+
+                let deleted = queue
+                    .schedule_task(&ScheduledPepeTask {
+                        number: 11,
+                        datetime: soon.to_string(),
+                    })
+                    .unwrap();
+                queue.soft_delete_task(deleted.id).unwrap();
+
+                let expired = NewTask::builder()
+                    .metadata(serde_json::json!({"number": 12}))
+                    .task_type("scheduled")
+                    .uniq_hash(None)
+                    .scheduled_at(soon)
+                    .binary_metadata(None)
+                    .build()
+                    .with_expires_at(Utc::now() - Duration::minutes(1));
+                queue.insert_new_task(&expired).unwrap();
+
+                queue
+                    .insert_full(
+                        serde_json::json!({"number": 13}),
+                        Some("paused_type".to_string()),
+                        None,
+                        Some(soon),
+                    )
+                    .unwrap();
+                queue.pause_task_type("paused_type").unwrap();
+
+                assert_eq!(Some(later), queue.next_scheduled_at().unwrap());
+            }
+
+            #[test]
+            fn rescheduled_task_is_not_fetched_before_the_new_time() {
+                let queue: $q = $e;
+
+                let task = queue.insert_task(&PepeTask { number: 10 }).unwrap();
+                let scheduled_at = (Utc::now() + Duration::hours(1)).round_subsecs(0);
+
+                let rescheduled_task = queue.reschedule_task(task.id, scheduled_at).unwrap();
+
+                assert_eq!(scheduled_at, rescheduled_task.scheduled_at);
+                assert_eq!(
+                    None,
+                    queue.fetch_and_touch_task(COMMON_TYPE.to_string()).unwrap()
+                );
+
+                queue.reschedule_task(task.id, Utc::now()).unwrap();
+
+                let found_task = queue
+                    .fetch_and_touch_task(COMMON_TYPE.to_string())
+                    .unwrap()
+                    .unwrap();
+
+                assert_eq!(task.id, found_task.id);
+                assert!(matches!(
+                    queue.reschedule_task(task.id, scheduled_at),
+                    Err(QueueError::TaskNotPendingError(_))
+                ));
             }
 
             #[test]
@@ -11,6 +11,13 @@ pub trait Runnable {
     /// Execute the task. This method should define its logic
     fn run(&self, _queueable: &dyn Queueable) -> Result<(), FangError>;
 
+    /// Execute the task and return its result, workers call this method.
+    /// The returned value is stored in the `result` column of the task when it's finished.
+    /// By default it runs the task with `run` and returns `serde_json::Value::Null`, which isn't stored.
+    fn run_with_result(&self, queueable: &dyn Queueable) -> Result<serde_json::Value, FangError> {
+        self.run(queueable).map(|_| serde_json::Value::Null)
+    }
+
     /// Define the type of the task.
     /// The `common` task type is used by default
     fn task_type(&self) -> String {
@@ -50,4 +57,11 @@ pub trait Runnable {
     fn backoff(&self, attempt: u32) -> u32 {
         u32::pow(2, attempt)
     }
+
+    /// Define the delay in seconds before retrying the task after the `error`, for example
+    /// the `Retry-After` of a rate-limited API. It overrides the `backoff` and the retry jitter of workers.
+    /// By default, it is `None`, so the `backoff` is used.
+    fn retry_after(&self, _error: &FangError) -> Option<u32> {
+        None
+    }
 }
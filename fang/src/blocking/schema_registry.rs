@@ -0,0 +1,166 @@
+use crate::queue::QueueError;
+use jsonschema::JSONSchema;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// JSON Schemas of the metadata of tasks by task type, used by the queue to validate tasks when they are inserted.
+///
+/// Tasks of the types without a registered schema are not validated.
+/// Tasks with binary metadata, see [`crate::MetadataCodec`], are not validated either.
+#[derive(Clone, Default)]
+pub struct SchemaRegistry {
+    schemas: HashMap<String, Arc<JSONSchema>>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the schema of the metadata of the `task_type` tasks, it replaces the previous schema of the type.
+    /// `QueueError::InvalidSchemaError` is returned if the schema itself is not valid.
+    pub fn register(
+        &mut self,
+        task_type: impl Into<String>,
+        schema: &serde_json::Value,
+    ) -> Result<(), QueueError> {
+        let schema = JSONSchema::compile(schema)
+            .map_err(|error| QueueError::InvalidSchemaError(error.to_string()))?;
+
+        self.schemas.insert(task_type.into(), Arc::new(schema));
+
+        Ok(())
+    }
+
+    /// Validate the metadata of a task of the `task_type` type,
+    /// `QueueError::MetadataValidationError` describes every violation of the schema.
+    pub fn validate(
+        &self,
+        task_type: &str,
+        metadata: &serde_json::Value,
+    ) -> Result<(), QueueError> {
+        let schema = match self.schemas.get(task_type) {
+            Some(schema) => schema,
+            None => return Ok(()),
+        };
+
+        schema.validate(metadata).map_err(|errors| {
+            let description = errors
+                .map(|error| error.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            QueueError::MetadataValidationError(task_type.to_string(), description)
+        })
+    }
+}
+
+#[cfg(test)]
+mod schema_registry_tests {
+    use super::SchemaRegistry;
+    use crate::queue::NewTask;
+    use crate::queue::Queue;
+    use crate::queue::QueueError;
+    use crate::queue::Queueable;
+    use crate::runnable::Runnable;
+    use crate::runnable::COMMON_TYPE;
+    use crate::typetag;
+    use crate::FangError;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize)]
+    struct SchemaTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for SchemaTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn validates_metadata_of_inserted_tasks() {
+        let mut schemas = SchemaRegistry::new();
+        schemas
+            .register(
+                COMMON_TYPE,
+                &serde_json::json!({
+                    "type": "object",
+                    "properties": {"number": {"type": "integer", "maximum": 100}},
+                    "required": ["number"]
+                }),
+            )
+            .unwrap();
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .schemas(schemas)
+            .build();
+
+        let task = queue.insert_task(&SchemaTask { number: 10 }).unwrap();
+        assert_eq!(COMMON_TYPE, task.task_type);
+
+        let result = queue.insert_task(&SchemaTask { number: 101 });
+        assert!(matches!(
+            result,
+            Err(QueueError::MetadataValidationError(task_type, _)) if task_type == COMMON_TYPE
+        ));
+
+        let result = queue.insert_full(serde_json::json!({"number": "ten"}), None, None, None);
+        assert!(matches!(
+            result,
+            Err(QueueError::MetadataValidationError(_, _))
+        ));
+
+        let task = queue
+            .insert_full(
+                serde_json::json!({"number": "ten"}),
+                Some("weirdo".to_string()),
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!("weirdo", task.task_type);
+    }
+
+    #[test]
+    fn validates_metadata_of_tasks_inserted_in_a_connection() {
+        let mut schemas = SchemaRegistry::new();
+        schemas
+            .register(
+                COMMON_TYPE,
+                &serde_json::json!({
+                    "type": "object",
+                    "properties": {"number": {"type": "integer", "maximum": 100}}
+                }),
+            )
+            .unwrap();
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::test().connection_pool)
+            .schemas(schemas)
+            .build();
+        let mut connection = queue.get_connection().unwrap();
+
+        let new_task = NewTask::from_runnable(&SchemaTask { number: 10 }).unwrap();
+        queue.insert_in(&mut connection, &new_task).unwrap();
+
+        let new_task = NewTask::from_runnable(&SchemaTask { number: 101 }).unwrap();
+        let result = queue.insert_in(&mut connection, &new_task);
+        assert!(matches!(
+            result,
+            Err(QueueError::MetadataValidationError(task_type, _)) if task_type == COMMON_TYPE
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_schemas() {
+        let mut schemas = SchemaRegistry::new();
+
+        let result = schemas.register(COMMON_TYPE, &serde_json::json!({"type": "not_a_type"}));
+
+        assert!(matches!(result, Err(QueueError::InvalidSchemaError(_))));
+    }
+}
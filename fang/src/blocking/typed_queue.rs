@@ -0,0 +1,175 @@
+use crate::queue::Queue;
+use crate::queue::QueueError;
+use crate::queue::Queueable;
+use crate::runnable::Runnable;
+use crate::Task;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::marker::PhantomData;
+
+/// A queue that carries only one type of jobs.
+///
+/// Jobs are pushed and popped as values of `T`, the metadata of the tasks is encoded and decoded
+/// with the codec of the underlying queue, so users never deal with `serde_json::Value`.
+/// The `task_type` of the typed queue has to be the one returned by `Runnable::task_type` of its jobs.
+pub struct TypedQueue<T, BQueue = Queue>
+where
+    T: Runnable + Serialize + DeserializeOwned,
+    BQueue: Queueable,
+{
+    queue: BQueue,
+    task_type: String,
+    job: PhantomData<fn() -> T>,
+}
+
+impl<T, BQueue> TypedQueue<T, BQueue>
+where
+    T: Runnable + Serialize + DeserializeOwned,
+    BQueue: Queueable,
+{
+    pub fn new(queue: BQueue, task_type: impl Into<String>) -> Self {
+        Self {
+            queue,
+            task_type: task_type.into(),
+            job: PhantomData,
+        }
+    }
+
+    /// The underlying queue
+    pub fn queue(&self) -> &BQueue {
+        &self.queue
+    }
+
+    /// Enqueue a job, it's the same as `Queueable::insert_task`.
+    pub fn push(&self, job: T) -> Result<Task, QueueError> {
+        self.queue.insert_task(&job)
+    }
+
+    /// Enqueue a job given as a JSON payload, for example received from an API.
+    /// The payload is decoded into `T` before the task is inserted, so if `T` is annotated with
+    /// `#[serde(deny_unknown_fields)]` a misspelled field is rejected with `QueueError::InvalidPayloadError`
+    /// instead of being silently dropped.
+    pub fn push_payload(&self, payload: serde_json::Value) -> Result<Task, QueueError> {
+        let job: T = serde_json::from_value(payload).map_err(|error| {
+            QueueError::InvalidPayloadError(self.task_type.clone(), error.to_string())
+        })?;
+
+        self.push(job)
+    }
+
+    /// Fetch and touch the next task of the typed queue and decode its job.
+    /// If the metadata of the task can not be decoded into `T`, the task is failed and the error is returned.
+    pub fn pop(&self) -> Result<Option<(Task, T)>, QueueError> {
+        let task = match self.queue.fetch_and_touch_task(self.task_type.clone())? {
+            Some(task) => task,
+            None => return Ok(None),
+        };
+
+        match self.queue.codec().decode_as::<T>(&task) {
+            Ok(job) => Ok(Some((task, job))),
+            Err(error) => {
+                self.queue.fail_task(&task, &error.to_string())?;
+
+                Err(error.into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod typed_queue_tests {
+    use super::TypedQueue;
+    use crate::queue::Queue;
+    use crate::queue::QueueError;
+    use crate::queue::Queueable;
+    use crate::runnable::Runnable;
+    use crate::FangError;
+    use crate::FangTaskState;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+    struct SendEmail {
+        pub to: String,
+        pub attempts: u8,
+    }
+
+    #[typetag::serde]
+    impl Runnable for SendEmail {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "send_email".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+    #[serde(deny_unknown_fields)]
+    struct StrictSendEmail {
+        pub to: String,
+    }
+
+    #[typetag::serde]
+    impl Runnable for StrictSendEmail {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "strict_send_email".to_string()
+        }
+    }
+
+    #[test]
+    fn pushes_and_pops_typed_jobs() {
+        let queue: TypedQueue<SendEmail> = TypedQueue::new(Queue::test(), "send_email");
+
+        assert!(queue.pop().unwrap().is_none());
+
+        let pushed_task = queue
+            .push(SendEmail {
+                to: "pepe@example.com".to_string(),
+                attempts: 1,
+            })
+            .unwrap();
+
+        let (task, job) = queue.pop().unwrap().unwrap();
+
+        assert_eq!(pushed_task.id, task.id);
+        assert_eq!(FangTaskState::InProgress, task.state);
+        assert_eq!(
+            SendEmail {
+                to: "pepe@example.com".to_string(),
+                attempts: 1,
+            },
+            job
+        );
+
+        assert!(queue.pop().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_payloads_with_unexpected_fields() {
+        let queue: TypedQueue<StrictSendEmail> =
+            TypedQueue::new(Queue::test(), "strict_send_email");
+
+        let result =
+            queue.push_payload(serde_json::json!({"to": "pepe@example.com", "tp": "typo"}));
+
+        match result {
+            Err(QueueError::InvalidPayloadError(task_type, description)) => {
+                assert_eq!("strict_send_email", task_type);
+                assert!(description.contains("unknown field `tp`"));
+            }
+            _ => panic!("the payload with an unexpected field is not rejected"),
+        }
+
+        let task = queue
+            .push_payload(serde_json::json!({"to": "pepe@example.com"}))
+            .unwrap();
+
+        assert_eq!("strict_send_email", task.task_type);
+        assert!(queue.queue().codec().decode(&task).is_ok());
+    }
+}
@@ -1,14 +1,234 @@
+use crate::queue::Queue;
+use crate::queue::QueueError;
 use crate::queue::Queueable;
+use crate::queue::TaskListener;
+use crate::queue::TaskTypeLock;
 use crate::runnable::Runnable;
 use crate::runnable::COMMON_TYPE;
 use crate::FangError;
 use crate::FangTaskState;
 use crate::Scheduled::*;
 use crate::Task;
-use crate::{RetentionMode, SleepParams};
+use crate::{BatchParams, RetentionMode, RetryJitter, SleepParams};
 use log::error;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ops::ControlFlow;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 use typed_builder::TypedBuilder;
+use uuid::Uuid;
+
+/// A handler for the tasks that can not be decoded into a `Runnable`,
+/// for example tasks of a type that is not compiled into the binary of the worker.
+/// The result of the handler is treated like the result of a task without retries.
+pub type FallbackHandler =
+    Arc<dyn Fn(&dyn Queueable, &Task) -> Result<(), FangError> + Send + Sync>;
+
+/// What the worker does with a task that can not be decoded into a `Runnable` if there is no `fallback` handler.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum DeserializationPolicy {
+    /// Mark the task as `FangTaskState::Failed` with the decoding error.
+    #[default]
+    Fail,
+    /// Put the task back to the queue in the `FangTaskState::Retried` state, so a worker that can decode it picks it up.
+    /// It's scheduled after the `max_sleep_period` of the worker, so the worker doesn't fetch it again right away,
+    /// and its `retries` counter is incremented to record the skip.
+    Skip,
+    /// Move the task to the `FangTaskState::Quarantined` state, quarantined tasks are never fetched.
+    DeadLetter,
+}
+
+/// How the worker fetches the next task to execute.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FetchMode {
+    /// Fetch the tasks with the highest priority first, the oldest ones first, see `Queueable::fetch_and_touch_task`.
+    #[default]
+    Standard,
+    /// Execute tasks strictly in the order of insertion, see `Queueable::fetch_and_touch_task_in_order`.
+    /// A pool in this mode should have only one worker, others will be waiting for the current task to finish.
+    StrictOrder,
+    /// Fetch random tasks instead of the oldest ones, see `Queueable::fetch_and_touch_random_task`.
+    /// It reduces the contention between workers when the backlog is large.
+    Random,
+    /// Fetch the tasks with the lowest priority first, see `Queueable::fetch_and_touch_lowest_priority_task`.
+    /// It is useful for a dedicated pool draining the low-priority backlog during quiet periods.
+    LowestPriorityFirst,
+    /// Fetch the tasks of the type with the largest backlog, whatever the `task_type` of the worker is,
+    /// see `Queueable::fetch_and_touch_longest_backlog_task`. It keeps the backlogs of all types even.
+    /// It can't be combined with `serial_per_type`, which serializes the workers of one type.
+    LongestBacklogFirst,
+    /// Fetch tasks with a lease instead of keeping them locked, see `Queueable::fetch_with_lease`.
+    /// The fetch transaction is committed before a task is executed, so long tasks don't hold a transaction open.
+    /// Tasks still running when their lease expires are requeued by `Queueable::requeue_expired_leases`,
+    /// the outcome of such a task is discarded when it finishes, see `Queueable::release_lease`.
+    Lease(chrono::Duration),
+}
+
+impl FetchMode {
+    /// Check that the fetch mode can be combined with the `serial_per_type` option of a worker.
+    pub fn check(&self, serial_per_type: bool) -> Result<(), FangError> {
+        if serial_per_type && *self == FetchMode::LongestBacklogFirst {
+            return Err(FangError {
+                description:
+                    "The longest backlog first fetch mode can't be combined with serial_per_type"
+                        .to_string(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// An outcome of the processing of a task, the worker sends them to its `events` channel.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WorkerEvent {
+    /// The worker fetched the task and starts executing it.
+    Fetched(Uuid),
+    /// The task was executed successfully.
+    Finished(Uuid),
+    /// The task failed and it won't be retried, quarantined tasks included.
+    Failed(Uuid),
+    /// The task failed and it's scheduled to be retried.
+    Retried(Uuid),
+}
+
+/// A hook called by the worker before every fetch, for example to consult an external rate limiter.
+/// `ControlFlow::Break` skips the fetch, the worker sleeps and calls the hook again in the next iteration.
+pub type BeforeFetchHook = Arc<dyn Fn() -> ControlFlow<()> + Send + Sync>;
+
+/// Stops workers gracefully. Clones of a `Shutdown` share the same state, pass a clone to every worker
+/// (or worker pool) that should be stopped together.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    in_flight: Arc<Mutex<HashMap<Uuid, Task>>>,
+    requeue: bool,
+    requeued: Arc<Mutex<HashSet<Uuid>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requeue the tasks that are still executed after the timeout of `shutdown`, they are moved
+    /// to the `FangTaskState::Retried` state so other workers pick them up immediately.
+    /// The interrupted workers leave the states of the requeued tasks untouched when they finish them,
+    /// but the tasks can be executed twice.
+    pub fn with_requeue(mut self) -> Self {
+        self.requeue = true;
+        self
+    }
+
+    /// Ask the workers to stop, they finish the tasks they are executing and don't fetch new ones.
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// The tasks the workers are executing at the moment.
+    pub fn in_flight(&self) -> Vec<Task> {
+        self.in_flight.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Request the shutdown and wait up to `timeout` for the workers to finish their tasks.
+    /// The tasks that are still executed after the timeout are recorded in the log of interrupted tasks,
+    /// see `Queueable::record_interrupted`, and their ids are returned. They are requeued if the shutdown
+    /// was created `with_requeue`.
+    pub fn shutdown(
+        &self,
+        queue: &dyn Queueable,
+        timeout: Duration,
+    ) -> Result<Vec<Uuid>, FangError> {
+        self.request();
+
+        let deadline = Instant::now() + timeout;
+
+        while !self.in_flight.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let interrupted = self.in_flight();
+
+        if !interrupted.is_empty() {
+            queue.record_interrupted(&interrupted)?;
+        }
+
+        if self.requeue {
+            // the workers must see the ids before the tasks are requeued, not to store the outcomes afterwards
+            self.requeued
+                .lock()
+                .unwrap()
+                .extend(interrupted.iter().map(|task| task.id));
+
+            for task in &interrupted {
+                // the task was finished or failed after the snapshot of `in_flight`
+                if queue.requeue_task(task)?.is_none() {
+                    self.requeued.lock().unwrap().remove(&task.id);
+                }
+            }
+        }
+
+        Ok(interrupted.into_iter().map(|task| task.id).collect())
+    }
+
+    fn is_requeued(&self, id: Uuid) -> bool {
+        self.requeued.lock().unwrap().contains(&id)
+    }
+
+    /// Forget a requeued task once its worker is done with it.
+    fn forget_requeued(&self, id: Uuid) -> bool {
+        self.requeued.lock().unwrap().remove(&id)
+    }
+
+    fn track(&self, task: &Task) -> InFlightTask<'_> {
+        self.in_flight.lock().unwrap().insert(task.id, task.clone());
+
+        InFlightTask {
+            shutdown: self,
+            id: task.id,
+        }
+    }
+}
+
+/// Removes a task from the in-flight tasks of a `Shutdown` when the worker is done with it
+struct InFlightTask<'a> {
+    shutdown: &'a Shutdown,
+    id: Uuid,
+}
+
+impl Drop for InFlightTask<'_> {
+    fn drop(&mut self) {
+        self.shutdown.in_flight.lock().unwrap().remove(&self.id);
+    }
+}
+
+/// The finished and failed tasks waiting for a batched update of their states
+#[derive(Default)]
+struct StateBatch {
+    finished: Vec<Uuid>,
+    failed: Vec<(Uuid, String)>,
+    started_at: Option<Instant>,
+}
+
+impl StateBatch {
+    fn len(&self) -> usize {
+        self.finished.len() + self.failed.len()
+    }
+}
 
 /// A executioner of tasks, it executes tasks only of one given task_type, it sleeps when they are
 /// not tasks to be executed.
@@ -25,6 +245,40 @@ where
     pub sleep_params: SleepParams,
     #[builder(default, setter(into))]
     pub retention_mode: RetentionMode,
+    #[builder(default, setter(into))]
+    pub retry_jitter: RetryJitter,
+    #[builder(default, setter(into))]
+    pub fetch_mode: FetchMode,
+    #[builder(default, setter(into))]
+    pub serial_per_type: bool,
+    #[builder(default, setter(into))]
+    pub listen: bool,
+    #[builder(default, setter(strip_option))]
+    pub poison_threshold: Option<i32>,
+    #[builder(default, setter(into))]
+    pub batch_params: Option<BatchParams>,
+    #[cfg(feature = "reqwest")]
+    #[builder(default, setter(into))]
+    pub on_failure_webhook: Option<reqwest::Url>,
+    #[cfg(feature = "reqwest")]
+    #[builder(default, setter(skip))]
+    webhook_client: reqwest::blocking::Client,
+    #[builder(default, setter(strip_option))]
+    pub fallback: Option<FallbackHandler>,
+    #[builder(default, setter(into))]
+    pub deserialization_policy: DeserializationPolicy,
+    #[builder(default, setter(strip_option))]
+    pub before_fetch: Option<BeforeFetchHook>,
+    #[builder(default, setter(strip_option))]
+    pub events: Option<Sender<WorkerEvent>>,
+    #[builder(default, setter(into))]
+    pub shutdown: Shutdown,
+    #[builder(default, setter(skip))]
+    listener: Option<TaskListener>,
+    #[builder(default, setter(skip))]
+    type_lock: Mutex<Option<TaskTypeLock>>,
+    #[builder(default, setter(skip))]
+    state_batch: Mutex<StateBatch>,
 }
 
 impl<BQueue> Worker<BQueue>
@@ -32,17 +286,77 @@ where
     BQueue: Queueable + Clone + Sync + Send + 'static,
 {
     pub fn run(&self, task: &Task) -> Result<(), FangError> {
-        let runnable: Box<dyn Runnable> = serde_json::from_value(task.metadata.clone()).unwrap();
-        let result = runnable.run(&self.queue);
+        #[cfg(feature = "tracing")]
+        let _span = tracing::info_span!(
+            "fang_task",
+            task_id = %task.id,
+            task_type = %task.task_type,
+            retries = task.retries
+        )
+        .entered();
+
+        let _in_flight = self.shutdown.track(task);
+
+        self.send_event(WorkerEvent::Fetched(task.id));
+
+        let runnable: Box<dyn Runnable> = match self.queue.codec().decode(task) {
+            Ok(runnable) => runnable,
+            Err(error) => match &self.fallback {
+                Some(fallback) => {
+                    let result = fallback(&self.queue, task).map(|_| serde_json::Value::Null);
+
+                    if !self.release_lease(task)? {
+                        return Ok(());
+                    }
+
+                    return self.finalize_task(task, &result);
+                }
+                None => return self.handle_undecodable_task(task, error.into()),
+            },
+        };
+        let result = Self::run_catching_panics(runnable.as_ref(), &self.queue);
+
+        if self.shutdown.is_requeued(task.id) || !self.release_lease(task)? {
+            return Ok(());
+        }
 
         match result {
             Ok(_) => self.finalize_task(task, &result)?,
             Err(ref error) => {
-                if task.retries < runnable.max_retries() {
-                    let backoff_seconds = runnable.backoff(task.retries as u32);
+                let max_retries = task.max_retries.unwrap_or_else(|| runnable.max_retries());
+
+                if self.is_poisoned(task) {
+                    error!(
+                        "Task {} of type {} failed {} times in a row and it's quarantined, last error: {}",
+                        task.id,
+                        task.task_type,
+                        task.retries + 1,
+                        error.description
+                    );
+
+                    self.queue.quarantine_task(task, &error.description)?;
+                    self.send_event(WorkerEvent::Failed(task.id));
+                } else if task.retries < max_retries {
+                    let delay = match runnable.retry_after(error) {
+                        Some(retry_after) => chrono::Duration::seconds(retry_after as i64),
+                        None => {
+                            let backoff_seconds = runnable.backoff(task.retries as u32);
+
+                            self.retry_jitter
+                                .apply(chrono::Duration::seconds(backoff_seconds as i64))
+                        }
+                    };
 
                     self.queue
-                        .schedule_retry(task, backoff_seconds, &error.description)?;
+                        .schedule_retry_after(task, delay, &error.description)?;
+                    self.send_event(WorkerEvent::Retried(task.id));
+
+                    #[cfg(feature = "tracing")]
+                    tracing::warn!(
+                        error = %error.description,
+                        backoff_seconds = delay.num_seconds(),
+                        "task is scheduled to be retried"
+                    );
                 } else {
                     self.finalize_task(task, &result)?;
                 }
@@ -52,24 +366,118 @@ where
         Ok(())
     }
 
+    fn run_catching_panics(
+        runnable: &dyn Runnable,
+        queue: &BQueue,
+    ) -> Result<serde_json::Value, FangError> {
+        match panic::catch_unwind(AssertUnwindSafe(|| runnable.run_with_result(queue))) {
+            Ok(result) => result,
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|message| message.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_default();
+
+                Err(FangError {
+                    description: format!("The task panicked: {message}"),
+                })
+            }
+        }
+    }
+
+    fn handle_undecodable_task(&self, task: &Task, error: FangError) -> Result<(), FangError> {
+        error!(
+            "Task {} of type {} can not be decoded, deserialization policy {:?}: {}",
+            task.id, task.task_type, self.deserialization_policy, error.description
+        );
+
+        if self.shutdown.is_requeued(task.id) || !self.release_lease(task)? {
+            return Ok(());
+        }
+
+        match self.deserialization_policy {
+            DeserializationPolicy::Fail => {
+                self.fail_task(task, &error.description)?;
+                self.send_event(WorkerEvent::Failed(task.id));
+
+                Ok(())
+            }
+            DeserializationPolicy::Skip => {
+                let delay = chrono::Duration::from_std(self.sleep_params.max_sleep_period)
+                    .unwrap_or(chrono::Duration::MAX);
+
+                self.queue
+                    .schedule_retry_after(task, delay, &error.description)?;
+                self.send_event(WorkerEvent::Retried(task.id));
+
+                Ok(())
+            }
+            DeserializationPolicy::DeadLetter => {
+                self.queue.quarantine_task(task, &error.description)?;
+                self.send_event(WorkerEvent::Failed(task.id));
+
+                Ok(())
+            }
+        }
+    }
+
+    /// Release the lease of a task fetched with a lease, `false` if the lease expired while the task was running.
+    /// The task was requeued then and its outcome belongs to the worker executing it now.
+    fn release_lease(&self, task: &Task) -> Result<bool, FangError> {
+        match self.queue.release_lease(task) {
+            Ok(()) => Ok(true),
+            Err(QueueError::LeaseLostError(id)) => {
+                error!(
+                    "The lease of task {} of type {} expired while it was running, its outcome is discarded",
+                    id, task.task_type
+                );
+
+                Ok(false)
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    fn send_event(&self, event: WorkerEvent) {
+        if let Some(events) = &self.events {
+            // the events are informational, a dropped receiver doesn't stop the worker
+            let _ = events.send(event);
+        }
+    }
+
+    fn is_poisoned(&self, task: &Task) -> bool {
+        match self.poison_threshold {
+            Some(threshold) => task.retries + 1 >= threshold,
+            None => false,
+        }
+    }
+
+    /// Execute tasks until a shutdown is requested through the `shutdown` handle.
     pub(crate) fn run_tasks(&mut self) -> Result<(), FangError> {
+        self.fetch_mode.check(self.serial_per_type)?;
+
         loop {
-            match self.queue.fetch_and_touch_task(self.task_type.clone()) {
-                Ok(Some(task)) => {
-                    let actual_task: Box<dyn Runnable> =
-                        serde_json::from_value(task.metadata.clone()).unwrap();
+            if self.shutdown.is_requested() {
+                return self.flush_state_updates();
+            }
+
+            if !self.fetch_allowed() {
+                self.sleep();
+                continue;
+            }
 
+            match self.fetch_task() {
+                Ok(Some(task)) => {
                     self.maybe_reset_sleep_period();
 
                     self.run(&task)?;
 
-                    // check if task is scheduled or not
-                    if let Some(CronPattern(_)) = actual_task.cron() {
-                        // program task
-                        self.queue.schedule_task(&*actual_task)?;
-                    }
+                    self.maybe_schedule_next_run(&task)?;
                 }
                 Ok(None) => {
+                    self.flush_state_updates()?;
+
                     self.sleep();
                 }
 
@@ -82,27 +490,75 @@ where
         }
     }
 
+    /// Execute up to `n` tasks and return the number of executed tasks.
+    /// It stops earlier if there are no tasks to execute or the `before_fetch` hook skips a fetch, instead of sleeping.
+    pub fn run_n(&self, n: usize) -> Result<usize, FangError> {
+        self.fetch_mode.check(self.serial_per_type)?;
+
+        let mut number_of_task_run = 0;
+
+        while number_of_task_run < n && self.fetch_allowed() {
+            match self.fetch_task()? {
+                Some(task) => {
+                    self.run(&task)?;
+                    number_of_task_run += 1;
+
+                    self.maybe_schedule_next_run(&task)?;
+                }
+                None => break,
+            }
+        }
+
+        self.flush_state_updates()?;
+
+        Ok(number_of_task_run)
+    }
+
+    /// Execute the next task and return it, `None` is returned if there are no tasks to execute
+    /// or the `before_fetch` hook skipped the fetch.
+    pub fn run_once(&self) -> Result<Option<Task>, FangError> {
+        self.fetch_mode.check(self.serial_per_type)?;
+
+        if !self.fetch_allowed() {
+            return Ok(None);
+        }
+
+        let task = match self.fetch_task()? {
+            Some(task) => task,
+            None => return Ok(None),
+        };
+
+        self.run(&task)?;
+        self.maybe_schedule_next_run(&task)?;
+        self.flush_state_updates()?;
+
+        Ok(Some(task))
+    }
+
     #[cfg(test)]
     pub fn run_tasks_until_none(&mut self) -> Result<u32, FangError> {
+        self.fetch_mode.check(self.serial_per_type)?;
+
         let mut number_of_task_run = 0u32;
 
         loop {
-            match self.queue.fetch_and_touch_task(self.task_type.clone()) {
-                Ok(Some(task)) => {
-                    let actual_task: Box<dyn Runnable> =
-                        serde_json::from_value(task.metadata.clone()).unwrap();
+            if !self.fetch_allowed() {
+                self.sleep();
+                continue;
+            }
 
+            match self.fetch_task() {
+                Ok(Some(task)) => {
                     self.maybe_reset_sleep_period();
 
                     self.run(&task)?;
                     number_of_task_run += 1;
-                    // check if task is scheduled or not
-                    if let Some(CronPattern(_)) = actual_task.cron() {
-                        // program task
-                        self.queue.schedule_task(&*actual_task)?;
-                    }
+
+                    self.maybe_schedule_next_run(&task)?;
                 }
                 Ok(None) => {
+                    self.flush_state_updates()?;
+
                     return Ok(number_of_task_run);
                 }
                 Err(error) => {
@@ -114,6 +570,77 @@ where
         }
     }
 
+    /// Schedule the next run of a periodic task. Tasks that can not be decoded are never periodic,
+    /// they are handled by the fallback handler or according to the `deserialization_policy`.
+    fn maybe_schedule_next_run(&self, task: &Task) -> Result<(), FangError> {
+        // the last look of the worker at the task
+        if self.shutdown.forget_requeued(task.id) {
+            return Ok(());
+        }
+
+        let actual_task: Box<dyn Runnable> = match self.queue.codec().decode(task) {
+            Ok(actual_task) => actual_task,
+            Err(_) => return Ok(()),
+        };
+
+        // check if task is scheduled or not
+        if let Some(CronPattern(_)) = actual_task.cron() {
+            // program task
+            self.queue.schedule_task(&*actual_task)?;
+        }
+
+        Ok(())
+    }
+
+    fn fetch_allowed(&self) -> bool {
+        match &self.before_fetch {
+            Some(before_fetch) => before_fetch().is_continue(),
+            None => true,
+        }
+    }
+
+    fn fetch_task(&self) -> Result<Option<Task>, QueueError> {
+        if !self.serial_per_type {
+            return self.fetch_next_task();
+        }
+
+        // the lock of the type is kept while there are tasks to execute and released when the worker is idle
+        let mut type_lock = self.type_lock.lock().unwrap();
+
+        if type_lock.is_none() {
+            *type_lock = self.queue.try_lock_task_type(&self.task_type)?;
+
+            if type_lock.is_none() {
+                return Ok(None);
+            }
+        }
+
+        let task = self.fetch_next_task();
+
+        if !matches!(task, Ok(Some(_))) {
+            *type_lock = None;
+        }
+
+        task
+    }
+
+    fn fetch_next_task(&self) -> Result<Option<Task>, QueueError> {
+        match self.fetch_mode {
+            FetchMode::Standard => self.queue.fetch_and_touch_task(self.task_type.clone()),
+            FetchMode::StrictOrder => self
+                .queue
+                .fetch_and_touch_task_in_order(self.task_type.clone()),
+            FetchMode::Random => self
+                .queue
+                .fetch_and_touch_random_task(self.task_type.clone()),
+            FetchMode::LowestPriorityFirst => self
+                .queue
+                .fetch_and_touch_lowest_priority_task(self.task_type.clone()),
+            FetchMode::LongestBacklogFirst => self.queue.fetch_and_touch_longest_backlog_task(),
+            FetchMode::Lease(lease) => self.queue.fetch_with_lease(&self.task_type, lease),
+        }
+    }
+
     pub fn maybe_reset_sleep_period(&mut self) {
         self.sleep_params.maybe_reset_sleep_period();
     }
@@ -121,19 +648,157 @@ where
     fn sleep(&mut self) {
         self.sleep_params.maybe_increase_sleep_period();
 
+        if self.listen {
+            match self.wait_for_task() {
+                Ok(true) => return,
+                Ok(false) => {}
+                Err(error) => {
+                    error!("Failed to listen for new tasks {:?}", error);
+
+                    self.listener = None;
+                }
+            }
+        }
+
         thread::sleep(self.sleep_params.sleep_period);
     }
 
-    fn finalize_task(&self, task: &Task, result: &Result<(), FangError>) -> Result<(), FangError> {
-        match self.retention_mode {
-            RetentionMode::KeepAll => {
-                match result {
-                    Ok(_) => self
-                        .queue
-                        .update_task_state(task, FangTaskState::Finished)?,
-                    Err(error) => self.queue.fail_task(task, &error.description)?,
-                };
+    /// Wait for a notification about a new task, but not longer than the sleep period.
+    /// Returns `false` if the queue doesn't support notifications.
+    fn wait_for_task(&mut self) -> Result<bool, QueueError> {
+        if self.listener.is_none() {
+            self.listener = self.queue.listen()?;
+        }
+
+        match self.listener.as_mut() {
+            Some(listener) => {
+                listener.wait(&self.task_type, self.sleep_params.sleep_period)?;
+
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    fn finish_task(&self, task: &Task, result: &serde_json::Value) -> Result<(), FangError> {
+        if !result.is_null() {
+            self.queue.finish_task_with_result(task, result.clone())?;
+
+            return Ok(());
+        }
+
+        match &self.batch_params {
+            Some(batch_params) => {
+                self.state_batch.lock().unwrap().finished.push(task.id);
+
+                self.maybe_flush_state_updates(batch_params)
+            }
+            None => {
+                self.queue
+                    .update_task_state(task, FangTaskState::Finished)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    fn fail_task(&self, task: &Task, error: &str) -> Result<(), FangError> {
+        match &self.batch_params {
+            Some(batch_params) => {
+                self.state_batch
+                    .lock()
+                    .unwrap()
+                    .failed
+                    .push((task.id, error.to_string()));
+
+                self.maybe_flush_state_updates(batch_params)
             }
+            None => {
+                self.queue.fail_task(task, error)?;
+
+                Ok(())
+            }
+        }
+    }
+
+    fn maybe_flush_state_updates(&self, batch_params: &BatchParams) -> Result<(), FangError> {
+        let flush = {
+            let mut state_batch = self.state_batch.lock().unwrap();
+            let started_at = *state_batch.started_at.get_or_insert_with(Instant::now);
+
+            state_batch.len() >= batch_params.max_tasks
+                || started_at.elapsed() >= batch_params.max_delay
+        };
+
+        if flush {
+            self.flush_state_updates()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the states of the tasks buffered because of `batch_params`.
+    /// The finished tasks are updated in one statement, the failed tasks in one statement per error message.
+    pub fn flush_state_updates(&self) -> Result<(), FangError> {
+        let state_batch = std::mem::take(&mut *self.state_batch.lock().unwrap());
+
+        if !state_batch.finished.is_empty() {
+            self.queue.finish_tasks(&state_batch.finished)?;
+        }
+
+        let mut failed: BTreeMap<String, Vec<Uuid>> = BTreeMap::new();
+
+        for (id, error) in state_batch.failed {
+            failed.entry(error).or_default().push(id);
+        }
+
+        for (error, ids) in failed {
+            self.queue.fail_tasks(&ids, &error)?;
+        }
+
+        Ok(())
+    }
+
+    /// POST the failed task to the `on_failure_webhook`. Errors are only logged,
+    /// they don't affect the state of the task.
+    #[cfg(feature = "reqwest")]
+    fn notify_failure_webhook(&self, task: &Task, error: &str) {
+        let url = match &self.on_failure_webhook {
+            Some(url) => url.clone(),
+            None => return,
+        };
+
+        let failed_task = Task {
+            state: FangTaskState::Failed,
+            error_message: Some(error.to_string()),
+            ..task.clone()
+        };
+
+        let response = self
+            .webhook_client
+            .post(url)
+            .json(&failed_task)
+            .send()
+            .and_then(|response| response.error_for_status());
+
+        if let Err(error) = response {
+            error!(
+                "Failed to send task {} to the failure webhook {:?}",
+                task.id, error
+            );
+        }
+    }
+
+    fn finalize_task(
+        &self,
+        task: &Task,
+        result: &Result<serde_json::Value, FangError>,
+    ) -> Result<(), FangError> {
+        match self.retention_mode {
+            RetentionMode::KeepAll => match result {
+                Ok(value) => self.finish_task(task, value)?,
+                Err(error) => self.fail_task(task, &error.description)?,
+            },
 
             RetentionMode::RemoveAll => {
                 self.queue.remove_task(task.id)?;
@@ -144,27 +809,78 @@ where
                     self.queue.remove_task(task.id)?;
                 }
                 Err(error) => {
-                    self.queue.fail_task(task, &error.description)?;
+                    self.fail_task(task, &error.description)?;
                 }
             },
         }
+
+        match result {
+            Ok(_) => self.send_event(WorkerEvent::Finished(task.id)),
+            Err(_) => self.send_event(WorkerEvent::Failed(task.id)),
+        }
+
+        #[cfg(feature = "reqwest")]
+        if let Err(error) = result {
+            self.notify_failure_webhook(task, &error.description);
+        }
+
+        #[cfg(feature = "tracing")]
+        match result {
+            Ok(_) => tracing::info!("task finished"),
+            Err(error) => tracing::error!(error = %error.description, "task failed"),
+        }
+
         Ok(())
     }
 }
 
+impl Worker<Queue> {
+    /// The test mode of workers: execute the next task like `run_once` in a transaction that is rolled back
+    /// afterwards, see `Queue::in_test_transaction`. `inspect` is called with the executed task before the rollback,
+    /// so tests can observe the effects of the iteration without committing them.
+    pub fn run_once_in_test_mode<T>(
+        &self,
+        inspect: impl FnOnce(&Queue, Option<&Task>) -> T,
+    ) -> Result<T, FangError> {
+        self.queue.in_test_transaction(|| {
+            let task = self.run_once()?;
+
+            Ok(inspect(&self.queue, task.as_ref()))
+        })
+    }
+}
+
 #[cfg(test)]
 mod worker_tests {
+    use super::DeserializationPolicy;
+    use super::FetchMode;
     use super::RetentionMode;
     use super::Runnable;
+    use super::Shutdown;
     use super::Worker;
+    use super::WorkerEvent;
+    use crate::queue::IsolationLevel;
+    use crate::queue::NewTask;
     use crate::queue::Queue;
     use crate::queue::Queueable;
     use crate::typetag;
+    use crate::BatchParams;
     use crate::FangError;
     use crate::FangTaskState;
+    use crate::RetryJitter;
     use crate::Scheduled;
+    use crate::SleepParams;
+    use crate::Task;
     use chrono::Utc;
     use serde::{Deserialize, Serialize};
+    use std::ops::ControlFlow;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::time::Duration;
+    use std::time::Instant;
 
     #[derive(Serialize, Deserialize)]
     struct WorkerTaskTest {
@@ -184,6 +900,38 @@ mod worker_tests {
         }
     }
 
+    #[derive(Serialize, Deserialize)]
+    struct UntypedTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for UntypedTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ResultTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for ResultTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn run_with_result(&self, _queue: &dyn Queueable) -> Result<serde_json::Value, FangError> {
+            Ok(serde_json::json!({"square": self.number * self.number}))
+        }
+
+        fn task_type(&self) -> String {
+            "result_task".to_string()
+        }
+    }
+
     #[derive(Serialize, Deserialize)]
     struct FailedTask {
         pub number: u16,
@@ -281,29 +1029,1215 @@ mod worker_tests {
         }
     }
 
-    // Worker tests has to commit because the worker operations commits
+    #[cfg(feature = "cbor")]
     #[test]
-    #[ignore]
-    fn executes_and_finishes_task() {
+    fn executes_task_stored_with_cbor_codec() {
         let task = WorkerTaskTest { number: 10 };
 
-        let pool = Queue::connection_pool(5);
-
-        let queue = Queue::builder().connection_pool(pool).build();
+        let queue = Queue {
+            codec: crate::MetadataCodec::Cbor,
+            ..Queue::test()
+        };
 
         let worker = Worker::<Queue>::builder()
             .queue(queue)
             .retention_mode(RetentionMode::KeepAll)
             .task_type(task.task_type())
             .build();
-        let mut pooled_connection = worker.queue.connection_pool.get().unwrap();
 
-        let task = Queue::insert_query(&mut pooled_connection, &task, Utc::now()).unwrap();
+        let task = worker.queue.insert_task(&task).unwrap();
 
-        assert_eq!(FangTaskState::New, task.state);
+        assert_eq!(serde_json::Value::Null, task.metadata);
+        assert!(task.binary_metadata.is_some());
 
-        // this operation commits and thats why need to commit this test
-        worker.run(&task).unwrap();
+        let fetched_task = worker
+            .queue
+            .fetch_and_touch_task(task.task_type.clone())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(task.id, fetched_task.id);
+
+        worker.run(&fetched_task).unwrap();
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+
+        assert_eq!(FangTaskState::Finished, found_task.state);
+    }
+
+    #[test]
+    fn fetches_from_the_largest_backlog_in_longest_backlog_first_mode() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .fetch_mode(FetchMode::LongestBacklogFirst)
+            .build();
+
+        worker
+            .queue
+            .insert_task(&WorkerTaskTest { number: 10 })
+            .unwrap();
+        worker.queue.insert_task(&TaskType1 {}).unwrap();
+        worker.queue.insert_task(&TaskType1 {}).unwrap();
+
+        let task = worker.run_once().unwrap().unwrap();
+
+        assert_eq!("type1", task.task_type);
+
+        let serial_worker = Worker::<Queue>::builder()
+            .queue(worker.queue.clone())
+            .task_type("worker_task")
+            .fetch_mode(FetchMode::LongestBacklogFirst)
+            .serial_per_type(true)
+            .build();
+
+        assert!(serial_worker.run_once().is_err());
+    }
+
+    #[test]
+    fn sends_events_of_processed_tasks() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .events(sender)
+            .build();
+
+        let finished = worker
+            .queue
+            .insert_task(&WorkerTaskTest { number: 10 })
+            .unwrap();
+        let retried = worker.queue.insert_task(&RetryTask { number: 11 }).unwrap();
+        let failed = worker
+            .queue
+            .insert_task(&FailedTask { number: 12 })
+            .unwrap();
+
+        for task in [&finished, &retried, &failed] {
+            worker.run(task).unwrap();
+        }
+
+        assert_eq!(
+            vec![
+                WorkerEvent::Fetched(finished.id),
+                WorkerEvent::Finished(finished.id),
+                WorkerEvent::Fetched(retried.id),
+                WorkerEvent::Retried(retried.id),
+                WorkerEvent::Fetched(failed.id),
+                WorkerEvent::Failed(failed.id),
+            ],
+            receiver.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn stores_the_result_of_finished_task() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("result_task")
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&ResultTask { number: 12 })
+            .unwrap();
+        assert_eq!(None, task.result);
+
+        worker.run_tasks_until_none().unwrap();
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+
+        assert_eq!(FangTaskState::Finished, found_task.state);
+        assert_eq!(Some(serde_json::json!({"square": 144})), found_task.result);
+    }
+
+    #[test]
+    fn executes_untyped_task_enqueued_with_default_task_type() {
+        let queue = Queue {
+            default_task_type: "emails".to_string(),
+            ..Queue::test()
+        };
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(queue)
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("emails")
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&UntypedTask { number: 10 })
+            .unwrap();
+
+        assert_eq!("emails", task.task_type);
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Finished, found_task.state);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct ListenTask {}
+
+    #[typetag::serde]
+    impl Runnable for ListenTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "listen_task".to_string()
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct JitterTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for JitterTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Err(FangError {
+                description: "downstream is not available".to_string(),
+            })
+        }
+
+        fn backoff(&self, _attempt: u32) -> u32 {
+            3600
+        }
+
+        fn task_type(&self) -> String {
+            "jitter_task".to_string()
+        }
+    }
+
+    #[test]
+    fn spreads_retries_of_tasks_failed_at_the_same_time() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("jitter_task")
+            .retry_jitter(RetryJitter::Full)
+            .build();
+
+        let task1 = worker.queue.insert_task(&JitterTask { number: 1 }).unwrap();
+        let task2 = worker.queue.insert_task(&JitterTask { number: 2 }).unwrap();
+
+        let failed_at = Utc::now();
+
+        worker.run(&task1).unwrap();
+        worker.run(&task2).unwrap();
+
+        let retried_task1 = worker.queue.find_task_by_id(task1.id).unwrap();
+        let retried_task2 = worker.queue.find_task_by_id(task2.id).unwrap();
+
+        assert_eq!(FangTaskState::Retried, retried_task1.state);
+        assert_eq!(FangTaskState::Retried, retried_task2.state);
+        assert_ne!(retried_task1.scheduled_at, retried_task2.scheduled_at);
+
+        for task in [retried_task1, retried_task2] {
+            assert!(task.scheduled_at >= failed_at);
+            assert!(task.scheduled_at <= Utc::now() + chrono::Duration::seconds(3600));
+        }
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct RetryBudgetTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for RetryBudgetTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Err(FangError {
+                description: "failed".to_string(),
+            })
+        }
+
+        fn max_retries(&self) -> i32 {
+            0
+        }
+
+        fn backoff(&self, _attempt: u32) -> u32 {
+            0
+        }
+
+        fn task_type(&self) -> String {
+            "retry_budget".to_string()
+        }
+    }
+
+    #[test]
+    fn fails_tasks_at_their_own_max_retries() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("retry_budget")
+            .build();
+
+        let task1 = worker
+            .queue
+            .insert_new_task(
+                &NewTask::from_runnable(&RetryBudgetTask { number: 1 })
+                    .unwrap()
+                    .with_max_retries(1),
+            )
+            .unwrap();
+        let task2 = worker
+            .queue
+            .insert_new_task(
+                &NewTask::from_runnable(&RetryBudgetTask { number: 2 })
+                    .unwrap()
+                    .with_max_retries(3),
+            )
+            .unwrap();
+
+        assert_eq!(6, worker.run_tasks_until_none().unwrap());
+
+        let failed_task1 = worker.queue.find_task_by_id(task1.id).unwrap();
+        assert_eq!(FangTaskState::Failed, failed_task1.state);
+        assert_eq!(1, failed_task1.retries);
+
+        let failed_task2 = worker.queue.find_task_by_id(task2.id).unwrap();
+        assert_eq!(FangTaskState::Failed, failed_task2.state);
+        assert_eq!(3, failed_task2.retries);
+    }
+
+    #[test]
+    fn batches_updates_of_task_states() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .batch_params(
+                BatchParams::builder()
+                    .max_tasks(10)
+                    .max_delay(Duration::from_secs(3600))
+                    .build(),
+            )
+            .build();
+
+        let mut tasks = Vec::new();
+
+        for number in 1..=3 {
+            worker
+                .queue
+                .insert_task(&WorkerTaskTest { number })
+                .unwrap();
+
+            tasks.push(
+                worker
+                    .queue
+                    .fetch_and_touch_task("worker_task".to_string())
+                    .unwrap()
+                    .unwrap(),
+            );
+        }
+
+        worker.queue.insert_task(&FailedTask { number: 1 }).unwrap();
+
+        tasks.push(
+            worker
+                .queue
+                .fetch_and_touch_task("F_task".to_string())
+                .unwrap()
+                .unwrap(),
+        );
+
+        for task in &tasks {
+            worker.run(task).unwrap();
+        }
+
+        for task in &tasks {
+            let task = worker.queue.find_task_by_id(task.id).unwrap();
+            assert_eq!(FangTaskState::InProgress, task.state);
+        }
+
+        worker.flush_state_updates().unwrap();
+
+        for task in &tasks[..3] {
+            let task = worker.queue.find_task_by_id(task.id).unwrap();
+            assert_eq!(FangTaskState::Finished, task.state);
+        }
+
+        let failed_task = worker.queue.find_task_by_id(tasks[3].id).unwrap();
+        assert_eq!(FangTaskState::Failed, failed_task.state);
+        assert_eq!(
+            "the number is 1".to_string(),
+            failed_task.error_message.unwrap()
+        );
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn posts_failed_tasks_to_webhook() {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/failures", listener.local_addr().unwrap());
+
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+
+            let mut content_length = 0;
+
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+
+                if header == "\r\n" {
+                    break;
+                }
+
+                if let Some(value) = header.to_lowercase().strip_prefix("content-length:") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+
+            (request_line, body)
+        });
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("F_task")
+            .on_failure_webhook(reqwest::Url::parse(&url).unwrap())
+            .build();
+
+        let task = worker.queue.insert_task(&FailedTask { number: 7 }).unwrap();
+
+        worker.run_tasks_until_none().unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        let posted_task: Task = serde_json::from_slice(&body).unwrap();
+
+        assert!(request_line.starts_with("POST /failures "));
+        assert_eq!(task.id, posted_task.id);
+        assert_eq!(FangTaskState::Failed, posted_task.state);
+        assert_eq!(
+            Some("the number is 7".to_string()),
+            posted_task.error_message
+        );
+
+        let failed_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Failed, failed_task.state);
+    }
+
+    #[cfg(feature = "reqwest")]
+    #[test]
+    fn ignores_failure_webhook_errors() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/failures", listener.local_addr().unwrap());
+        drop(listener);
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("F_task")
+            .on_failure_webhook(reqwest::Url::parse(&url).unwrap())
+            .build();
+
+        let task = worker.queue.insert_task(&FailedTask { number: 8 }).unwrap();
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let failed_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Failed, failed_task.state);
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct RateLimitedTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for RateLimitedTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            Err(FangError {
+                description: "rate limited, retry after 30 seconds".to_string(),
+            })
+        }
+
+        fn retry_after(&self, error: &FangError) -> Option<u32> {
+            error
+                .description
+                .strip_prefix("rate limited, retry after ")?
+                .strip_suffix(" seconds")?
+                .parse()
+                .ok()
+        }
+
+        fn task_type(&self) -> String {
+            "rate_limited".to_string()
+        }
+    }
+
+    #[test]
+    fn retries_after_the_delay_of_the_error() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .retry_jitter(RetryJitter::Full)
+            .task_type("rate_limited")
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&RateLimitedTask { number: 1 })
+            .unwrap();
+
+        let before_run = Utc::now();
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let retried_task = worker.queue.find_task_by_id(task.id).unwrap();
+        let delay = retried_task.scheduled_at - before_run;
+
+        assert_eq!(FangTaskState::Retried, retried_task.state);
+        assert!(delay >= chrono::Duration::seconds(30));
+        assert!(delay < chrono::Duration::seconds(31));
+    }
+
+    #[test]
+    fn run_n_executes_at_most_n_tasks() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .build();
+
+        for number in 1..=5 {
+            worker
+                .queue
+                .insert_task(&WorkerTaskTest { number })
+                .unwrap();
+        }
+
+        assert_eq!(3, worker.run_n(3).unwrap());
+        assert_eq!(
+            2,
+            worker
+                .queue
+                .count_runnable_tasks("worker_task".to_string())
+                .unwrap()
+        );
+
+        assert_eq!(2, worker.run_n(3).unwrap());
+        assert_eq!(0, worker.run_n(3).unwrap());
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct PoisonTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for PoisonTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            panic!("poison");
+        }
+
+        fn max_retries(&self) -> i32 {
+            100
+        }
+
+        fn backoff(&self, _attempt: u32) -> u32 {
+            0
+        }
+
+        fn task_type(&self) -> String {
+            "poison".to_string()
+        }
+    }
+
+    #[test]
+    fn quarantines_tasks_past_poison_threshold() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("poison")
+            .poison_threshold(3)
+            .build();
+
+        let task = worker.queue.insert_task(&PoisonTask { number: 1 }).unwrap();
+
+        assert_eq!(3, worker.run_tasks_until_none().unwrap());
+
+        let quarantined_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Quarantined, quarantined_task.state);
+        assert_eq!(2, quarantined_task.retries);
+        assert_eq!(
+            "The task panicked: poison",
+            quarantined_task.error_message.unwrap()
+        );
+
+        assert_eq!(
+            None,
+            worker
+                .queue
+                .fetch_and_touch_task("poison".to_string())
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn runs_fallback_handler_for_unknown_tasks() {
+        let handled_tasks = Arc::new(Mutex::new(Vec::new()));
+        let handled = handled_tasks.clone();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("unknown")
+            .fallback(Arc::new(move |_queue: &dyn Queueable, task: &Task| {
+                handled.lock().unwrap().push(task.id);
+
+                Ok(())
+            }))
+            .build();
+
+        let task = worker
+            .queue
+            .insert_new_task(
+                &NewTask::builder()
+                    .metadata(serde_json::json!({"type": "NotRegisteredTask"}))
+                    .task_type("unknown")
+                    .uniq_hash(None)
+                    .scheduled_at(Utc::now())
+                    .binary_metadata(None)
+                    .build(),
+            )
+            .unwrap();
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+        assert_eq!(vec![task.id], *handled_tasks.lock().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Finished, found_task.state);
+    }
+
+    fn insert_undecodable_task(queue: &Queue) -> Task {
+        queue
+            .insert_new_task(
+                &NewTask::builder()
+                    .metadata(serde_json::json!({"type": "NotRegisteredTask"}))
+                    .task_type("unknown")
+                    .uniq_hash(None)
+                    .scheduled_at(Utc::now())
+                    .binary_metadata(None)
+                    .build(),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn fails_undecodable_tasks_by_default() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("unknown")
+            .build();
+
+        let task = insert_undecodable_task(&worker.queue);
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Failed, found_task.state);
+        assert!(found_task
+            .error_message
+            .unwrap()
+            .contains("NotRegisteredTask"));
+    }
+
+    #[test]
+    fn skips_undecodable_tasks_with_skip_policy() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("unknown")
+            .deserialization_policy(DeserializationPolicy::Skip)
+            .build();
+
+        let task = insert_undecodable_task(&worker.queue);
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Retried, found_task.state);
+        assert_eq!(1, found_task.retries);
+        assert!(found_task.scheduled_at > Utc::now());
+        assert!(found_task.error_message.is_some());
+        assert_eq!(0, worker.run_tasks_until_none().unwrap());
+    }
+
+    #[test]
+    fn releases_the_lease_of_undecodable_tasks() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("unknown")
+            .fetch_mode(FetchMode::Lease(chrono::Duration::minutes(5)))
+            .build();
+
+        let task = insert_undecodable_task(&worker.queue);
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Failed, found_task.state);
+        assert_eq!(None, found_task.locked_until);
+    }
+
+    #[test]
+    fn leaves_undecodable_tasks_whose_lease_expired_to_be_requeued() {
+        // the lease expires as soon as the task is fetched
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("unknown")
+            .fetch_mode(FetchMode::Lease(chrono::Duration::zero()))
+            .build();
+
+        let task = insert_undecodable_task(&worker.queue);
+
+        worker.run_once().unwrap();
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::InProgress, found_task.state);
+        assert_eq!(None, found_task.error_message);
+        assert_eq!(1, worker.queue.requeue_expired_leases().unwrap());
+    }
+
+    #[test]
+    fn dead_letters_undecodable_tasks_with_dead_letter_policy() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::RemoveAll)
+            .task_type("unknown")
+            .deserialization_policy(DeserializationPolicy::DeadLetter)
+            .build();
+
+        let task = insert_undecodable_task(&worker.queue);
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Quarantined, found_task.state);
+        assert!(found_task.error_message.is_some());
+        assert_eq!(0, worker.run_tasks_until_none().unwrap());
+    }
+
+    #[test]
+    fn run_once_in_test_mode_rolls_back_the_iteration() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&WorkerTaskTest { number: 10 })
+            .unwrap();
+
+        let state_inside = worker
+            .run_once_in_test_mode(|queue, executed_task| {
+                assert_eq!(Some(task.id), executed_task.map(|task| task.id));
+
+                queue.find_task_by_id(task.id).unwrap().state
+            })
+            .unwrap();
+
+        assert_eq!(FangTaskState::Finished, state_inside);
+        assert_eq!(
+            FangTaskState::New,
+            worker.queue.find_task_by_id(task.id).unwrap().state
+        );
+
+        let pooled_queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(2))
+            .build();
+        assert!(pooled_queue.in_test_transaction(|| Ok(())).is_err());
+    }
+
+    #[test]
+    fn in_test_transaction_rolls_back_on_a_pool_of_one_connection() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(1))
+            .build();
+
+        let task = queue
+            .in_test_transaction(|| {
+                let task = queue.insert_task(&WorkerTaskTest { number: 10 })?;
+                assert!(queue.find_task_by_id(task.id).is_some());
+
+                Ok(task)
+            })
+            .unwrap();
+
+        assert!(queue.find_task_by_id(task.id).is_none());
+
+        let worker = Worker::<Queue>::builder()
+            .queue(queue)
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .build();
+
+        let executed = worker
+            .queue
+            .in_test_transaction(|| {
+                let task = worker.queue.insert_task(&WorkerTaskTest { number: 11 })?;
+
+                worker.run_once_in_test_mode(|queue, executed_task| {
+                    (
+                        executed_task.map(|task| task.id),
+                        queue.find_task_by_id(task.id).unwrap().state,
+                    )
+                })
+            })
+            .unwrap();
+
+        assert!(executed.0.is_some());
+        assert_eq!(FangTaskState::Finished, executed.1);
+        assert!(worker.queue.find_task_by_id(executed.0.unwrap()).is_none());
+    }
+
+    #[test]
+    fn before_fetch_hook_can_skip_fetches() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .sleep_params(SleepParams {
+                sleep_period: Duration::from_millis(1),
+                max_sleep_period: Duration::from_millis(1),
+                min_sleep_period: Duration::from_millis(1),
+                sleep_step: Duration::from_millis(1),
+            })
+            .before_fetch(Arc::new(move || {
+                if hook_calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            }))
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&WorkerTaskTest { number: 10 })
+            .unwrap();
+
+        assert_eq!(0, worker.run_n(1).unwrap());
+        assert_eq!(
+            FangTaskState::New,
+            worker.queue.find_task_by_id(task.id).unwrap().state
+        );
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+        assert_eq!(
+            FangTaskState::Finished,
+            worker.queue.find_task_by_id(task.id).unwrap().state
+        );
+        assert_eq!(3, calls.load(Ordering::SeqCst));
+    }
+
+    static LEASE_PROBES: Mutex<Vec<Result<FangTaskState, String>>> = Mutex::new(Vec::new());
+
+    #[derive(Serialize, Deserialize)]
+    struct LeaseProbeTask {}
+
+    #[typetag::serde]
+    impl Runnable for LeaseProbeTask {
+        // locks the row of the task from another connection, it fails if the fetch transaction is still open
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            use crate::postgres_schema::fang_tasks;
+            use diesel::prelude::*;
+
+            let mut connection = Queue::connection_pool(1).get().unwrap();
+
+            let probe = fang_tasks::table
+                .filter(fang_tasks::task_type.eq("lease_probe"))
+                .select(fang_tasks::state)
+                .for_update()
+                .no_wait()
+                .get_result::<FangTaskState>(&mut connection)
+                .map_err(|error| error.to_string());
+
+            LEASE_PROBES.lock().unwrap().push(probe);
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "lease_probe".to_string()
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn releases_the_fetch_lock_while_a_leased_task_runs() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(queue)
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("lease_probe")
+            .fetch_mode(FetchMode::Lease(chrono::Duration::minutes(5)))
+            .build();
+
+        let task = worker.queue.insert_task(&LeaseProbeTask {}).unwrap();
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+        assert_eq!(
+            vec![Ok(FangTaskState::InProgress)],
+            *LEASE_PROBES.lock().unwrap()
+        );
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Finished, found_task.state);
+
+        worker.queue.remove_tasks_of_type("lease_probe").unwrap();
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct OutlivedLeaseTask {}
+
+    #[typetag::serde]
+    impl Runnable for OutlivedLeaseTask {
+        // the lease expires while the task runs and the task is requeued
+        fn run(&self, queue: &dyn Queueable) -> Result<(), FangError> {
+            std::thread::sleep(Duration::from_millis(10));
+
+            queue.requeue_expired_leases().unwrap();
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "outlived_lease".to_string()
+        }
+    }
+
+    #[test]
+    fn discards_the_outcome_of_a_task_whose_lease_expired() {
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("outlived_lease")
+            .fetch_mode(FetchMode::Lease(chrono::Duration::milliseconds(1)))
+            .build();
+
+        let task = worker.queue.insert_task(&OutlivedLeaseTask {}).unwrap();
+
+        worker.run_once().unwrap();
+
+        let found_task = worker.queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::New, found_task.state);
+        assert_eq!(None, found_task.locked_until);
+    }
+
+    static SERIAL_EXECUTIONS: Mutex<Vec<(String, Instant, Instant)>> = Mutex::new(Vec::new());
+
+    #[derive(Serialize, Deserialize)]
+    struct SerialTask {
+        pub task_type: String,
+    }
+
+    #[typetag::serde]
+    impl Runnable for SerialTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            let started_at = Instant::now();
+            std::thread::sleep(Duration::from_millis(300));
+
+            SERIAL_EXECUTIONS.lock().unwrap().push((
+                self.task_type.clone(),
+                started_at,
+                Instant::now(),
+            ));
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            self.task_type.clone()
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn serializes_workers_of_the_same_type_in_serial_per_type_mode() {
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(8))
+            .build();
+
+        for task_type in ["serial_a", "serial_a", "serial_b"] {
+            queue
+                .insert_task(&SerialTask {
+                    task_type: task_type.to_string(),
+                })
+                .unwrap();
+        }
+
+        let barrier = Arc::new(std::sync::Barrier::new(3));
+
+        let handles: Vec<_> = ["serial_a", "serial_a", "serial_b"]
+            .into_iter()
+            .map(|task_type| {
+                let queue = queue.clone();
+                let barrier = barrier.clone();
+
+                std::thread::spawn(move || {
+                    let mut worker = Worker::<Queue>::builder()
+                        .queue(queue)
+                        .retention_mode(RetentionMode::KeepAll)
+                        .task_type(task_type)
+                        .serial_per_type(true)
+                        .build();
+
+                    barrier.wait();
+
+                    worker.run_tasks_until_none().unwrap()
+                })
+            })
+            .collect();
+
+        let executed: u32 = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum();
+        assert_eq!(3, executed);
+
+        let executions = SERIAL_EXECUTIONS.lock().unwrap();
+        let of_type = |task_type: &str| -> Vec<(Instant, Instant)> {
+            executions
+                .iter()
+                .filter(|(execution_type, _, _)| execution_type == task_type)
+                .map(|(_, started_at, finished_at)| (*started_at, *finished_at))
+                .collect()
+        };
+        let overlap = |a: (Instant, Instant), b: (Instant, Instant)| a.0 < b.1 && b.0 < a.1;
+
+        let serial_a = of_type("serial_a");
+        let serial_b = of_type("serial_b");
+
+        assert_eq!(2, serial_a.len());
+        assert!(!overlap(serial_a[0], serial_a[1]));
+        assert!(serial_a.iter().any(|a| overlap(*a, serial_b[0])));
+
+        queue.remove_tasks_of_type("serial_a").unwrap();
+        queue.remove_tasks_of_type("serial_b").unwrap();
+    }
+
+    static STRICT_ORDER_EXECUTIONS: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+
+    #[derive(Serialize, Deserialize)]
+    struct StrictOrderTask {
+        pub number: u16,
+    }
+
+    #[typetag::serde]
+    impl Runnable for StrictOrderTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            STRICT_ORDER_EXECUTIONS.lock().unwrap().push(self.number);
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "strict_order".to_string()
+        }
+    }
+
+    #[test]
+    fn executes_tasks_in_insertion_order_in_strict_order_mode() {
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("strict_order")
+            .fetch_mode(FetchMode::StrictOrder)
+            .build();
+
+        let numbers = [3, 1, 2, 5, 4];
+
+        for number in numbers {
+            worker
+                .queue
+                .insert_task(&StrictOrderTask { number })
+                .unwrap();
+        }
+
+        assert_eq!(5, worker.run_tasks_until_none().unwrap());
+        assert_eq!(numbers.to_vec(), *STRICT_ORDER_EXECUTIONS.lock().unwrap());
+    }
+
+    #[test]
+    fn sleep_period_grows_while_idle_and_resets_on_work() {
+        let sleep_params = SleepParams {
+            sleep_period: Duration::from_millis(2),
+            max_sleep_period: Duration::from_millis(9),
+            min_sleep_period: Duration::from_millis(2),
+            sleep_step: Duration::from_millis(3),
+        };
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("type1")
+            .sleep_params(sleep_params)
+            .build();
+
+        let mut periods = Vec::new();
+
+        for _ in 0..4 {
+            assert_eq!(
+                None,
+                worker
+                    .queue
+                    .fetch_and_touch_task("type1".to_string())
+                    .unwrap()
+            );
+
+            worker.sleep();
+            periods.push(worker.sleep_params.sleep_period);
+        }
+
+        assert_eq!(
+            vec![
+                Duration::from_millis(5),
+                Duration::from_millis(8),
+                Duration::from_millis(9),
+                Duration::from_millis(9)
+            ],
+            periods
+        );
+
+        worker.queue.insert_task(&TaskType1 {}).unwrap();
+
+        assert_eq!(1, worker.run_tasks_until_none().unwrap());
+        assert_eq!(Duration::from_millis(2), worker.sleep_params.sleep_period);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn creates_a_span_with_the_task_id() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        #[derive(Default)]
+        struct TaskIdVisitor(Option<String>);
+
+        impl Visit for TaskIdVisitor {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "task_id" {
+                    self.0 = Some(format!("{value:?}"));
+                }
+            }
+        }
+
+        #[derive(Clone, Default)]
+        struct SpanRecorder {
+            task_ids: Arc<Mutex<Vec<String>>>,
+            events: Arc<Mutex<usize>>,
+        }
+
+        impl Subscriber for SpanRecorder {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut visitor = TaskIdVisitor::default();
+                span.record(&mut visitor);
+
+                let mut task_ids = self.task_ids.lock().unwrap();
+                task_ids.extend(visitor.0);
+
+                Id::from_u64(task_ids.len() as u64 + 1)
+            }
+
+            fn record(&self, _span: &Id, _values: &Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+            fn event(&self, _event: &Event<'_>) {
+                *self.events.lock().unwrap() += 1;
+            }
+
+            fn enter(&self, _span: &Id) {}
+
+            fn exit(&self, _span: &Id) {}
+        }
+
+        let worker = Worker::<Queue>::builder()
+            .queue(Queue::test())
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type("worker_task")
+            .build();
+
+        let task = worker
+            .queue
+            .insert_task(&WorkerTaskTest { number: 10 })
+            .unwrap();
+
+        let recorder = SpanRecorder::default();
+
+        tracing::subscriber::with_default(recorder.clone(), || worker.run(&task).unwrap());
+
+        assert_eq!(
+            vec![task.id.to_string()],
+            *recorder.task_ids.lock().unwrap()
+        );
+        assert_eq!(1, *recorder.events.lock().unwrap());
+    }
+
+    // Worker tests has to commit because the worker operations commits
+    #[test]
+    #[ignore]
+    fn executes_and_finishes_task() {
+        let task = WorkerTaskTest { number: 10 };
+
+        let pool = Queue::connection_pool(5);
+
+        let queue = Queue::builder().connection_pool(pool).build();
+
+        let worker = Worker::<Queue>::builder()
+            .queue(queue)
+            .retention_mode(RetentionMode::KeepAll)
+            .task_type(task.task_type())
+            .build();
+        let mut pooled_connection = worker.queue.connection_pool.get().unwrap();
+
+        let task = Queue::insert_query(&mut pooled_connection, &task, Utc::now()).unwrap();
+
+        assert_eq!(FangTaskState::New, task.state);
+
+        // this operation commits and thats why need to commit this test
+        worker.run(&task).unwrap();
 
         let found_task = Queue::find_task_by_id_query(&mut pooled_connection, task.id).unwrap();
 
@@ -435,7 +2369,210 @@ mod worker_tests {
 
     #[test]
     #[ignore]
+    fn fetches_task_under_serializable_isolation() {
+        let task = TaskType1 {};
+
+        let pool = Queue::connection_pool(5);
+
+        let queue = Queue::builder()
+            .connection_pool(pool)
+            .isolation_level(IsolationLevel::Serializable)
+            .build();
+
+        let inserted_task = queue.insert_task(&task).unwrap();
+
+        let fetched_task = queue
+            .fetch_and_touch_task(task.task_type())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(inserted_task.id, fetched_task.id);
+        assert_eq!(FangTaskState::InProgress, fetched_task.state);
+
+        let found_task = queue.find_task_by_id(inserted_task.id).unwrap();
+        assert_eq!(FangTaskState::InProgress, found_task.state);
+
+        queue.remove_tasks_of_type(&task.task_type()).unwrap();
+    }
+
+    static INTERRUPTIBLE_TASK_RELEASED: AtomicBool = AtomicBool::new(false);
+
+    #[derive(Serialize, Deserialize)]
+    struct InterruptibleTask {}
+
+    #[typetag::serde]
+    impl Runnable for InterruptibleTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            while !INTERRUPTIBLE_TASK_RELEASED.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "interruptible_task".to_string()
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn shutdown_records_interrupted_tasks() {
+        use crate::postgres_schema::fang_interrupted;
+        use diesel::prelude::*;
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+        let shutdown = Shutdown::new();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(queue.clone())
+            .task_type("interruptible_task")
+            .retention_mode(RetentionMode::KeepAll)
+            .shutdown(shutdown.clone())
+            .build();
+
+        let task = queue.insert_task(&InterruptibleTask {}).unwrap();
+
+        let worker_thread = std::thread::spawn(move || worker.run_tasks());
+
+        while shutdown.in_flight().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let interrupted = shutdown
+            .shutdown(&queue, Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(vec![task.id], interrupted);
+
+        let logged = queue.interrupted_tasks().unwrap();
+        assert!(logged
+            .iter()
+            .any(|interrupted| interrupted.task_id == task.id
+                && interrupted.task_type == "interruptible_task"));
+
+        INTERRUPTIBLE_TASK_RELEASED.store(true, Ordering::SeqCst);
+        worker_thread.join().unwrap().unwrap();
+
+        assert!(shutdown.in_flight().is_empty());
+        assert_eq!(
+            FangTaskState::Finished,
+            queue.find_task_by_id(task.id).unwrap().state
+        );
+
+        diesel::delete(fang_interrupted::table.filter(fang_interrupted::task_id.eq(task.id)))
+            .execute(&mut queue.get_connection().unwrap())
+            .unwrap();
+        queue.remove_tasks_of_type("interruptible_task").unwrap();
+    }
+
+    static REQUEUED_TASK_RELEASED: AtomicBool = AtomicBool::new(false);
+
+    #[derive(Serialize, Deserialize)]
+    struct RequeuedTask {}
+
+    #[typetag::serde]
+    impl Runnable for RequeuedTask {
+        fn run(&self, _queue: &dyn Queueable) -> Result<(), FangError> {
+            while !REQUEUED_TASK_RELEASED.load(Ordering::SeqCst) {
+                std::thread::sleep(Duration::from_millis(10));
+            }
+
+            Ok(())
+        }
+
+        fn task_type(&self) -> String {
+            "requeued_task".to_string()
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn shutdown_with_requeue_retries_interrupted_tasks() {
+        use crate::postgres_schema::fang_interrupted;
+        use diesel::prelude::*;
+
+        let queue = Queue::builder()
+            .connection_pool(Queue::connection_pool(5))
+            .build();
+        let shutdown = Shutdown::new().with_requeue();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(queue.clone())
+            .task_type("requeued_task")
+            .retention_mode(RetentionMode::KeepAll)
+            .shutdown(shutdown.clone())
+            .build();
+
+        let task = queue.insert_task(&RequeuedTask {}).unwrap();
+
+        let worker_thread = std::thread::spawn(move || worker.run_tasks());
+
+        while shutdown.in_flight().is_empty() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let interrupted = shutdown
+            .shutdown(&queue, Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(vec![task.id], interrupted);
+        assert_eq!(
+            FangTaskState::Retried,
+            queue.find_task_by_id(task.id).unwrap().state
+        );
+
+        REQUEUED_TASK_RELEASED.store(true, Ordering::SeqCst);
+        worker_thread.join().unwrap().unwrap();
+
+        assert_eq!(
+            FangTaskState::Retried,
+            queue.find_task_by_id(task.id).unwrap().state
+        );
+        assert!(!shutdown.is_requeued(task.id));
+
+        diesel::delete(fang_interrupted::table.filter(fang_interrupted::task_id.eq(task.id)))
+            .execute(&mut queue.get_connection().unwrap())
+            .unwrap();
+        queue.remove_tasks_of_type("requeued_task").unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn wakes_up_on_notification_about_new_task() {
+        let pool = Queue::connection_pool(5);
+
+        let queue = Queue::builder().connection_pool(pool).build();
+
+        let mut worker = Worker::<Queue>::builder()
+            .queue(queue.clone())
+            .task_type("listen_task")
+            .retention_mode(RetentionMode::KeepAll)
+            .sleep_params(SleepParams {
+                sleep_period: Duration::from_secs(30),
+                max_sleep_period: Duration::from_secs(30),
+                min_sleep_period: Duration::from_secs(30),
+                sleep_step: Duration::from_secs(0),
+            })
+            .listen(true)
+            .build();
+
+        std::thread::spawn(move || worker.run_tasks());
+
+        std::thread::sleep(Duration::from_millis(500));
 
+        let task = queue.insert_task(&ListenTask {}).unwrap();
+
+        std::thread::sleep(Duration::from_millis(1000));
+
+        let found_task = queue.find_task_by_id(task.id).unwrap();
+        assert_eq!(FangTaskState::Finished, found_task.state);
+
+        queue.remove_tasks_of_type("listen_task").unwrap();
+    }
+
+    #[test]
+    #[ignore]
     fn no_schedule_until_run() {
         let task = TaskScheduled {};
 
@@ -1,13 +1,24 @@
 use crate::queue::Queueable;
 use crate::runnable::COMMON_TYPE;
+use crate::worker::BeforeFetchHook;
+use crate::worker::DeserializationPolicy;
+use crate::worker::FallbackHandler;
+use crate::worker::FetchMode;
+use crate::worker::Shutdown;
 use crate::worker::Worker;
+use crate::worker::WorkerEvent;
+use crate::BatchParams;
 use crate::FangError;
 use crate::RetentionMode;
+use crate::RetryJitter;
 use crate::SleepParams;
 use log::error;
 use log::info;
+use std::sync::mpsc::Sender;
 use std::thread;
+use std::time::Duration;
 use typed_builder::TypedBuilder;
+use uuid::Uuid;
 
 #[derive(Clone, TypedBuilder)]
 pub struct WorkerPool<BQueue>
@@ -30,6 +41,49 @@ where
     /// The type of tasks that will be executed by `AsyncWorkerPool`.
     #[builder(setter(into), default=COMMON_TYPE.into())]
     pub task_type: String,
+    /// retry_jitter randomizes the delay before retrying failed tasks
+    #[builder(setter(into), default)]
+    pub retry_jitter: RetryJitter,
+    /// How the workers fetch the next task to execute, see `FetchMode`.
+    #[builder(setter(into), default)]
+    pub fetch_mode: FetchMode,
+    /// Process the tasks of the type of the pool by one worker at a time, while pools of other types run in parallel.
+    /// A worker takes the advisory lock of the type, see `Queueable::try_lock_task_type`, before fetching
+    /// and keeps it until there are no tasks to execute. Every worker holding the lock holds a connection of the pool.
+    /// It can't be combined with `FetchMode::LongestBacklogFirst`.
+    #[builder(setter(into), default)]
+    pub serial_per_type: bool,
+    /// Wake up idle workers as soon as a task is inserted instead of waiting for the end of the sleep period.
+    /// Every worker holds a connection of the pool to listen for notifications.
+    #[builder(setter(into), default)]
+    pub listen: bool,
+    /// Quarantine tasks after this number of consecutive failures, panics included,
+    /// instead of retrying them. See `FangTaskState::Quarantined`.
+    #[builder(setter(strip_option), default)]
+    pub poison_threshold: Option<i32>,
+    /// Buffer the states of executed tasks and write them in batched updates, see `BatchParams`.
+    #[builder(setter(into), default)]
+    pub batch_params: Option<BatchParams>,
+    /// POST failed tasks serialized as JSON to this URL.
+    /// Errors of the webhook call are logged and don't affect the state of tasks.
+    #[cfg(feature = "reqwest")]
+    #[builder(setter(into), default)]
+    pub on_failure_webhook: Option<reqwest::Url>,
+    /// A handler for the tasks that the workers can not decode, see `FallbackHandler`.
+    #[builder(setter(strip_option), default)]
+    pub fallback: Option<FallbackHandler>,
+    /// What the workers do with the tasks they can not decode if there is no `fallback`, see `DeserializationPolicy`.
+    #[builder(setter(into), default)]
+    pub deserialization_policy: DeserializationPolicy,
+    /// A hook the workers call before every fetch to throttle themselves, see `BeforeFetchHook`.
+    #[builder(setter(strip_option), default)]
+    pub before_fetch: Option<BeforeFetchHook>,
+    /// The workers of the pool send the outcomes of the tasks they process to this channel, see `WorkerEvent`.
+    #[builder(setter(strip_option), default)]
+    pub events: Option<Sender<WorkerEvent>>,
+    /// Stops the workers of the pool, see `WorkerPool::shutdown`.
+    #[builder(setter(into), default)]
+    pub shutdown: Shutdown,
 }
 
 #[derive(Clone, TypedBuilder)]
@@ -56,6 +110,8 @@ where
     /// Starts the configured number of workers
     /// This is necessary in order to execute tasks.
     pub fn start(&mut self) -> Result<(), FangError> {
+        self.fetch_mode.check(self.serial_per_type)?;
+
         for idx in 1..self.number_of_workers + 1 {
             let name = format!("worker_{}{idx}", self.task_type);
 
@@ -69,6 +125,12 @@ where
         }
         Ok(())
     }
+
+    /// Stop the workers of the pool gracefully, waiting up to `timeout` for the tasks they are executing.
+    /// The ids of the tasks that didn't finish in time are returned, see `Shutdown::shutdown`.
+    pub fn shutdown(&self, timeout: Duration) -> Result<Vec<Uuid>, FangError> {
+        self.shutdown.shutdown(&self.queue, timeout)
+    }
 }
 
 impl<BQueue> WorkerThread<BQueue>
@@ -90,8 +152,26 @@ where
                     .task_type(self.worker_pool.task_type.clone())
                     .retention_mode(self.worker_pool.retention_mode.clone())
                     .sleep_params(self.worker_pool.sleep_params.clone())
+                    .retry_jitter(self.worker_pool.retry_jitter.clone())
+                    .fetch_mode(self.worker_pool.fetch_mode)
+                    .serial_per_type(self.worker_pool.serial_per_type)
+                    .listen(self.worker_pool.listen)
+                    .batch_params(self.worker_pool.batch_params.clone())
+                    .deserialization_policy(self.worker_pool.deserialization_policy)
+                    .shutdown(self.worker_pool.shutdown.clone())
                     .build();
 
+                worker.poison_threshold = self.worker_pool.poison_threshold;
+
+                #[cfg(feature = "reqwest")]
+                {
+                    worker.on_failure_webhook = self.worker_pool.on_failure_webhook.clone();
+                }
+
+                worker.fallback = self.worker_pool.fallback.clone();
+                worker.before_fetch = self.worker_pool.before_fetch.clone();
+                worker.events = self.worker_pool.events.clone();
+
                 // Run worker
                 if let Err(error) = worker.run_tasks() {
                     error!(
@@ -111,6 +191,12 @@ where
     BQueue: Queueable + Clone + Sync + Send + 'static,
 {
     fn drop(&mut self) {
+        if self.worker_pool.shutdown.is_requested() {
+            info!("Worker {} stopped after a shutdown request", self.name);
+
+            return;
+        }
+
         self.restarts += 1;
 
         error!(
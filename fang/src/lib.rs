@@ -1,7 +1,7 @@
 #![doc = include_str!("../README.md")]
 
 #[cfg(feature = "blocking")]
-use diesel::{Identifiable, Queryable};
+use diesel::{Identifiable, Queryable, QueryableByName};
 use std::time::Duration;
 use thiserror::Error;
 use typed_builder::TypedBuilder;
@@ -52,6 +52,42 @@ pub enum RetentionMode {
     RemoveFinished,
 }
 
+/// Randomization of the delay before retrying a failed task,
+/// so tasks that failed at the same time are not retried at the same time.
+///
+/// The default mode is [`RetryJitter::None`]
+#[derive(Clone, Debug, Default)]
+pub enum RetryJitter {
+    /// Retry exactly after the backoff of the task
+    #[default]
+    None,
+    /// Retry after a random delay between zero and the backoff
+    Full,
+    /// Retry after a random delay between half of the backoff and the backoff
+    Equal,
+}
+
+impl RetryJitter {
+    /// Apply the jitter to the `backoff` of a task.
+    pub fn apply(&self, backoff: chrono::Duration) -> chrono::Duration {
+        use rand::Rng;
+
+        let backoff_millis = backoff.num_milliseconds().max(0);
+
+        let millis = match self {
+            RetryJitter::None => return backoff,
+            RetryJitter::Full => rand::thread_rng().gen_range(0..=backoff_millis),
+            RetryJitter::Equal => {
+                let half = backoff_millis / 2;
+
+                half + rand::thread_rng().gen_range(0..=backoff_millis - half)
+            }
+        };
+
+        chrono::Duration::milliseconds(millis)
+    }
+}
+
 /// Configuration parameters for putting workers to sleep
 /// while they don't have any tasks to execute
 #[derive(Clone, Debug, TypedBuilder)]
@@ -75,10 +111,11 @@ impl SleepParams {
         }
     }
 
-    /// Increase the `sleep_period` by the `sleep_step` if the `max_sleep_period` is not reached
+    /// Increase the `sleep_period` by the `sleep_step` if the `max_sleep_period` is not reached.
+    /// The `sleep_period` never exceeds the `max_sleep_period`
     pub fn maybe_increase_sleep_period(&mut self) {
         if self.sleep_period < self.max_sleep_period {
-            self.sleep_period += self.sleep_step;
+            self.sleep_period = (self.sleep_period + self.sleep_step).min(self.max_sleep_period);
         }
     }
 }
@@ -94,6 +131,19 @@ impl Default for SleepParams {
     }
 }
 
+/// Configuration parameters for batching the updates of the states of executed tasks.
+///
+/// Workers buffer the finished and failed tasks and write their states in batched updates
+/// when `max_tasks` tasks are buffered, `max_delay` elapsed since the first buffered task
+/// or there are no tasks to execute. Tasks stay in the `FangTaskState::InProgress` state until a flush.
+#[derive(Clone, Debug, TypedBuilder)]
+pub struct BatchParams {
+    /// the maximum number of buffered tasks
+    pub max_tasks: usize,
+    /// the maximum time a task can stay in the buffer
+    pub max_delay: Duration,
+}
+
 /// An error that can happen during executing of tasks
 #[derive(Debug)]
 pub struct FangError {
@@ -102,7 +152,8 @@ pub struct FangError {
 }
 
 /// Possible states of the task
-#[derive(Debug, Eq, PartialEq, Clone)]
+#[derive(Debug, Eq, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 #[cfg_attr(feature = "blocking", derive(diesel_derive_enum::DbEnum))]
 #[cfg_attr(feature = "asynk", derive(ToSql, FromSql, Default))]
 #[cfg_attr(feature = "asynk", postgres(name = "fang_task_state"))]
@@ -130,10 +181,20 @@ pub enum FangTaskState {
     /// The task is being retried. It means it failed but it's scheduled to be executed again
     #[cfg_attr(feature = "asynk", postgres(name = "retried"))]
     Retried,
+    /// The task was cancelled before it was executed
+    #[cfg_attr(feature = "asynk", postgres(name = "cancelled"))]
+    Cancelled,
+    /// The task failed too many times in a row and it's excluded from fetching
+    /// until it's inspected and moved back to the queue
+    #[cfg_attr(feature = "asynk", postgres(name = "quarantined"))]
+    Quarantined,
+    /// The task wasn't executed before its expiration time and it will never be executed
+    #[cfg_attr(feature = "asynk", postgres(name = "expired"))]
+    Expired,
 }
 
-#[derive(Debug, Eq, PartialEq, Clone, TypedBuilder)]
-#[cfg_attr(feature = "blocking", derive(Queryable, Identifiable))]
+#[derive(Debug, Eq, PartialEq, Clone, TypedBuilder, Serialize, Deserialize)]
+#[cfg_attr(feature = "blocking", derive(Queryable, QueryableByName, Identifiable))]
 #[cfg_attr(feature = "blocking",  diesel(table_name = fang_tasks))]
 pub struct Task {
     #[builder(setter(into))]
@@ -156,6 +217,38 @@ pub struct Task {
     pub created_at: DateTime<Utc>,
     #[builder(setter(into))]
     pub updated_at: DateTime<Utc>,
+    #[builder(setter(into))]
+    pub binary_metadata: Option<Vec<u8>>,
+    #[builder(setter(into))]
+    pub error_details: Option<serde_json::Value>,
+    #[builder(setter(into))]
+    pub max_retries: Option<i32>,
+    #[builder(setter(into))]
+    pub deleted_at: Option<DateTime<Utc>>,
+    #[builder(setter(into))]
+    pub priority: i32,
+    #[builder(setter(into))]
+    pub tags: Option<serde_json::Value>,
+    #[builder(setter(into))]
+    pub correlation_id: Option<Uuid>,
+    #[builder(setter(into))]
+    pub locked_until: Option<DateTime<Utc>>,
+    #[builder(setter(into))]
+    pub depends_on: Option<Uuid>,
+    #[builder(setter(into))]
+    pub result: Option<serde_json::Value>,
+    #[builder(setter(into))]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Task {
+    /// Deserialize the JSON `metadata` of the task into the type of the job.
+    /// The `type` tag added by `typetag` is ignored unless `T` denies unknown fields.
+    pub fn deserialize_metadata<T: serde::de::DeserializeOwned>(
+        &self,
+    ) -> Result<T, serde_json::Error> {
+        serde_json::from_value(self.metadata.clone())
+    }
 }
 
 #[doc(hidden)]
@@ -175,6 +268,9 @@ pub extern crate serde;
 #[doc(hidden)]
 pub extern crate chrono;
 
+#[doc(hidden)]
+pub extern crate serde_json;
+
 #[doc(hidden)]
 pub use serde_derive::{Deserialize, Serialize};
 